@@ -0,0 +1,164 @@
+use std::{io::{BufRead, ErrorKind}, net::IpAddr, process::exit, time::{Duration, Instant}};
+use clap::Parser;
+use rvban::vban_text::{VBanTextFormat, VbanText, VbanTextSender};
+use rvban::vban_packet::VbanPacketRef;
+use rvban::{VBanProtocol, VBAN_PROTOCOL_MAX_SIZE};
+use log::{error, debug, trace};
+use simplelog::{Config, TermLogger};
+
+/// VBAN Cmd
+/// Send VBAN-TEXT commands, e.g. Voicemeeter remote commands like "Strip[0].Gain = -6;", to a peer.
+/// Commands are taken from the arguments or, if there are none, from stdin (one command per line).
+
+
+#[derive(Parser)]
+struct Cli {
+
+    /// Commands to send. Read from stdin if omitted.
+    commands : Vec<String>,
+
+    /// IP address of the receiver, e.g. 192.168.0.100 (defaults to 127.0.0.1)
+    #[arg(short='i', long, default_value = "127.0.0.1")]
+    peer_address : String,
+
+    /// Port of the receiver (defaults to 6980)
+    #[arg(short='p', long, default_value_t = 6980)]
+    peer_port : u16,
+
+    /// Name of the text stream (defaults to "Command1")
+    #[arg(short='n', long, value_name = "NAME", default_value = "Command1")]
+    stream_name : String,
+
+    /// Specify an IP-address if you don't want to bind to all interfaces
+    #[arg(short='a', long)]
+    local_addr : Option<IpAddr>,
+
+    /// Specify a local port if you don't want the OS to choose one for you
+    #[arg(short='o', long)]
+    local_port : Option<u16>,
+
+    /// Text format [UTF8 (default), ASCII]
+    #[arg(short='f', long, default_value = "utf8")]
+    format : String,
+
+    /// Wait for replies on the same socket and print them. Supply the time to wait in milliseconds.
+    #[arg(short='w', long, value_name = "duration")]
+    wait : Option<u64>,
+
+    /// Set a log level for terminal printouts (0 = Off, 5 = Trace, default = 3).
+    #[arg(short='l', long)]
+    log_level : Option<usize>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let ll = match cli.log_level {
+        None => log::LevelFilter::Info,
+        Some(0) => log::LevelFilter::Off,
+        Some(1) => log::LevelFilter::Error,
+        Some(2) => log::LevelFilter::Warn,
+        Some(3) => log::LevelFilter::Info,
+        Some(4) => log::LevelFilter::Debug,
+        Some(5) => log::LevelFilter::Trace,
+        _ => {
+            println!("Log level must be between 0 and 5. Using default.");
+            log::LevelFilter::Info
+        }
+    };
+
+    TermLogger::init(ll, Config::default(), simplelog::TerminalMode::Stderr, simplelog::ColorChoice::Auto).unwrap();
+
+    let peer_ip : IpAddr = match cli.peer_address.parse(){
+        Ok(addr) => {
+            debug!("Using {} as peer address", addr);
+            addr
+        }
+        Err(_e) => {
+            error!("{} is not a valid IP address. Example: 127.0.0.1", cli.peer_address);
+            exit(1);
+        }
+    };
+
+    let format = match cli.format.as_str(){
+        "UTF8" | "Utf8" | "utf8" | "UTF-8" | "utf-8" => VBanTextFormat::Utf8,
+        "ASCII" | "Ascii" | "ascii" => VBanTextFormat::Ascii,
+        _ => {
+            error!("Text format not recognized.");
+            exit(1)
+        }
+    };
+
+    let local_ip = match cli.local_addr {
+        None => "0.0.0.0".parse().unwrap(),
+        Some(addr) => addr,
+    };
+    let local_port = cli.local_port.unwrap_or(0);
+
+    let mut sender = match VbanTextSender::create((peer_ip, cli.peer_port), (local_ip, local_port), cli.stream_name, format){
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error while initializing: {e}");
+            exit(1);
+        }
+    };
+
+    let commands : Vec<String> = match cli.commands.is_empty() {
+        false => cli.commands,
+        true => std::io::stdin().lock().lines().map_while(Result::ok).filter(|l| !l.trim().is_empty()).collect(),
+    };
+
+    for cmd in commands.iter() {
+        match sender.send(cmd){
+            Ok(()) => debug!("Sent '{cmd}'"),
+            Err(e) => {
+                error!("Could not send '{cmd}': {e}");
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(wait) = cli.wait {
+        print_replies(&sender, Duration::from_millis(wait));
+    }
+}
+
+/// Print every text packet that arrives on the sender's socket within `duration`.
+fn print_replies(sender : &VbanTextSender, duration : Duration){
+    let socket = sender.socket();
+    let start = Instant::now();
+    let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+
+    while let Some(remaining) = duration.checked_sub(start.elapsed()) {
+        if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+
+        let (size, addr) = match socket.recv_from(&mut buf){
+            Ok(res) => res,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => {
+                error!("Error while waiting for a reply: {e}");
+                break;
+            }
+        };
+
+        let packet = match VbanPacketRef::parse(&buf[..size]){
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Ignoring packet from {addr} ({e}).");
+                continue;
+            }
+        };
+
+        if packet.protocol() != VBanProtocol::VbanProtocolTxt {
+            trace!("Ignoring packet with protocol {:?} from {addr}.", packet.protocol());
+            continue;
+        }
+
+        match VbanText::from_packet(&packet){
+            Ok(reply) => println!("{}", reply.text),
+            Err(e) => error!("Could not decode reply from {addr}: {e}"),
+        }
+    }
+}
@@ -367,11 +367,11 @@ fn build_ui(app: &Application) {
                 println!("Activated");
 
                 let mut vbs = match rvban::vban_sender_pw::VbanSender::create(peer.get(), local_addr, stream_name.clone(), numch, sample_rate.get(), format, source_name.borrow().to_string(), encoder.get()) {
-                    None => {
-                            println!("Error: Could not create VBAN Sender");
+                    Err(e) => {
+                            println!("Error: Could not create VBAN Sender ({e})");
                             return;
                         }
-                    Some(sender) => sender
+                    Ok(sender) => sender
                 };
 
                 let new_handle = std::thread::spawn(move || {
@@ -0,0 +1,90 @@
+use std::{net::IpAddr, process::exit};
+use clap::Parser;
+use rvban::vban_midi::VbanMidiBridge;
+use log::{debug, error, info};
+use simplelog::{Config, TermLogger};
+
+/// VBAN MIDI
+/// Bridge VBAN-SERIAL/MIDI streams to an ALSA sequencer client. Incoming MIDI is played on the client's port, events
+/// sent to the port are forwarded to the peer. Connect the port to your hardware or virtual MIDI ports with `aconnect`.
+
+
+#[derive(Parser)]
+struct Cli {
+
+    /// Specify an IP-address if you don't want to bind to all interfaces
+    addr : Option<IpAddr>,
+
+    /// Specify a different port if you don't want to use port 6980
+    #[arg(short, long, default_value_t = 6980)]
+    port : u16,
+
+    /// IP address to send captured MIDI events to. Only incoming MIDI is played if omitted.
+    #[arg(short='i', long)]
+    peer_address : Option<IpAddr>,
+
+    /// Port of the peer (defaults to 6980)
+    #[arg(short='o', long, default_value_t = 6980)]
+    peer_port : u16,
+
+    /// Name of the outgoing MIDI stream (defaults to "Midi1")
+    #[arg(short='n', long, value_name = "NAME", default_value = "Midi1")]
+    stream_name : String,
+
+    /// Only play incoming MIDI streams with this name
+    #[arg(short='s', long, value_name = "NAME")]
+    stream_filter : Option<String>,
+
+    /// Name of the ALSA sequencer client (defaults to "VBAN MIDI")
+    #[arg(short='c', long, default_value = "VBAN MIDI")]
+    client_name : String,
+
+    /// Set a log level for terminal printouts (0 = Off, 5 = Trace, default = 3).
+    #[arg(short='l', long)]
+    log_level : Option<usize>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let ll = match cli.log_level {
+        None => log::LevelFilter::Info,
+        Some(0) => log::LevelFilter::Off,
+        Some(1) => log::LevelFilter::Error,
+        Some(2) => log::LevelFilter::Warn,
+        Some(3) => log::LevelFilter::Info,
+        Some(4) => log::LevelFilter::Debug,
+        Some(5) => log::LevelFilter::Trace,
+        _ => {
+            println!("Log level must be between 0 and 5. Using default.");
+            log::LevelFilter::Info
+        }
+    };
+
+    TermLogger::init(ll, Config::default(), simplelog::TerminalMode::Stdout, simplelog::ColorChoice::Auto).unwrap();
+
+    let addr = match cli.addr {
+        None => "0.0.0.0".parse().unwrap(),
+        Some(addr) => {
+            info!("Using {addr} as address to bind to.");
+            addr
+        },
+    };
+
+    let peer = cli.peer_address.map(|ip| (ip, cli.peer_port));
+    if let Some((ip, port)) = peer {
+        debug!("Sending captured MIDI events to {ip}:{port}");
+    }
+
+    let mut bridge = match VbanMidiBridge::create((addr, cli.port), peer, cli.stream_name, cli.stream_filter, &cli.client_name){
+        Ok(b) => b,
+        Err(e) => {
+            error!("Could not create VBAN MIDI bridge: {e}");
+            exit(1);
+        }
+    };
+
+    loop {
+        bridge.handle();
+    }
+}
@@ -0,0 +1,84 @@
+use std::{net::IpAddr, process::exit, time::Duration};
+use clap::Parser;
+use rvban::vban_service::{self, VbanPing0, VBAN_DEVICE_RECEPTOR, VBAN_DEVICE_TRANSMITTER, VBAN_FEATURE_AUDIO};
+use log::{error, debug};
+use simplelog::{Config, TermLogger};
+
+/// VBAN Scan
+/// Broadcast a VBAN-SERVICE ping and list every VBAN node that answers.
+
+
+#[derive(Parser)]
+struct Cli {
+
+    /// Address the ping is sent to (defaults to the broadcast address 255.255.255.255)
+    #[arg(short='i', long, default_value = "255.255.255.255")]
+    peer_address : IpAddr,
+
+    /// Port the ping is sent to (defaults to 6980)
+    #[arg(short='p', long, default_value_t = 6980)]
+    peer_port : u16,
+
+    /// Specify an IP-address if you don't want to bind to all interfaces
+    #[arg(short='a', long)]
+    local_addr : Option<IpAddr>,
+
+    /// Specify a local port if you don't want the OS to choose one for you
+    #[arg(short='o', long)]
+    local_port : Option<u16>,
+
+    /// Time to wait for replies in milliseconds (defaults to 1000)
+    #[arg(short='w', long, value_name = "duration", default_value_t = 1000)]
+    wait : u64,
+
+    /// Set a log level for terminal printouts (0 = Off, 5 = Trace, default = 3).
+    #[arg(short='l', long)]
+    log_level : Option<usize>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let ll = match cli.log_level {
+        None => log::LevelFilter::Info,
+        Some(0) => log::LevelFilter::Off,
+        Some(1) => log::LevelFilter::Error,
+        Some(2) => log::LevelFilter::Warn,
+        Some(3) => log::LevelFilter::Info,
+        Some(4) => log::LevelFilter::Debug,
+        Some(5) => log::LevelFilter::Trace,
+        _ => {
+            println!("Log level must be between 0 and 5. Using default.");
+            log::LevelFilter::Info
+        }
+    };
+
+    TermLogger::init(ll, Config::default(), simplelog::TerminalMode::Stderr, simplelog::ColorChoice::Auto).unwrap();
+
+    let local_ip = match cli.local_addr {
+        None => "0.0.0.0".parse().unwrap(),
+        Some(addr) => addr,
+    };
+    let local_port = cli.local_port.unwrap_or(0);
+
+    debug!("Sending ping to {}:{}", cli.peer_address, cli.peer_port);
+
+    let identity = VbanPing0::new(VBAN_DEVICE_RECEPTOR | VBAN_DEVICE_TRANSMITTER, VBAN_FEATURE_AUDIO, "vban_scan");
+    let nodes = match vban_service::scan((local_ip, local_port), (cli.peer_address, cli.peer_port), Duration::from_millis(cli.wait), &identity){
+        Ok(nodes) => nodes,
+        Err(e) => {
+            error!("Scan failed: {e}");
+            exit(1);
+        }
+    };
+
+    for node in nodes.iter() {
+        let info = &node.info;
+        println!("{}\t{}\t{} {}.{}.{}.{}\t{}\t{}\t{}",
+            node.addr, info.device_type(),
+            info.application_name, info.version[0], info.version[1], info.version[2], info.version[3],
+            info.host_name, info.device_name, info.manufacturer_name);
+    }
+
+    debug!("{} node(s) answered", nodes.len());
+}
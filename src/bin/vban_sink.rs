@@ -1,7 +1,7 @@
-use std::{net::IpAddr, path::PathBuf, process::Command};
+use std::{net::IpAddr, path::PathBuf, process::{exit, Command}};
 use simplelog::{TermLogger, Config};
 use log::{info, error};
-use rvban::{vban_recipient::VbanRecipient, VBanSampleRates};
+use rvban::{vban_recipient::{SenderPolicy, VbanRecipient}, vban_jitter::JitterBufferConfig, vban_drift::DriftCompensation, vban_resample::ResampleQuality, vban_routing::{ChannelMatrix, ChannelRouting}, vban_ducking::{DuckingConfig, DuckingMode}, vban_access::{AccessAction, AccessRule, IpNet}, vban_multicast::MulticastInterface, VBanSampleRates};
 use clap::{Parser};
 
 /// VBAN Sink - by Lennard Jönsson 
@@ -43,9 +43,100 @@ struct Cli {
     #[arg(short, long)]
     log_level : Option<usize>,
 
-    /// Sample rate
+    /// Fixed sample rate of the audio device. Streams of other rates are resampled.
     #[arg(short='r', long)]
-    sample_rate : Option<u32>
+    sample_rate : Option<u32>,
+
+    /// Interpolation used when resampling [linear, cubic, sinc (default)]
+    #[arg(long, default_value = "sinc")]
+    resample_quality : String,
+
+    /// Latency of the jitter buffer in milliseconds (defaults to 10). The lower bound if the latency is adaptive.
+    #[arg(short='j', long, value_name = "ms", default_value_t = 10)]
+    latency : u32,
+
+    /// Maximum latency of the jitter buffer in milliseconds (defaults to 200)
+    #[arg(long, value_name = "ms", default_value_t = 200)]
+    max_latency : u32,
+
+    /// Keep the latency fixed instead of adapting it to the network jitter
+    #[arg(long)]
+    fixed_latency : bool,
+
+    /// Compensate the clock drift between sender and audio device [off (default), resample, insert-drop]
+    #[arg(long, default_value = "off")]
+    drift : String,
+
+    /// Map the stream channels to the device channels [mono-to-stereo, stereo-to-mono, 5.1-to-stereo] or play a
+    /// list of stream channels, e.g. 3,4 to play channels 3 and 4 of the stream on a stereo device
+    #[arg(long, value_name = "routing")]
+    route : Option<String>,
+
+    /// Routing matrix with one row of gains per device channel and one gain per stream channel, rows are separated
+    /// by semicolons, e.g. "1,0,0.7;0,1,0.7"
+    #[arg(long, value_name = "gains")]
+    matrix : Option<String>,
+
+    /// Play a stream on its own audio device, e.g. Kitchen=hw:1. May be given several times. Other streams play on
+    /// the device given by -d.
+    #[arg(long, value_name = "stream=device")]
+    stream_device : Vec<String>,
+
+    /// Mix a stream with a gain in dB, e.g. Doorbell=6. May be given several times.
+    #[arg(long, value_name = "stream=dB", allow_hyphen_values = true)]
+    stream_gain : Vec<String>,
+
+    /// Mute a stream. May be given several times.
+    #[arg(long, value_name = "stream")]
+    mute : Vec<String>,
+
+    /// Stream names from the highest to the lowest priority, e.g. Announce,Doorbell. While a stream receives packets,
+    /// all streams of lower priority are ducked. Unlisted streams have the lowest priority.
+    #[arg(long, value_name = "streams")]
+    priority : Option<String>,
+
+    /// Attenuation of ducked streams in dB
+    #[arg(long, default_value_t = 20.0)]
+    duck_db : f32,
+
+    /// Pause ducked streams instead of attenuating them
+    #[arg(long)]
+    duck_pause : bool,
+
+    /// Duration of the fade to the ducked gain in ms
+    #[arg(long, default_value_t = 50)]
+    duck_attack : u32,
+
+    /// Duration of the fade back from the ducked gain in ms
+    #[arg(long, default_value_t = 500)]
+    duck_release : u32,
+
+    /// Only accept packets from an address or network, e.g. 192.168.1.0/24, or only for one stream, e.g.
+    /// Announce=10.0.0.7. May be given several times.
+    #[arg(long, value_name = "[stream=]address")]
+    allow : Vec<String>,
+
+    /// Reject packets from an address or network, for all streams or only for one stream. May be given several times.
+    #[arg(long, value_name = "[stream=]address")]
+    deny : Vec<String>,
+
+    /// Play only one of several senders [first, latest] or only the sender with the given address. By default all
+    /// senders are mixed.
+    #[arg(long, value_name = "policy")]
+    lock : Option<String>,
+
+    /// Duration of the crossfade in ms when the latest sender takes over
+    #[arg(long, default_value_t = 100)]
+    crossfade : u32,
+
+    /// Join a multicast group, e.g. 239.0.0.100. Leave the address to bind to empty to receive multicast packets. May
+    /// be given several times.
+    #[arg(long, value_name = "group")]
+    multicast : Vec<IpAddr>,
+
+    /// Interface that receives the multicast groups: its IPv4 address, or the name or index of the interface
+    #[arg(long, value_name = "interface")]
+    multicast_interface : Option<String>,
 }
 
 // #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
@@ -121,15 +212,166 @@ fn main() -> Result<(), i32> {
     let mut vbr = match VbanRecipient::create(
     addr, port, stream_name, None, Some(sr),
     device_name, cli.silence){
-        None => {
-            error!("Could not create VBAN recipient.");
+        Err(e) => {
+            error!("Could not create VBAN recipient: {e}");
             return Err(-1)
         },
-        Some(_vbr) => {
+        Ok(_vbr) => {
             _vbr
         }
     };
 
+    vbr.set_jitter_buffer(JitterBufferConfig {
+        latency_ms : cli.latency,
+        max_latency_ms : cli.max_latency.max(cli.latency),
+        adaptive : !cli.fixed_latency,
+    });
+
+    vbr.set_drift_compensation(match cli.drift.as_str() {
+        "Off" | "OFF" | "off" => DriftCompensation::Off,
+        "Resample" | "RESAMPLE" | "resample" => DriftCompensation::Resample,
+        "InsertDrop" | "INSERT-DROP" | "insert-drop" => DriftCompensation::InsertDrop,
+        _ => {
+            error!("Drift compensation mode not recognized.");
+            exit(1)
+        }
+    });
+
+    vbr.set_output_rate(cli.sample_rate);
+    vbr.set_resample_quality(match cli.resample_quality.as_str() {
+        "Linear" | "LINEAR" | "linear" => ResampleQuality::Linear,
+        "Cubic" | "CUBIC" | "cubic" => ResampleQuality::Cubic,
+        "Sinc" | "SINC" | "sinc" => ResampleQuality::Sinc,
+        _ => {
+            error!("Resample quality not recognized.");
+            exit(1)
+        }
+    });
+
+    let routing = match (cli.route, cli.matrix) {
+        (None, None) => ChannelRouting::Direct,
+        (Some(_), Some(_)) => {
+            error!("Use either a routing or a matrix, not both.");
+            exit(1)
+        },
+        (Some(route), None) => match route.as_str() {
+            "mono-to-stereo" => ChannelRouting::MonoToStereo,
+            "stereo-to-mono" => ChannelRouting::StereoToMono,
+            "5.1-to-stereo" => ChannelRouting::SurroundToStereo,
+            list => match list.split(',').map(|ch| ch.trim().parse::<usize>()).collect::<Result<Vec<usize>, _>>() {
+                Ok(channels) if !channels.contains(&0) => ChannelRouting::Select(channels.iter().map(|ch| ch - 1).collect()),
+                _ => {
+                    error!("Routing not recognized. Use a preset or a list of channels starting at 1.");
+                    exit(1)
+                }
+            },
+        },
+        (None, Some(matrix)) => {
+            let rows = matrix.split(';').map(|row| row.split(',').map(|gain| gain.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>()).collect::<Result<Vec<_>, _>>();
+            match rows.map_err(|e| e.to_string()).and_then(|rows| ChannelMatrix::from_rows(&rows).map_err(|e| e.to_string())) {
+                Ok(matrix) => ChannelRouting::Matrix(matrix),
+                Err(e) => {
+                    error!("Could not read the routing matrix ({e}).");
+                    exit(1)
+                }
+            }
+        },
+    };
+    vbr.set_channel_routing(routing);
+
+    for entry in cli.stream_device.iter() {
+        match entry.split_once('=') {
+            Some((stream, device)) if !stream.is_empty() && !device.is_empty() => {
+                info!("Playing stream {stream} on {device}.");
+                vbr.set_stream_device(stream, device);
+            },
+            _ => {
+                error!("Expected stream=device, found {entry}.");
+                exit(1)
+            }
+        }
+    }
+
+    for entry in cli.stream_gain.iter() {
+        match entry.split_once('=').map(|(stream, gain)| (stream, gain.trim().parse::<f32>())) {
+            Some((stream, Ok(gain))) if !stream.is_empty() => vbr.set_stream_gain(stream, gain),
+            _ => {
+                error!("Expected stream=dB, found {entry}.");
+                exit(1)
+            }
+        }
+    }
+
+    for stream in cli.mute.iter() {
+        vbr.set_stream_mute(stream, true);
+    }
+
+    if let Some(priority) = cli.priority {
+        let priorities : Vec<String> = priority.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect();
+        if priorities.is_empty() {
+            error!("Expected a list of stream names, found {priority}.");
+            exit(1)
+        }
+        info!("Stream priorities: {}.", priorities.join(" > "));
+        vbr.set_ducking(Some(DuckingConfig {
+            priorities,
+            mode : match cli.duck_pause {
+                true => DuckingMode::Pause,
+                false => DuckingMode::Attenuate(cli.duck_db),
+            },
+            attack_ms : cli.duck_attack,
+            release_ms : cli.duck_release,
+        }));
+    }
+
+    let interface = match cli.multicast_interface.as_deref().map(|iface| iface.parse::<MulticastInterface>()) {
+        None => MulticastInterface::Default,
+        Some(Ok(iface)) => iface,
+        Some(Err(e)) => {
+            error!("Multicast interface not recognized ({e}).");
+            exit(1)
+        }
+    };
+    for group in cli.multicast.iter() {
+        if let Err(e) = vbr.join_multicast(*group, interface) {
+            error!("Could not join multicast group {group}: {e}");
+            exit(1)
+        }
+    }
+
+    match cli.lock.as_deref() {
+        None => (),
+        Some("First" | "FIRST" | "first") => vbr.set_sender_policy(SenderPolicy::FirstWins),
+        Some("Latest" | "LATEST" | "latest") => vbr.set_sender_policy(SenderPolicy::LatestWins { crossfade_ms : cli.crossfade }),
+        Some(addr) => match addr.parse::<IpAddr>() {
+            Ok(addr) => vbr.set_sender_policy(SenderPolicy::Fixed(addr)),
+            Err(_) => {
+                error!("Sender policy not recognized. Use first, latest or an IP address.");
+                exit(1)
+            }
+        },
+    }
+
+    let rules = cli.allow.iter().map(|entry| (AccessAction::Allow, entry)).chain(cli.deny.iter().map(|entry| (AccessAction::Deny, entry)));
+    for (action, entry) in rules {
+        let (stream, net) = match entry.split_once('=') {
+            None => (None, entry.as_str()),
+            Some((stream, net)) => (Some(stream.trim()), net),
+        };
+        match (stream, net.parse::<IpNet>()) {
+            (None, Ok(net)) => vbr.add_access_rule(AccessRule::new(action, net)),
+            (Some(stream), Ok(net)) if !stream.is_empty() => vbr.add_access_rule(AccessRule::for_stream(action, net, stream)),
+            (_, Err(e)) => {
+                error!("Could not read access rule {entry} ({e}).");
+                exit(1)
+            },
+            _ => {
+                error!("Expected [stream=]address, found {entry}.");
+                exit(1)
+            }
+        }
+    }
+
     match cli.command {
         None => (),
         Some(cmd) => {
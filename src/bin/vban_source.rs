@@ -1,7 +1,9 @@
 
-use std::{net::{IpAddr, UdpSocket}, path::PathBuf, process::exit};
+use std::{net::IpAddr, path::PathBuf, process::exit};
 use clap::Parser;
 use rvban::{VBanSampleRates, VBanBitResolution, VBanCodec};
+use rvban::vban_opus::{OpusApplication, OpusBandwidth, OpusConfig, OpusFrameDuration, OpusSignal, OPUS_MAX_BITRATE_PER_CHANNEL};
+use rvban::vban_multicast::{MulticastConfig, MulticastInterface, MULTICAST_DEFAULT_TTL};
 use log::{error, debug};
 use simplelog::{Config, TermLogger};
 
@@ -39,6 +41,18 @@ struct Cli {
     #[arg(short='o', long)]
     local_port : Option<u16>,
 
+    /// Number of routers a multicast packet may pass, if the receiver address is a multicast group (defaults to 1)
+    #[arg(long, default_value_t = MULTICAST_DEFAULT_TTL)]
+    ttl : u32,
+
+    /// Interface that sends multicast packets: its IPv4 address, or the name or index of an interface for IPv6 groups
+    #[arg(long, value_name = "INTERFACE")]
+    multicast_interface : Option<String>,
+
+    /// Allow a broadcast address as receiver address, e.g. 192.168.0.255 to reach every host of the subnet
+    #[arg(long)]
+    broadcast : bool,
+
     /// Use a config file (currently not supported)
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
@@ -51,6 +65,47 @@ struct Cli {
     #[arg(short, long, default_value = "opus")]
     encoder : String,
 
+    /// Number of channels to capture and send (defaults to 2). The Opus encoder supports up to 8 channels.
+    #[arg(long, default_value_t = 2)]
+    channels : u8,
+
+    /// Sample format [16 (default), 8, 10, 12, 24, 32, 32f, 64f]. Anything but 16 requires the PCM encoder.
+    #[arg(short='b', long, default_value = "16")]
+    bit_depth : String,
+
+    /// Opus bitrate of all channels in kbit/s (defaults to 160 kbit/s per channel)
+    #[arg(long, value_name = "KBPS")]
+    opus_bitrate : Option<u32>,
+
+    /// Use a constant instead of a variable Opus bitrate
+    #[arg(long)]
+    opus_cbr : bool,
+
+    /// Opus complexity from 0 (fastest) to 10 (best quality, default)
+    #[arg(long, default_value_t = 10)]
+    opus_complexity : u8,
+
+    /// Opus frame duration in ms [2.5, 5 (default), 10, 20]. A frame must not exceed 256 samples.
+    #[arg(long, value_name = "MS", default_value = "5")]
+    opus_frame : String,
+
+    /// Opus application [audio (default), voip, lowdelay]
+    #[arg(long, default_value = "audio")]
+    opus_application : String,
+
+    /// Opus signal type [auto (default), voice, music]
+    #[arg(long, default_value = "auto")]
+    opus_signal : String,
+
+    /// Opus bandwidth [auto (default), narrowband, mediumband, wideband, superwideband, fullband]
+    #[arg(long, default_value = "auto")]
+    opus_bandwidth : String,
+
+    /// Enable Opus in-band FEC for the given expected packet loss in percent. Requires frames of 10 ms or more, i.e. a
+    /// sample rate of 24 kHz or lower.
+    #[arg(long, value_name = "PERCENT")]
+    opus_fec : Option<u8>,
+
     /// Set a log level for terminal printouts (0 = Off, 5 = Trace, default = 3).
     #[arg(short='l', long)]
     log_level : Option<usize>,
@@ -114,6 +169,84 @@ fn main() {
     };
     
 
+    let bit_resolution = match cli.bit_depth.as_str() {
+        "8" => VBanBitResolution::VbanBitfmt8Int,
+        "10" => VBanBitResolution::VbanBitfmt10Int,
+        "12" => VBanBitResolution::VbanBitfmt12Int,
+        "16" => VBanBitResolution::VbanBitfmt16Int,
+        "24" => VBanBitResolution::VbanBitfmt24Int,
+        "32" => VBanBitResolution::VbanBitfmt32Int,
+        "32f" | "32F" | "float" => VBanBitResolution::VbanBitfmt32Float,
+        "64f" | "64F" | "double" => VBanBitResolution::VbanBitfmt64Float,
+        _ => {
+            error!("Sample format not supported. Supported formats are 8, 10, 12, 16, 24, 32, 32f and 64f.");
+            exit(1)
+        }
+    };
+
+    let max_opus_kbps = OPUS_MAX_BITRATE_PER_CHANNEL as u32 / 1000 * cli.channels.max(1) as u32;
+    let opus_config = OpusConfig {
+        // clamped to the limit of libopus before the conversion to bit/s, so that it cannot overflow
+        bitrate : cli.opus_bitrate.map(|kbps| kbps.min(max_opus_kbps) as i32 * 1000),
+        vbr : !cli.opus_cbr,
+        complexity : match cli.opus_complexity {
+            0..=10 => cli.opus_complexity,
+            _ => {
+                error!("Opus complexity must be between 0 and 10.");
+                exit(1)
+            }
+        },
+        frame_duration : match cli.opus_frame.as_str() {
+            "2.5" => OpusFrameDuration::Ms2_5,
+            "5" => OpusFrameDuration::Ms5,
+            "10" => OpusFrameDuration::Ms10,
+            "20" => OpusFrameDuration::Ms20,
+            _ => {
+                error!("Opus frame duration not supported. Supported durations are 2.5, 5, 10 and 20 ms.");
+                exit(1)
+            }
+        },
+        application : match cli.opus_application.to_lowercase().as_str() {
+            "audio" => OpusApplication::Audio,
+            "voip" => OpusApplication::Voip,
+            "lowdelay" => OpusApplication::LowDelay,
+            _ => {
+                error!("Opus application not recognized.");
+                exit(1)
+            }
+        },
+        signal : match cli.opus_signal.to_lowercase().as_str() {
+            "auto" => OpusSignal::Auto,
+            "voice" => OpusSignal::Voice,
+            "music" => OpusSignal::Music,
+            _ => {
+                error!("Opus signal type not recognized.");
+                exit(1)
+            }
+        },
+        bandwidth : match cli.opus_bandwidth.to_lowercase().as_str() {
+            "auto" => OpusBandwidth::Auto,
+            "narrowband" | "nb" => OpusBandwidth::Narrowband,
+            "mediumband" | "mb" => OpusBandwidth::Mediumband,
+            "wideband" | "wb" => OpusBandwidth::Wideband,
+            "superwideband" | "swb" => OpusBandwidth::Superwideband,
+            "fullband" | "fb" => OpusBandwidth::Fullband,
+            _ => {
+                error!("Opus bandwidth not recognized.");
+                exit(1)
+            }
+        },
+        inband_fec : cli.opus_fec.is_some(),
+        packet_loss_perc : match cli.opus_fec {
+            None => 0,
+            Some(perc @ 0..=100) => perc,
+            Some(_) => {
+                error!("Expected packet loss must be between 0 and 100 percent.");
+                exit(1)
+            }
+        },
+    };
+
     if use_config {
         // todo: use a config
         local_ip = "127.0.0.1".parse().unwrap();
@@ -148,7 +281,48 @@ fn main() {
 
     let local_addr = (local_ip, local_port);
 
-    let mut vbs = VbanSender::create(peer_addr, local_addr, cli.stream_name, 2, sample_rate, VBanBitResolution::VbanBitfmt16Int, source_name, encoder.into()).expect("Error while initializing.");
+    let use_opus = matches!(encoder, VBanCodec::VbanCodecOpus(_));
+
+    let mut vbs = match VbanSender::create(peer_addr, local_addr, cli.stream_name, cli.channels, sample_rate, bit_resolution, source_name, encoder.into()){
+        Ok(sender) => sender,
+        Err(e) => {
+            error!("Error while initializing: {e}");
+            exit(1);
+        }
+    };
+
+    if peer_ip.is_multicast() {
+        let interface = match cli.multicast_interface.as_deref().map(|iface| iface.parse::<MulticastInterface>()) {
+            None => MulticastInterface::Default,
+            Some(Ok(iface)) => iface,
+            Some(Err(e)) => {
+                error!("Multicast interface not recognized ({e}).");
+                exit(1);
+            },
+        };
+        let config = MulticastConfig { ttl : cli.ttl, interface, ..MulticastConfig::default() };
+        if let Err(e) = vbs.set_multicast(config) {
+            error!("Error while configuring multicast: {e}");
+            exit(1);
+        }
+    } else if cli.multicast_interface.is_some() {
+        error!("A multicast interface requires a multicast group as receiver address.");
+        exit(1);
+    }
+
+    if cli.broadcast {
+        if let Err(e) = vbs.set_broadcast(true) {
+            error!("Error while enabling broadcast: {e}");
+            exit(1);
+        }
+    }
+
+    if use_opus {
+        if let Err(e) = vbs.set_opus_config(opus_config) {
+            error!("Error while configuring the Opus encoder: {e}");
+            exit(1);
+        }
+    }
 
     loop {
         vbs.handle();
@@ -3,10 +3,11 @@
 
 use core::{panic};
 use byteorder::{ByteOrder, LittleEndian};
+#[cfg(any(feature = "alsa", feature = "pipewire"))]
 use log::{trace, debug, info, warn, error};
 
 #[cfg(feature = "pipewire")]
-use std::thread::JoinHandle;
+use std::{thread::JoinHandle, sync::mpsc::{channel, Receiver, Sender}};
 
 #[cfg(feature = "alsa")]
 use alsa::{pcm::*, ValueOr, Direction};
@@ -15,6 +16,19 @@ use alsa::{pcm::*, ValueOr, Direction};
 #[cfg(feature = "pipewire")]
 use pipewire::{stream::Stream, main_loop::MainLoop, properties::properties, context::Context, spa::{self, param::audio::AudioFormat}, spa::sys::{spa_format_audio_raw_build}};
 
+pub mod vban_packet;
+pub mod vban_text;
+pub mod vban_service;
+pub mod vban_opus;
+pub mod vban_stats;
+pub mod vban_jitter;
+pub mod vban_resample;
+pub mod vban_drift;
+pub mod vban_routing;
+pub mod vban_ducking;
+pub mod vban_access;
+pub mod vban_multicast;
+
 #[cfg(feature = "recipient")]
 pub mod vban_recipient;
 
@@ -22,27 +36,26 @@ pub mod vban_recipient;
 pub mod vban_sender_pw;
 #[cfg(feature = "alsa")]
 pub mod vban_sender_alsa;
+#[cfg(feature = "alsa")]
+pub mod vban_midi;
 
 
 
 const VBAN_HEADER_SIZE : usize = 4 + 1 + 1 + 1 + 1 + 16;
-const VBAN_STREAM_NAME_SIZE : usize = 16;
-const VBAN_PROTOCOL_MAX_SIZE : usize = 1464;
-const VBAN_DATA_MAX_SIZE : usize = VBAN_PROTOCOL_MAX_SIZE - VBAN_HEADER_SIZE - VBAN_PACKET_COUNTER_BYTES;
+pub const VBAN_STREAM_NAME_SIZE : usize = 16;
+pub const VBAN_PROTOCOL_MAX_SIZE : usize = 1464;
+pub const VBAN_DATA_MAX_SIZE : usize = VBAN_PROTOCOL_MAX_SIZE - VBAN_HEADER_SIZE - VBAN_PACKET_COUNTER_BYTES;
 const VBAN_CHANNELS_MAX_NB : usize = 256;
 const VBAN_SAMPLES_MAX_NB : u16 = 256;
 
 const _VBAN_PACKET_NUM_SAMPLES : usize = 256;  
 const VBAN_PACKET_MAX_SAMPLES : usize = 256;
+#[cfg(feature = "recipient")]
 const VBAN_PACKET_HEADER_BYTES : usize = 24;  
 const VBAN_PACKET_COUNTER_BYTES : usize = 4;  
+#[cfg(feature = "recipient")]
 const VBAN_PACKET_MAX_LEN_BYTES : usize = VBAN_PACKET_HEADER_BYTES + VBAN_PACKET_COUNTER_BYTES + VBAN_DATA_MAX_SIZE;
 
-// OPUS
-/// Number of samples per channel per opus packet, may be one of 120, 240, 480, 960, 1920, 2880
-/// VBAN only allows a maximum of 256 samples per packet though
-const OPUS_FRAME_SIZE : usize = 240; 
-const OPUS_BITRATE : i32 = 320000;
 
 
 
@@ -61,35 +74,17 @@ struct VBanHeader {
     nu_frame : u32
 }
 
-impl From<[u8; 28]> for VBanHeader {
-    fn from (item: [u8; 28]) -> Self {
-
-        // let frame_count : u32 = item[24] as u32 + (item[25] as u32) << 8 + (item[26] as u32) << 16 + (item[27] as u32) << 24;
-        let frame_count  = 0;
-
-        Self {
-            preamble : item[0..4].try_into().unwrap(),
-            sample_rate : item[4],
-            num_samples : item[5],
-            num_channels : item[6],
-            sample_format : item[7],
-            stream_name : [item[8], item[9], item[10], item[11], item[12], item[13], item[14], item[15], item[16], item[17], item[18], item[19], item[20], item[21], item[22], item[23]],
-            nu_frame : frame_count
-        }
-    }
-}
-
-impl Into<[u8; VBAN_HEADER_SIZE+VBAN_PACKET_COUNTER_BYTES]> for VBanHeader {
-    fn into(self) -> [u8; VBAN_HEADER_SIZE+VBAN_PACKET_COUNTER_BYTES] {
+impl From<VBanHeader> for [u8; VBAN_HEADER_SIZE+VBAN_PACKET_COUNTER_BYTES] {
+    fn from(hdr : VBanHeader) -> Self {
         let mut result = [0; VBAN_HEADER_SIZE+VBAN_PACKET_COUNTER_BYTES];
 
-        result[..4].copy_from_slice(&self.preamble);
-        result[4] = self.sample_rate;
-        result[5] = self.num_samples;
-        result[6] = self.num_channels;
-        result[7] = self.sample_format;
-        result[8..24].copy_from_slice(&self.stream_name);
-        LittleEndian::write_u32(&mut result[24..28], self.nu_frame);
+        result[..4].copy_from_slice(&hdr.preamble);
+        result[4] = hdr.sample_rate;
+        result[5] = hdr.num_samples;
+        result[6] = hdr.num_channels;
+        result[7] = hdr.sample_format;
+        result[8..24].copy_from_slice(&hdr.stream_name);
+        LittleEndian::write_u32(&mut result[24..28], hdr.nu_frame);
 
         result
     }
@@ -104,6 +99,14 @@ const VBAN_SRLIST : [u32; 21] = [
     11025, 22050, 44100, 88200, 176400, 352800, 705600
 ];
 
+/// Bit rates of the VBAN-SERIAL sub-protocol, indexed like VBAN_SRLIST
+#[cfg(feature = "alsa")]
+const VBAN_BPSLIST : [u32; 25] = [
+    0, 110, 150, 300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 31250,
+    38400, 57600, 115200, 128000, 230400, 250000, 256000, 460800, 921600,
+    1000000, 1500000, 2000000, 3000000
+];
+
 // ****************************************
 //           VBAN Samples Rates
 // ****************************************
@@ -163,6 +166,7 @@ impl std::fmt::Display for VBanSampleRates {
     }
 }
 
+/// Sample rate of the index in the lower five bits, `SampleRateNotSupported` for indices outside of the VBAN list
 impl From<u8> for VBanSampleRates {
     fn from(item : u8) -> Self{
         match item & VBAN_SR_MASK {
@@ -187,14 +191,15 @@ impl From<u8> for VBanSampleRates {
             18 => VBanSampleRates::SampleRate176400Hz,
             19 => VBanSampleRates::SampleRate352800Hz,
             20 => VBanSampleRates::SampleRate705600Hz,
-            _ => panic!("Invalid value for enum VBanSampleRates ({:b})", item)
+            _ => VBanSampleRates::SampleRateNotSupported
         }
     }
 }
 
-impl Into<u8> for VBanSampleRates {
-    fn into(self) -> u8 {
-        match self {
+/// Index of the sample rate in the VBAN list, `SampleRateNotSupported` maps to the invalid index `VBAN_SR_MASK`
+impl From<VBanSampleRates> for u8 {
+    fn from(value : VBanSampleRates) -> Self {
+        match value {
             VBanSampleRates::SampleRate6000Hz => 0,
             VBanSampleRates::SampleRate12000Hz => 1,
             VBanSampleRates::SampleRate24000Hz => 2,
@@ -216,15 +221,15 @@ impl Into<u8> for VBanSampleRates {
             VBanSampleRates::SampleRate176400Hz => 18,
             VBanSampleRates::SampleRate352800Hz => 19,
             VBanSampleRates::SampleRate705600Hz => 20,
-            VBanSampleRates::SampleRateNotSupported => panic!("Sample rate not supported")
+            VBanSampleRates::SampleRateNotSupported => VBAN_SR_MASK
         }
     }
 }
 
 
-impl Into<u32> for VBanSampleRates {
-    fn into(self) -> u32 {
-        match self {
+impl From<VBanSampleRates> for u32 {
+    fn from(value : VBanSampleRates) -> Self {
+        match value {
             VBanSampleRates::SampleRate6000Hz => 6000,
             VBanSampleRates::SampleRate12000Hz => 12000,
             VBanSampleRates::SampleRate24000Hz => 24000,
@@ -287,8 +292,8 @@ const VBAN_PROTOCOL_MASK : u8 = 0xE0;
 // ****************************************
 //             VBAN Protocol
 // ****************************************
-#[derive(Debug, PartialEq)]
-enum VBanProtocol {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanProtocol {
     VbanProtocolAudio         =   0x00,
     VbanProtocolSerial        =   0x20,
     VbanProtocolTxt           =   0x40,
@@ -347,15 +352,14 @@ impl From<u8> for VBanBitResolution {
             5 => VBanBitResolution::VbanBitfmt64Float,
             6 => VBanBitResolution::VbanBitfmt12Int,
             7 => VBanBitResolution::VbanBitfmt10Int,
-            8 => VBanBitResolution::VbanBitResolutionMax,
             _ => panic!("Invalid value for enum VBanBitResolution ({item})"),
         }
     }
 }
 
-impl Into<u8> for VBanBitResolution {
-    fn into(self) -> u8 {
-        match self {
+impl From<VBanBitResolution> for u8 {
+    fn from(value : VBanBitResolution) -> Self {
+        match value {
             VBanBitResolution::VbanBitfmt8Int => 0,
             VBanBitResolution::VbanBitfmt16Int => 1,
             VBanBitResolution::VbanBitfmt24Int => 2,
@@ -369,7 +373,69 @@ impl Into<u8> for VBanBitResolution {
     }
 }
 
-const VBAN_BIT_RESOLUTION_SIZE : [u8; 6] = [ 1, 2, 3, 4, 4, 8, ];
+/// Bits per sample, indexed by VBanBitResolution. 12 and 10 bit samples are packed without padding.
+const VBAN_BIT_RESOLUTION_BITS : [u8; 8] = [ 8, 16, 24, 32, 32, 64, 12, 10, ];
+
+impl VBanBitResolution {
+    /// Number of bits per sample, `None` for `VbanBitResolutionMax`
+    pub fn bits(&self) -> Option<u8> {
+        VBAN_BIT_RESOLUTION_BITS.get(*self as usize).copied()
+    }
+
+    /// Number of payload bytes taken by `num_samples` samples (summed over all channels)
+    pub fn payload_size(&self, num_samples : usize) -> Option<usize> {
+        self.bits().map(|bits| (num_samples * bits as usize).div_ceil(8))
+    }
+}
+
+/// Unpack samples of `bits` bits (e.g. 12 or 10), packed into a little endian bit stream, to 16 bit samples.
+fn unpack_samples(data : &[u8], bits : u32) -> Vec<i16> {
+    let mut samples = Vec::with_capacity(data.len() * 8 / bits as usize);
+    let mut acc : u32 = 0;
+    let mut acc_bits = 0;
+
+    for byte in data {
+        acc |= (*byte as u32) << acc_bits;
+        acc_bits += 8;
+
+        while acc_bits >= bits {
+            let raw = acc & ((1 << bits) - 1);
+            acc >>= bits;
+            acc_bits -= bits;
+
+            // sign extend, then scale to 16 bit
+            let smp = ((raw << (32 - bits)) as i32) >> (32 - bits);
+            samples.push((smp << (16 - bits)) as i16);
+        }
+    }
+
+    samples
+}
+
+/// Pack 16 bit samples into a little endian bit stream of `bits` bits (e.g. 12 or 10) per sample.
+fn pack_samples(samples : &[i16], bits : u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity((samples.len() * bits as usize).div_ceil(8));
+    let mut acc : u32 = 0;
+    let mut acc_bits = 0;
+
+    for smp in samples {
+        let raw = ((*smp as i32) >> (16 - bits)) as u32 & ((1 << bits) - 1);
+        acc |= raw << acc_bits;
+        acc_bits += bits;
+
+        while acc_bits >= 8 {
+            data.push(acc as u8);
+            acc >>= 8;
+            acc_bits -= 8;
+        }
+    }
+
+    if acc_bits > 0 {
+        data.push(acc as u8);
+    }
+
+    data
+}
 
 
 
@@ -394,7 +460,7 @@ pub enum VBanCodec {
     VbanCodecUndefined9,
     VbanCodecUndefined10,
     VbanCodecUndefined11,
-    VbanCodecOpus(Option<opus::Encoder>),
+    VbanCodecOpus(Option<vban_opus::OpusEncoder>),
     VbanCodecUndefined13,
     VbanCodecUndefined14,
     VbanCodecUser 
@@ -434,8 +500,9 @@ impl From<u8> for VBanCodec {
     }
 }
 
-impl Into<u8> for VBanCodec {
-    fn into(self) -> u8 {
+impl VBanCodec {
+    /// Codec bits of the `format_bit` header field
+    pub fn format_bits(&self) -> u8 {
         match self {
             VBanCodec::VbanCodecPcm => 0x00,
             VBanCodec::VbanCodecVbca => 0x10,
@@ -457,6 +524,110 @@ impl Into<u8> for VBanCodec {
     }
 }
 
+impl From<VBanCodec> for u8 {
+    fn from(value : VBanCodec) -> Self {
+        value.format_bits()
+    }
+}
+
+// ****************************************
+//              VBAN Error
+// ****************************************
+
+/// Errors returned by the constructors of senders, recipients, sinks and sources.
+#[derive(Debug)]
+pub enum Error {
+    /// The UDP socket could not be bound to the requested address
+    SocketBind(std::io::Error),
+    /// Any other I/O error, e.g. while configuring a socket
+    Io(std::io::Error),
+    /// The requested combination of sample rate, bit resolution, channels and codec is not supported
+    UnsupportedFormat(String),
+    /// The Opus encoder or decoder could not be set up
+    Codec(vban_opus::OpusError),
+    #[cfg(feature = "alsa")]
+    Alsa(alsa::Error),
+    #[cfg(feature = "pipewire")]
+    PipeWire(pipewire::Error),
+    /// The stream name is too long or not valid UTF-8
+    InvalidStreamName(String),
+    /// The payload of a text packet cannot be decoded or encoded in the requested format
+    InvalidText(String),
+    /// A packet could not be parsed or composed
+    Packet(vban_packet::VbanPacketError),
+    /// The payload of a service packet is malformed
+    InvalidService(String),
+    /// A channel routing does not fit the stream or is malformed
+    InvalidRouting(String),
+    /// An address or network of an access rule is malformed
+    InvalidAddress(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::SocketBind(e) => write!(f, "could not bind socket: {e}"),
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::UnsupportedFormat(msg) => write!(f, "unsupported format: {msg}"),
+            Error::Codec(e) => write!(f, "codec error: {e}"),
+            #[cfg(feature = "alsa")]
+            Error::Alsa(e) => write!(f, "ALSA error: {e}"),
+            #[cfg(feature = "pipewire")]
+            Error::PipeWire(e) => write!(f, "PipeWire error: {e}"),
+            Error::InvalidStreamName(msg) => write!(f, "invalid stream name: {msg}"),
+            Error::InvalidText(msg) => write!(f, "invalid text: {msg}"),
+            Error::Packet(e) => write!(f, "invalid packet: {e}"),
+            Error::InvalidService(msg) => write!(f, "invalid service packet: {msg}"),
+            Error::InvalidRouting(msg) => write!(f, "invalid channel routing: {msg}"),
+            Error::InvalidAddress(msg) => write!(f, "invalid address: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::SocketBind(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Codec(e) => Some(e),
+            #[cfg(feature = "alsa")]
+            Error::Alsa(e) => Some(e),
+            #[cfg(feature = "pipewire")]
+            Error::PipeWire(e) => Some(e),
+            Error::Packet(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<vban_opus::OpusError> for Error {
+    fn from(e : vban_opus::OpusError) -> Self {
+        Error::Codec(e)
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl From<alsa::Error> for Error {
+    fn from(e : alsa::Error) -> Self {
+        Error::Alsa(e)
+    }
+}
+
+#[cfg(feature = "pipewire")]
+impl From<pipewire::Error> for Error {
+    fn from(e : pipewire::Error) -> Self {
+        Error::PipeWire(e)
+    }
+}
+
+impl From<vban_packet::VbanPacketError> for Error {
+    fn from(e : vban_packet::VbanPacketError) -> Self {
+        Error::Packet(e)
+    }
+}
+
+
+#[cfg(feature = "recipient")]
 #[derive (PartialEq)]
 enum PlayerState {
     Idle,
@@ -467,11 +638,217 @@ enum PlayerState {
 
 
 
+// ****************************************
+//             AUDIO BUFFER
+// ****************************************
+
+/// Sample formats that are carried through sinks and sources without conversion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    I16,
+    /// 24 bit samples, stored sign extended in the lower bits of an `i32`
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl SampleFormat {
+    /// The VBAN bit resolution that carries this format without loss
+    pub fn bit_resolution(&self) -> VBanBitResolution {
+        match self {
+            SampleFormat::I16 => VBanBitResolution::VbanBitfmt16Int,
+            SampleFormat::I24 => VBanBitResolution::VbanBitfmt24Int,
+            SampleFormat::I32 => VBanBitResolution::VbanBitfmt32Int,
+            SampleFormat::F32 => VBanBitResolution::VbanBitfmt32Float,
+            SampleFormat::F64 => VBanBitResolution::VbanBitfmt64Float,
+        }
+    }
+}
+
+impl VBanBitResolution {
+    /// The sample format audio of this bit resolution is handled in. 8, 10 and 12 bit samples are handled as 16 bit.
+    pub fn sample_format(&self) -> Option<SampleFormat> {
+        match self {
+            VBanBitResolution::VbanBitfmt8Int
+            | VBanBitResolution::VbanBitfmt10Int
+            | VBanBitResolution::VbanBitfmt12Int
+            | VBanBitResolution::VbanBitfmt16Int => Some(SampleFormat::I16),
+            VBanBitResolution::VbanBitfmt24Int => Some(SampleFormat::I24),
+            VBanBitResolution::VbanBitfmt32Int => Some(SampleFormat::I32),
+            VBanBitResolution::VbanBitfmt32Float => Some(SampleFormat::F32),
+            VBanBitResolution::VbanBitfmt64Float => Some(SampleFormat::F64),
+            VBanBitResolution::VbanBitResolutionMax => None,
+        }
+    }
+}
+
+/// Interleaved samples
+#[derive(Clone, Debug, PartialEq)]
+pub enum Samples {
+    I16(Vec<i16>),
+    /// 24 bit samples, sign extended in the lower bits
+    I24(Vec<i32>),
+    I32(Vec<i32>),
+    /// Float samples in the range [-1.0, 1.0]
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+/// Interleaved audio together with its channel count and sample rate
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioBuffer {
+    pub samples : Samples,
+    pub num_channels : usize,
+    pub sample_rate : u32,
+}
+
+impl AudioBuffer {
+
+    /// Create a buffer of `num_frames` frames of silence
+    pub fn new(format : SampleFormat, num_frames : usize, num_channels : usize, sample_rate : u32) -> Self {
+        let len = num_frames * num_channels;
+        let samples = match format {
+            SampleFormat::I16 => Samples::I16(vec![0; len]),
+            SampleFormat::I24 => Samples::I24(vec![0; len]),
+            SampleFormat::I32 => Samples::I32(vec![0; len]),
+            SampleFormat::F32 => Samples::F32(vec![0.0; len]),
+            SampleFormat::F64 => Samples::F64(vec![0.0; len]),
+        };
+
+        Self { samples, num_channels, sample_rate }
+    }
+
+    pub fn format(&self) -> SampleFormat {
+        match self.samples {
+            Samples::I16(_) => SampleFormat::I16,
+            Samples::I24(_) => SampleFormat::I24,
+            Samples::I32(_) => SampleFormat::I32,
+            Samples::F32(_) => SampleFormat::F32,
+            Samples::F64(_) => SampleFormat::F64,
+        }
+    }
+
+    /// Number of samples summed over all channels
+    pub fn len(&self) -> usize {
+        match &self.samples {
+            Samples::I16(s) => s.len(),
+            Samples::I24(s) | Samples::I32(s) => s.len(),
+            Samples::F32(s) => s.len(),
+            Samples::F64(s) => s.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_frames(&self) -> usize {
+        match self.num_channels {
+            0 => 0,
+            ch => self.len() / ch,
+        }
+    }
+
+    /// Samples as 32 bit integers using the full range
+    fn to_i32_full_scale(&self) -> Vec<i32> {
+        match &self.samples {
+            Samples::I16(s) => s.iter().map(|smp| (*smp as i32) << 16).collect(),
+            Samples::I24(s) => s.iter().map(|smp| smp << 8).collect(),
+            Samples::I32(s) => s.clone(),
+            // the conversion saturates at i32::MAX for 1.0
+            Samples::F32(s) => s.iter().map(|smp| (smp.clamp(-1.0, 1.0) as f64 * 2147483648.0) as i32).collect(),
+            Samples::F64(s) => s.iter().map(|smp| (smp.clamp(-1.0, 1.0) * 2147483648.0) as i32).collect(),
+        }
+    }
+
+    fn to_f64(&self) -> Vec<f64> {
+        match &self.samples {
+            Samples::I16(s) => s.iter().map(|smp| *smp as f64 / 32768.0).collect(),
+            Samples::I24(s) => s.iter().map(|smp| *smp as f64 / 8388608.0).collect(),
+            Samples::I32(s) => s.iter().map(|smp| *smp as f64 / 2147483648.0).collect(),
+            Samples::F32(s) => s.iter().map(|smp| *smp as f64).collect(),
+            Samples::F64(s) => s.clone(),
+        }
+    }
+
+    /// Convert the samples to `format`. Integer formats are scaled to their full range, float samples are clipped
+    /// to [-1.0, 1.0] when converted to integers.
+    pub fn convert(&self, format : SampleFormat) -> AudioBuffer {
+        if format == self.format() {
+            return self.clone();
+        }
+
+        let samples = match format {
+            SampleFormat::I16 => Samples::I16(self.to_i32_full_scale().iter().map(|smp| (smp >> 16) as i16).collect()),
+            SampleFormat::I24 => Samples::I24(self.to_i32_full_scale().iter().map(|smp| smp >> 8).collect()),
+            SampleFormat::I32 => Samples::I32(self.to_i32_full_scale()),
+            SampleFormat::F32 => Samples::F32(self.to_f64().iter().map(|smp| *smp as f32).collect()),
+            SampleFormat::F64 => Samples::F64(self.to_f64()),
+        };
+
+        AudioBuffer { samples, num_channels : self.num_channels, sample_rate : self.sample_rate }
+    }
+
+    /// Decode the payload of a PCM packet. The payload must hold whole frames of `num_channels` samples.
+    pub fn from_payload(data : &[u8], bit_resolution : VBanBitResolution, num_channels : usize, sample_rate : u32) -> Result<Self, Error> {
+        let frame_bits = bit_resolution.bits().unwrap_or(0) as usize * num_channels;
+        if frame_bits > 0 && bit_resolution.payload_size(data.len() * 8 / frame_bits * num_channels) != Some(data.len()) {
+            return Err(Error::Packet(vban_packet::VbanPacketError::InvalidPayloadSize(data.len())));
+        }
+
+        let samples = match bit_resolution {
+            // 8 bit samples are unsigned
+            VBanBitResolution::VbanBitfmt8Int => Samples::I16(data.iter().map(|smp| (*smp as i16 - 128) << 8).collect()),
+            VBanBitResolution::VbanBitfmt16Int => Samples::I16(data.chunks_exact(2).map(LittleEndian::read_i16).collect()),
+            VBanBitResolution::VbanBitfmt24Int => Samples::I24(data.chunks_exact(3).map(LittleEndian::read_i24).collect()),
+            VBanBitResolution::VbanBitfmt32Int => Samples::I32(data.chunks_exact(4).map(LittleEndian::read_i32).collect()),
+            VBanBitResolution::VbanBitfmt32Float => Samples::F32(data.chunks_exact(4).map(LittleEndian::read_f32).collect()),
+            VBanBitResolution::VbanBitfmt64Float => Samples::F64(data.chunks_exact(8).map(LittleEndian::read_f64).collect()),
+            VBanBitResolution::VbanBitfmt12Int => Samples::I16(unpack_samples(data, 12)),
+            VBanBitResolution::VbanBitfmt10Int => Samples::I16(unpack_samples(data, 10)),
+            res => return Err(Error::UnsupportedFormat(format!("bit resolution {:?}", res))),
+        };
+
+        Ok(Self { samples, num_channels, sample_rate })
+    }
+
+    /// Encode the samples as payload of a PCM packet with `bit_resolution`, converting them if necessary
+    pub fn to_payload(&self, bit_resolution : VBanBitResolution) -> Result<Vec<u8>, Error> {
+        let format = match bit_resolution.sample_format() {
+            Some(f) => f,
+            None => return Err(Error::UnsupportedFormat(format!("bit resolution {:?}", bit_resolution))),
+        };
+        let buf = self.convert(format);
+
+        let payload = match (&buf.samples, bit_resolution) {
+            // 8 bit samples are unsigned
+            (Samples::I16(s), VBanBitResolution::VbanBitfmt8Int) => s.iter().map(|smp| ((*smp >> 8) + 128) as u8).collect(),
+            (Samples::I16(s), VBanBitResolution::VbanBitfmt12Int) => pack_samples(s, 12),
+            (Samples::I16(s), VBanBitResolution::VbanBitfmt10Int) => pack_samples(s, 10),
+            (Samples::I16(s), _) => s.iter().flat_map(|smp| smp.to_le_bytes()).collect(),
+            (Samples::I24(s), _) => s.iter().flat_map(|smp| {
+                let le = smp.to_le_bytes();
+                [le[0], le[1], le[2]]
+            }).collect(),
+            (Samples::I32(s), _) => s.iter().flat_map(|smp| smp.to_le_bytes()).collect(),
+            (Samples::F32(s), _) => s.iter().flat_map(|smp| smp.to_le_bytes()).collect(),
+            (Samples::F64(s), _) => s.iter().flat_map(|smp| smp.to_le_bytes()).collect(),
+        };
+
+        Ok(payload)
+    }
+}
+
+
 // ****************************************
 //             VBAN SINK 
 // ****************************************
 pub trait VbanSink {
-    fn write(&self, buf : &[i16]);
+    /// Sample formats the sink plays without converting them
+    fn native_formats(&self) -> &'static [SampleFormat];
+
+    fn write(&self, buf : &AudioBuffer);
 }
 
 // ****************************************
@@ -480,43 +857,100 @@ pub trait VbanSink {
 #[cfg(feature = "alsa")]
 pub struct AlsaSink {
     pcm : PCM,
+
+    /// Sample format the sink was opened for
+    format : SampleFormat,
+
+    /// Sample format negotiated with the device
+    device_format : SampleFormat,
+
+    /// Sample rate negotiated with the device
+    rate : u32,
+}
+
+/// Sample formats ALSA devices are driven with. 24 bit samples are transferred packed (S24_3LE).
+#[cfg(feature = "alsa")]
+const ALSA_NATIVE_FORMATS : [SampleFormat; 5] = [SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32, SampleFormat::F64];
+
+#[cfg(feature = "alsa")]
+fn alsa_format(format : SampleFormat) -> Format {
+    match format {
+        SampleFormat::I16 => Format::s16(),
+        SampleFormat::I24 => Format::s24_3(),
+        SampleFormat::I32 => Format::s32(),
+        SampleFormat::F32 => Format::float(),
+        SampleFormat::F64 => Format::float64(),
+    }
+}
+
+/// Pick the first sample format out of the preferred ones for `format` that the device supports.
+#[cfg(feature = "alsa")]
+fn negotiate_format(hwp : &HwParams, format : SampleFormat) -> Result<SampleFormat, Error> {
+    let candidates : &[SampleFormat] = match format {
+        SampleFormat::I16 => &[SampleFormat::I16, SampleFormat::I32, SampleFormat::F32],
+        SampleFormat::I24 => &[SampleFormat::I24, SampleFormat::I32, SampleFormat::I16],
+        SampleFormat::I32 => &[SampleFormat::I32, SampleFormat::I24, SampleFormat::I16],
+        SampleFormat::F32 => &[SampleFormat::F32, SampleFormat::I32, SampleFormat::I16],
+        SampleFormat::F64 => &[SampleFormat::F64, SampleFormat::F32, SampleFormat::I32, SampleFormat::I16],
+    };
+
+    for candidate in candidates {
+        if hwp.test_format(alsa_format(*candidate)).is_ok() {
+            if *candidate != format {
+                info!("Device does not support {}, converting to {}.", alsa_format(format), alsa_format(*candidate));
+            }
+            return Ok(*candidate);
+        }
+    }
+
+    Err(Error::UnsupportedFormat(format!("device supports none of the sample formats for {:?}", format)))
 }
 
 #[cfg(feature = "alsa")]
 impl AlsaSink {
 
-    pub fn init(device : &str, num_channels : Option<u32>, sample_rate : Option<u32>) -> Option<Self> {
+    pub fn init(device : &str, num_channels : Option<u32>, sample_rate : Option<u32>) -> Result<Self, Error> {
+        AlsaSink::init_with_format(device, num_channels, sample_rate, SampleFormat::I16)
+    }
 
-        let sink = Self {
-            pcm : {
-                PCM::new(device, Direction::Playback, false).expect("Could not create PCM.")
-            },
-        };
+    /// Open the device for samples of `format`. If the device does not support `format`, the closest format it
+    /// supports is used and samples are converted when written.
+    pub fn init_with_format(device : &str, num_channels : Option<u32>, sample_rate : Option<u32>, format : SampleFormat) -> Result<Self, Error> {
 
-        let num_channels = match num_channels {
-            None => {2},
-            Some(ch) => ch,
-        };
-        let rate = match sample_rate {
-            None => 44100,
-            Some(r) => r,
+        let mut sink = Self {
+            pcm : PCM::new(device, Direction::Playback, false)?,
+            format,
+            device_format : format,
+            rate : 0,
         };
 
+        let num_channels = num_channels.unwrap_or(2);
+        let rate = sample_rate.unwrap_or(44100);
+
         {
-            let hwp = HwParams::any(&sink.pcm).expect("Could not get hwp.");
+            let hwp = HwParams::any(&sink.pcm)?;
+
+            hwp.set_channels(num_channels)?;
+            hwp.set_rate(rate, ValueOr::Nearest)?;
+            sink.device_format = negotiate_format(&hwp, format)?;
+            hwp.set_format(alsa_format(sink.device_format))?;
+            hwp.set_access(Access::RWInterleaved)?;
+            sink.pcm.hw_params(&hwp)?;
+        }
 
-            hwp.set_channels(num_channels).expect("Could not set channel number.");
-            hwp.set_rate(rate, ValueOr::Nearest).expect("Could not set sample rate.");
-            hwp.set_format(Format::s16()).expect("Could not set sample format.");
-            hwp.set_access(Access::RWInterleaved).expect("Could not set access.");
-            sink.pcm.hw_params(&hwp).expect("Could not attach hwp to PCM.");
+        // the device may not support the requested rate
+        sink.rate = sink.pcm.hw_params_current()?.get_rate()?;
+        if sink.rate != rate {
+            info!("Device does not support {rate} Hz, using {} Hz.", sink.rate);
         }
 
         match sink.pcm.start(){
             Ok(()) => (),
             Err(errno) => {
                 error!("Error starting PCM: {errno}");
-                sink.pcm.drain().expect("Drain failed");
+                if let Err(e) = sink.pcm.drain() {
+                    error!("Drain failed ({e}).");
+                }
                 match sink.pcm.recover(errno.errno(), true){
                     Ok(()) => (),
                     Err(errno) => error!("Recovering after failed start failed too ({errno})."),
@@ -540,28 +974,40 @@ impl AlsaSink {
         // }
 
         {
-            let swp = sink.pcm.sw_params_current().unwrap();
+            let swp = sink.pcm.sw_params_current()?;
             match swp.set_start_threshold(512) {
                 Ok(()) => (),
                 Err(errno) => warn!("Could not set start_threshold sw parameter (error {errno})."),
             }
 
-            let thr = swp.get_start_threshold().unwrap();
+            let thr = swp.get_start_threshold()?;
+            debug!("Start threshold is {thr}.");
 
             // TODO? Set silence threshold?
 
         }
-        Some(sink)
+        Ok(sink)
     }
 
-}
+    /// Sample format the sink was opened for
+    pub fn format(&self) -> SampleFormat {
+        self.format
+    }
 
-#[cfg(feature = "alsa")]
-impl VbanSink for AlsaSink {
+    /// Sample rate the device plays at, which may differ from the requested one
+    pub fn sample_rate(&self) -> u32 {
+        self.rate
+    }
 
-    fn write(&self, buf : &[i16]){
-        let io = self.pcm.io_i16().unwrap();
+    /// Number of frames written to the device that have not been played yet
+    pub fn queued_frames(&self) -> usize {
+        match self.pcm.avail_delay() {
+            Ok((_, delay)) => delay.max(0) as usize,
+            Err(_) => 0,
+        }
+    }
 
+    fn write_io<S : IoFormat>(&self, io : IO<'_, S>, buf : &[S]){
         match io.writei(buf){
             Err(errno) => {
                 // Maybe try to investigate the pcm device here and try to reopen it (because broken pipe)
@@ -586,13 +1032,50 @@ impl VbanSink for AlsaSink {
     }
 }
 
+#[cfg(feature = "alsa")]
+impl VbanSink for AlsaSink {
+
+    fn native_formats(&self) -> &'static [SampleFormat] {
+        &ALSA_NATIVE_FORMATS
+    }
+
+    fn write(&self, buf : &AudioBuffer){
+        let converted;
+        let buf = match buf.format() == self.device_format {
+            true => buf,
+            false => {
+                converted = buf.convert(self.device_format);
+                &converted
+            }
+        };
+
+        match &buf.samples {
+            Samples::I16(s) => self.write_io(self.pcm.io_i16().unwrap(), s),
+            Samples::I24(s) => {
+                let bytes : Vec<u8> = s.iter().flat_map(|smp| {
+                    let le = smp.to_le_bytes();
+                    [le[0], le[1], le[2]]
+                }).collect();
+                self.write_io(self.pcm.io_bytes(), &bytes);
+            },
+            Samples::I32(s) => self.write_io(self.pcm.io_i32().unwrap(), s),
+            Samples::F32(s) => self.write_io(self.pcm.io_f32().unwrap(), s),
+            Samples::F64(s) => self.write_io(self.pcm.io_f64().unwrap(), s),
+        }
+    }
+}
+
 
 
 // ****************************************
 //             VBAN SOURCES
 // ****************************************
 pub trait VbanSource {
-    fn read(&mut self, buf : &mut [i16]);
+    /// Sample formats the source captures without converting them
+    fn native_formats(&self) -> &'static [SampleFormat];
+
+    /// Fill `buf` completely. Format, channel count and number of frames are given by `buf`.
+    fn read(&mut self, buf : &mut AudioBuffer);
 }
 
 
@@ -601,32 +1084,39 @@ pub trait VbanSource {
 // ****************************************
 #[cfg(feature = "alsa")]
 struct AlsaSource {
-    pcm : PCM
+    pcm : PCM,
+
+    /// Sample format negotiated with the device
+    device_format : SampleFormat,
 }
 
 #[cfg(feature = "alsa")]
 impl AlsaSource {
 
-    pub fn init(device : &str, num_channels : u32, sample_rate : u32) -> Option<Self> {
-        let source = Self {
-            pcm : PCM::new(device, Direction::Capture, false).expect("Could not create capture PCM")
+    pub fn init(device : &str, num_channels : u32, sample_rate : u32, format : SampleFormat) -> Result<Self, Error> {
+        let mut source = Self {
+            pcm : PCM::new(device, Direction::Capture, false)?,
+            device_format : format,
         };
 
         {
-            let hwp = HwParams::any(&source.pcm).expect("Could not get hwp.");
-
-            hwp.set_channels(num_channels).expect("Could not set channel number.");
-            hwp.set_rate(sample_rate, ValueOr::Nearest).expect("Could not set sample rate.");
-            hwp.set_format(Format::s16()).expect("Could not set sample format.");
-            hwp.set_access(Access::RWInterleaved).expect("Could not set access.");
-            source.pcm.hw_params(&hwp).expect("Could not attach hwp to PCM.");
+            let hwp = HwParams::any(&source.pcm)?;
+
+            hwp.set_channels(num_channels)?;
+            hwp.set_rate(sample_rate, ValueOr::Nearest)?;
+            source.device_format = negotiate_format(&hwp, format)?;
+            hwp.set_format(alsa_format(source.device_format))?;
+            hwp.set_access(Access::RWInterleaved)?;
+            source.pcm.hw_params(&hwp)?;
         }
 
         match source.pcm.start(){
             Ok(()) => (),
             Err(errno) => {
                 warn!("Error starting PCM: {errno}");
-                source.pcm.drain().expect("Drain failed");
+                if let Err(e) = source.pcm.drain() {
+                    error!("Drain failed ({e}).");
+                }
                 match source.pcm.recover(errno.errno(), true){
                     Ok(()) => (),
                     Err(errno) => error!("Recovering after failed start failed too ({errno}."),
@@ -635,25 +1125,25 @@ impl AlsaSource {
         }
 
         {
-            let swp = source.pcm.sw_params_current().unwrap();
+            let swp = source.pcm.sw_params_current()?;
             match swp.set_start_threshold(512) {
                 Ok(()) => (),
                 Err(errno) => warn!("Could not set start_threshold sw parameter (error {errno})."),
             }
 
-            let thr = swp.get_start_threshold().unwrap();
+            let thr = swp.get_start_threshold()?;
             // todo? set silence threshold?
             debug!("Start threshold is {thr}.");
         }
 
-        Some(source)
+        Ok(source)
     }
 }
 
 #[cfg(feature = "alsa")]
-impl VbanSource for AlsaSource {
-    fn read(&mut self, buf : &mut [i16]) {
-        let io = match self.pcm.io_i16(){
+impl AlsaSource {
+    fn read_io<S : IoFormat>(&self, io : alsa::Result<IO<'_, S>>, buf : &mut [S]) {
+        let io = match io {
             Err(e) => {
                 error!("PCM error while grabbing I/O: {e}");
                 return;
@@ -663,51 +1153,107 @@ impl VbanSource for AlsaSource {
 
         match io.readi(buf){
             Ok(frames) => trace!("PCM: read {frames} frames"),
-            Err(e) => { 
-                error!("PCM I/O Error: {e}");
-                return;
-            }
+            Err(e) => error!("PCM I/O Error: {e}"),
         }
 
     }
 }
 
+#[cfg(feature = "alsa")]
+impl VbanSource for AlsaSource {
+
+    fn native_formats(&self) -> &'static [SampleFormat] {
+        &ALSA_NATIVE_FORMATS
+    }
+
+    fn read(&mut self, buf : &mut AudioBuffer) {
+        if buf.format() != self.device_format {
+            let mut native = AudioBuffer::new(self.device_format, buf.num_frames(), buf.num_channels, buf.sample_rate);
+            self.read(&mut native);
+            *buf = native.convert(buf.format());
+            return;
+        }
+
+        match &mut buf.samples {
+            Samples::I16(s) => self.read_io(self.pcm.io_i16(), s),
+            Samples::I24(s) => {
+                let mut bytes = vec![0u8; s.len() * 3];
+                self.read_io(Ok(self.pcm.io_bytes()), &mut bytes);
+                for (smp, le) in s.iter_mut().zip(bytes.chunks_exact(3)) {
+                    *smp = LittleEndian::read_i24(le);
+                }
+            },
+            Samples::I32(s) => self.read_io(self.pcm.io_i32(), s),
+            Samples::F32(s) => self.read_io(self.pcm.io_f32(), s),
+            Samples::F64(s) => self.read_io(self.pcm.io_f64(), s),
+        }
+    }
+}
+
 #[cfg(feature = "pipewire")]
 struct PipewireSource {
     rx : Receiver<Vec<u8>>,
     remainder : Vec<u8>,
-    _handle : JoinHandle<Option<()>>
+    /// Sample format requested from pipewire
+    format : SampleFormat,
+    bytes_per_sample : usize,
+    _handle : JoinHandle<()>
 }
 
 #[cfg(feature = "pipewire")]
 impl PipewireSource {
-    pub fn init(num_channels : u32, sample_rate: u32, target : Option<String>) -> Option<Self> {
+    pub fn init(num_channels : u32, sample_rate: u32, target : Option<String>, format : SampleFormat) -> Result<Self, Error> {
+
+        // packed little endian samples, just like in VBAN packets
+        let (pw_format, bytes_per_sample) = match format {
+            SampleFormat::I16 => (AudioFormat::S16LE, 2),
+            SampleFormat::I24 => (AudioFormat::S24LE, 3),
+            SampleFormat::I32 => (AudioFormat::S32LE, 4),
+            SampleFormat::F32 => (AudioFormat::F32LE, 4),
+            SampleFormat::F64 => (AudioFormat::F64LE, 8),
+        };
 
         // create arc/mutex of self and put data into self.data in seperate thread?
 
         // create a channel, read from the channel in the sender::read function. implement a for loop in the ::handle to send all samples
         let (tx , rx) : (Sender<Vec<u8>>, Receiver<Vec<u8>>)= channel();
 
+        // the pipewire thread reports back once the stream is connected (or why it could not be connected)
+        let (init_tx, init_rx) : (Sender<Result<(), Error>>, Receiver<Result<(), Error>>) = channel();
+
+        let handle = PipewireSource::get_pw_loop_handle(num_channels, sample_rate, pw_format, target, tx, init_tx);
+
+        match init_rx.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(Error::PipeWire(pipewire::Error::CreationFailed)),
+        }
+
         let src = PipewireSource {
             rx,
 
             remainder : Vec::<u8>::new(),
 
-            _handle : PipewireSource::get_pw_loop_handle(num_channels, sample_rate, target, tx)
+            format,
+
+            bytes_per_sample,
+
+            _handle : handle
         };
 
-        Some(src)
+        Ok(src)
 
     }
 
-    fn get_pw_loop_handle(num_channels : u32, sample_rate : u32, target : Option<String>, tx: Sender<Vec<u8>>) -> JoinHandle<Option<()>> {
+    fn get_pw_loop_handle(num_channels : u32, sample_rate : u32, format : AudioFormat, target : Option<String>, tx: Sender<Vec<u8>>, init_tx : Sender<Result<(), Error>>) -> JoinHandle<()> {
         std::thread::spawn(move ||{
 
                 let mainloop = match MainLoop::new(None){
                     Ok(theloop) => theloop,
                     Err(e) => {
                         error!("Error while creating a pipewire main loop ({e}).");
-                        return None;
+                        let _ = init_tx.send(Err(e.into()));
+                        return;
                     }
                 };
 
@@ -715,7 +1261,8 @@ impl PipewireSource {
                     Ok(ctx) => ctx,
                     Err(e) => {
                         error!("Error while creating pipewire context: {e}.");
-                        return None;
+                        let _ = init_tx.send(Err(e.into()));
+                        return;
                     }
                 };
 
@@ -723,7 +1270,8 @@ impl PipewireSource {
                     Ok(c) => c,
                     Err(e) => {
                         error!("Error while connecting pipewire core to context: {e}.");
-                        return None;
+                        let _ = init_tx.send(Err(e.into()));
+                        return;
                     }
                 };
 
@@ -742,44 +1290,66 @@ impl PipewireSource {
                     *pipewire::keys::TARGET_OBJECT => tgt.as_str()
                 };
                 
-                let stream = Stream::new(&core, "vban", stream_props).unwrap();
-                let _handle = stream.add_local_listener().process( move |stream, _: &mut Vec<u8>| {
+                let stream = match Stream::new(&core, "vban", stream_props){
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Error while creating pipewire stream: {e}.");
+                        let _ = init_tx.send(Err(e.into()));
+                        return;
+                    }
+                };
+                let _handle = match stream.add_local_listener().process( move |stream, _: &mut Vec<u8>| {
                     let mut buf = match stream.dequeue_buffer(){
                         None => return,
                         Some(buffer) => buffer
                     };
-                    let size = buf.datas_mut()[0].chunk().size();
-                    let data = Vec::from(buf.datas_mut()[0].data().unwrap());
-                    let data = &data[..size as usize];
-        
-                    // let mut buffer = buffer.write().unwrap();
-                    // buffer.resize(data.len(), 0);
-                    // buffer.copy_from_slice(data);
+                    let size = buf.datas_mut()[0].chunk().size() as usize;
+                    let data = match buf.datas_mut()[0].data() {
+                        None => return,
+                        Some(d) => d,
+                    };
 
-                    let iter = data.chunks_exact(256);
-                    for chunks in iter{
-                        let _ = tx.send(chunks.to_vec());
-                    }
+                    // hand over the whole buffer, `read_bytes` splits it into packets. Dropping any bytes would shift
+                    // the frame alignment of everything that follows.
+                    let _ = tx.send(data[..size.min(data.len())].to_vec());
         
-                }).register().unwrap();
+                }).register(){
+                    Ok(h) => h,
+                    Err(e) => {
+                        error!("Error while registering pipewire stream listener: {e}.");
+                        let _ = init_tx.send(Err(e.into()));
+                        return;
+                    }
+                };
         
                 
                 // set up stream connection
                 let mut pod_data = vec![0];
                 let builder = spa::pod::builder::Builder::new(&mut pod_data);
                 let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-                audio_info.set_format(AudioFormat::S16LE);
+                audio_info.set_format(format);
                 audio_info.set_channels(num_channels);
                 audio_info.set_rate(sample_rate);
                 unsafe {
                     spa_format_audio_raw_build(builder.as_raw_ptr(), spa::sys::SPA_PARAM_EnumFormat, &mut audio_info.as_raw());
                 }
-                let pod = spa::pod::Pod::from_bytes(&pod_data).unwrap();
-                stream.connect(spa::utils::Direction::Input, Some(pipewire::constants::ID_ANY), pipewire::stream::StreamFlags::AUTOCONNECT, &mut [pod]).expect("Could not connect pipewire stream.");
+                let pod = match spa::pod::Pod::from_bytes(&pod_data){
+                    Some(p) => p,
+                    None => {
+                        error!("Could not build pipewire audio format.");
+                        let _ = init_tx.send(Err(Error::PipeWire(pipewire::Error::CreationFailed)));
+                        return;
+                    }
+                };
+                if let Err(e) = stream.connect(spa::utils::Direction::Input, Some(pipewire::constants::ID_ANY), pipewire::stream::StreamFlags::AUTOCONNECT, &mut [pod]) {
+                    error!("Could not connect pipewire stream: {e}.");
+                    let _ = init_tx.send(Err(e.into()));
+                    return;
+                }
+
+                let _ = init_tx.send(Ok(()));
                 
                 mainloop.run();
-
-                Some(())    // is never reached
             })
             
     }
@@ -787,10 +1357,9 @@ impl PipewireSource {
 }
 
 #[cfg(feature = "pipewire")]
-impl VbanSource for PipewireSource {
-    fn read(&mut self, buf : &mut [i16]) {
-
-        let bytes = buf.len() * 2;
+impl PipewireSource {
+    /// Take exactly `bytes` bytes of audio data from the pipewire thread
+    fn read_bytes(&mut self, bytes : usize) -> Vec<u8> {
 
         let mut data = match self.remainder.len() > 0 {
             false => self.rx.recv().unwrap(),
@@ -812,13 +1381,150 @@ impl VbanSource for PipewireSource {
         }
 
         if bytes != data.len(){
-            panic!("sizes of pipewire and vban data are different: data {}, vban: {}", data.len(), buf.len()*2);
+            panic!("sizes of pipewire and vban data are different: data {}, vban: {}", data.len(), bytes);
         }
 
-        for (idx, frame) in data.chunks(2).enumerate(){
-            buf[idx] = LittleEndian::read_i16(frame);
+        trace!("read {} bytes from pipewire", data.len());
+        data
+    }
+}
+
+#[cfg(feature = "pipewire")]
+impl VbanSource for PipewireSource {
+
+    fn native_formats(&self) -> &'static [SampleFormat] {
+        &[SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32, SampleFormat::F64]
+    }
+
+    fn read(&mut self, buf : &mut AudioBuffer) {
+        let data = self.read_bytes(buf.len() * self.bytes_per_sample);
+
+        let native = match AudioBuffer::from_payload(&data, self.format.bit_resolution(), buf.num_channels, buf.sample_rate) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Could not decode pipewire data: {e}");
+                return;
+            }
+        };
+
+        *buf = native.convert(buf.format());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_rate_indices_round_trip(){
+        for idx in 0..VBAN_SRLIST.len() as u8 {
+            let sr = VBanSampleRates::from(idx);
+            assert_eq!(u32::from(sr), VBAN_SRLIST[idx as usize]);
+            assert_eq!(u8::from(sr), idx);
         }
+    }
 
-        trace!("read {} bytes from pipewire", data.len());
+    #[test]
+    fn invalid_sample_rate_indices_are_not_supported(){
+        for idx in VBAN_SRLIST.len() as u8..=VBAN_SR_MASK {
+            assert_eq!(VBanSampleRates::from(idx), VBanSampleRates::SampleRateNotSupported);
+        }
+        assert_eq!(u8::from(VBanSampleRates::SampleRateNotSupported), VBAN_SR_MASK);
+        assert_eq!(VBanSampleRates::from(u8::from(VBanSampleRates::SampleRateNotSupported)), VBanSampleRates::SampleRateNotSupported);
+    }
+
+    #[test]
+    fn bit_resolutions_use_the_lower_three_bits(){
+        for idx in 0..8u8 {
+            assert_eq!(u8::from(VBanBitResolution::from(idx)), idx);
+            assert_eq!(VBanBitResolution::from(idx | 0xF8), VBanBitResolution::from(idx));
+        }
+    }
+
+    #[test]
+    fn payloads_hold_whole_frames(){
+        let stereo = AudioBuffer::from_payload(&[0; 12], VBanBitResolution::VbanBitfmt24Int, 2, 48000).unwrap();
+        assert_eq!(stereo.num_frames(), 2);
+
+        // a truncated 24 bit stereo payload would shift all later frames by a channel
+        for len in [11, 9, 3] {
+            assert!(matches!(
+                AudioBuffer::from_payload(&[0; 12][..len], VBanBitResolution::VbanBitfmt24Int, 2, 48000),
+                Err(Error::Packet(vban_packet::VbanPacketError::InvalidPayloadSize(l))) if l == len
+            ));
+        }
+
+        // packed formats round up to whole bytes
+        assert_eq!(AudioBuffer::from_payload(&[0; 3], VBanBitResolution::VbanBitfmt12Int, 2, 48000).unwrap().num_frames(), 1);
+        assert_eq!(AudioBuffer::from_payload(&[0; 5], VBanBitResolution::VbanBitfmt10Int, 1, 48000).unwrap().num_frames(), 4);
+        assert!(AudioBuffer::from_payload(&[0; 4], VBanBitResolution::VbanBitfmt12Int, 2, 48000).is_err());
+    }
+
+    /// A source that is fed through its channel instead of a pipewire thread
+    #[cfg(feature = "pipewire")]
+    fn pipewire_source(format : SampleFormat, bytes_per_sample : usize) -> (PipewireSource, Sender<Vec<u8>>) {
+        let (tx, rx) = channel();
+        let src = PipewireSource {
+            rx,
+            remainder : Vec::new(),
+            format,
+            bytes_per_sample,
+            _handle : std::thread::spawn(|| ()),
+        };
+        (src, tx)
+    }
+
+    #[cfg(feature = "pipewire")]
+    #[test]
+    fn pipewire_read_keeps_frames_of_odd_sized_buffers(){
+        // 24 bit, 6 channels: 18 bytes per frame, the buffers below are no multiples of it or of 256
+        let num_channels = 6;
+        let samples : Vec<i32> = (0..441 * num_channels as i32 * 3).map(|i| (i * 997) % 8_388_607 - 4_000_000).collect();
+        let bytes : Vec<u8> = samples.iter().flat_map(|smp| smp.to_le_bytes()[..3].to_vec()).collect();
+
+        let (mut src, tx) = pipewire_source(SampleFormat::I24, 3);
+        let mut start = 0;
+        for end in [2646, 2653, 7653, 7654, bytes.len()] {
+            tx.send(bytes[start..end].to_vec()).unwrap();
+            start = end;
+        }
+
+        let frames = 256 / num_channels;
+        let mut received = Vec::new();
+        while received.len() + frames * num_channels <= samples.len() {
+            let mut buf = AudioBuffer::new(SampleFormat::I24, frames, num_channels, 48000);
+            src.read(&mut buf);
+            match buf.samples {
+                Samples::I24(s) => received.extend(s),
+                _ => panic!("format changed"),
+            }
+        }
+        assert!(!received.is_empty());
+        assert_eq!(received[..], samples[..received.len()]);
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "pipewire")]
+    #[test]
+    fn pipewire_read_keeps_frames_of_16_bit_multichannel_buffers(){
+        let num_channels = 6;
+        let samples : Vec<i16> = (0..441 * num_channels as i32 * 2).map(|i| (i % 30000) as i16 - 15000).collect();
+        let bytes : Vec<u8> = samples.iter().flat_map(|smp| smp.to_le_bytes()).collect();
+
+        let (mut src, tx) = pipewire_source(SampleFormat::I16, 2);
+        for chunk in bytes.chunks(2646) {
+            tx.send(chunk.to_vec()).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while received.len() + 42 * num_channels <= samples.len() {
+            let mut buf = AudioBuffer::new(SampleFormat::I16, 42, num_channels, 44100);
+            src.read(&mut buf);
+            match buf.samples {
+                Samples::I16(s) => received.extend(s),
+                _ => panic!("format changed"),
+            }
+        }
+        assert_eq!(received[..], samples[..received.len()]);
+    }
+}
@@ -0,0 +1,279 @@
+//! Allow and deny rules for the source addresses of incoming packets.
+//!
+//! VBAN has no authentication, anyone who can reach the port of a recipient can play audio on it. An [`AccessList`]
+//! restricts the senders by IP address or network (CIDR notation), optionally only for some stream names. Deny rules
+//! always win. As soon as an allow rule exists for a stream name, only the addresses of the allow rules for that stream
+//! name are accepted, streams without any allow rule stay open to everyone who is not denied.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::Error;
+
+
+// ****************************************
+//              IP Network
+// ****************************************
+
+/// A single address or a network of addresses, e.g. `192.168.1.0/24`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr : IpAddr,
+    prefix_len : u8,
+}
+
+impl IpNet {
+    /// The network of all addresses that share the first `prefix_len` bits with `addr`.
+    ///
+    /// IPv4 addresses mapped to IPv6 (`::ffff:a.b.c.d`) are stored as IPv4 networks, their prefix length counts the
+    /// 96 bits of the mapping prefix.
+    pub fn new(addr : IpAddr, prefix_len : u8) -> Result<Self, Error> {
+        let canonical = addr.to_canonical();
+        let (prefix_len, max_len) = match (addr, canonical) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => match prefix_len.checked_sub(96) {
+                Some(len) => (len, 32),
+                None => return Err(Error::InvalidAddress(format!("prefix length {prefix_len} of {addr} is shorter than the 96 bits of the IPv4 mapping"))),
+            },
+            (_, IpAddr::V4(_)) => (prefix_len, 32),
+            (_, IpAddr::V6(_)) => (prefix_len, 128),
+        };
+        if prefix_len > max_len {
+            return Err(Error::InvalidAddress(format!("prefix length {prefix_len} is too long for {addr}")));
+        }
+        Ok(IpNet { addr : canonical, prefix_len })
+    }
+
+    /// The network that only contains `addr`
+    pub fn host(addr : IpAddr) -> Self {
+        let prefix_len = match addr.to_canonical() {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        IpNet { addr : addr.to_canonical(), prefix_len }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` belongs to the network. IPv4 addresses mapped to IPv6 are compared as IPv4 addresses.
+    pub fn contains(&self, addr : &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    /// Parse an address with an optional prefix length, e.g. `10.0.0.7` or `fd00::/8`
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            None => (s, None),
+            Some((addr, len)) => (addr, Some(len)),
+        };
+        let addr = match addr.trim().parse::<IpAddr>() {
+            Ok(a) => a,
+            Err(_) => return Err(Error::InvalidAddress(format!("{addr} is not an IP address"))),
+        };
+        match prefix_len {
+            None => Ok(IpNet::host(addr)),
+            Some(len) => match len.trim().parse::<u8>() {
+                Ok(len) => IpNet::new(addr, len),
+                Err(_) => Err(Error::InvalidAddress(format!("{len} is not a prefix length"))),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+
+// ****************************************
+//              Access Rules
+// ****************************************
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessRule {
+    pub action : AccessAction,
+    pub net : IpNet,
+    /// Stream names the rule applies to, empty for all streams
+    pub streams : Vec<String>,
+}
+
+impl AccessRule {
+    /// A rule for all streams
+    pub fn new(action : AccessAction, net : IpNet) -> Self {
+        AccessRule { action, net, streams : Vec::new() }
+    }
+
+    /// A rule for the stream `stream_name` only
+    pub fn for_stream(action : AccessAction, net : IpNet, stream_name : &str) -> Self {
+        AccessRule { action, net, streams : vec![String::from(stream_name)] }
+    }
+
+    pub fn applies_to(&self, stream_name : &str) -> bool {
+        self.streams.is_empty() || self.streams.iter().any(|name| name == stream_name)
+    }
+}
+
+impl std::fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            AccessAction::Allow => "allow",
+            AccessAction::Deny => "deny",
+        };
+        match self.streams.is_empty() {
+            true => write!(f, "{action} {}", self.net),
+            false => write!(f, "{action} {} for {}", self.net, self.streams.join(", ")),
+        }
+    }
+}
+
+/// Rules that decide which senders are accepted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    rules : Vec<AccessRule>,
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        AccessList::default()
+    }
+
+    pub fn add(&mut self, rule : AccessRule){
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[AccessRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a packet of the stream `stream_name` from `addr` is accepted
+    pub fn is_allowed(&self, addr : &IpAddr, stream_name : &str) -> bool {
+        let mut restricted = false;
+        let mut allowed = false;
+
+        for rule in self.rules.iter().filter(|rule| rule.applies_to(stream_name)) {
+            let matches = rule.net.contains(addr);
+            match rule.action {
+                AccessAction::Deny if matches => return false,
+                AccessAction::Deny => (),
+                AccessAction::Allow => {
+                    restricted = true;
+                    allowed |= matches;
+                },
+            }
+        }
+
+        allowed || !restricted
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s : &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn net(s : &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefixes(){
+        assert!(net("192.168.1.0/24").contains(&ip("192.168.1.200")));
+        assert!(!net("192.168.1.0/24").contains(&ip("192.168.2.1")));
+        assert!(net("10.1.2.3/8").contains(&ip("10.255.0.1")));
+        assert!(net("0.0.0.0/0").contains(&ip("8.8.8.8")));
+        assert!(net("10.0.0.7").contains(&ip("10.0.0.7")));
+        assert!(!net("10.0.0.7").contains(&ip("10.0.0.8")));
+        assert!(!net("10.0.0.0/8").contains(&ip("fd00::1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn ipv6_prefixes(){
+        assert!(net("fd00::/8").contains(&ip("fd12::1")));
+        assert!(!net("fd00::/8").contains(&ip("fe80::1")));
+        assert!(net("::/0").contains(&ip("2001:db8::1")));
+        assert!(net("2001:db8::1/128").contains(&ip("2001:db8::1")));
+        assert!(!net("2001:db8::1/128").contains(&ip("2001:db8::2")));
+        assert!("fd00::/129".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn mapped_prefixes(){
+        let mapped = net("::ffff:10.0.0.0/104");
+        assert_eq!(mapped.addr(), ip("10.0.0.0"));
+        assert_eq!(mapped.prefix_len(), 8);
+        assert!(mapped.contains(&ip("10.20.30.40")));
+        assert!(mapped.contains(&ip("::ffff:10.20.30.40")));
+        assert!(!mapped.contains(&ip("11.0.0.1")));
+        assert_eq!(net("::ffff:10.0.0.1").prefix_len(), 32);
+        assert!(net("::ffff:0.0.0.0/96").contains(&ip("1.2.3.4")));
+        assert!("::ffff:10.0.0.0/95".parse::<IpNet>().is_err());
+        assert!("::ffff:10.0.0.0/129".parse::<IpNet>().is_err());
+        assert!(net("10.0.0.0/8").contains(&ip("::ffff:10.1.1.1")));
+    }
+
+    #[test]
+    fn malformed_networks(){
+        assert!("foo".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow(){
+        let mut list = AccessList::new();
+        list.add(AccessRule::new(AccessAction::Allow, net("192.168.1.0/24")));
+        list.add(AccessRule::new(AccessAction::Deny, net("192.168.1.66")));
+        assert!(list.is_allowed(&ip("192.168.1.5"), "Stream1"));
+        assert!(!list.is_allowed(&ip("192.168.1.66"), "Stream1"));
+        assert!(!list.is_allowed(&ip("192.168.2.5"), "Stream1"));
+    }
+
+    #[test]
+    fn rules_per_stream(){
+        let mut list = AccessList::new();
+        assert!(list.is_allowed(&ip("1.2.3.4"), "Stream1"));
+
+        list.add(AccessRule::for_stream(AccessAction::Allow, net("10.0.0.7"), "Announce"));
+        list.add(AccessRule::for_stream(AccessAction::Deny, net("10.0.0.0/8"), "Music"));
+        assert!(list.is_allowed(&ip("10.0.0.7"), "Announce"));
+        assert!(!list.is_allowed(&ip("10.0.0.8"), "Announce"));
+        assert!(!list.is_allowed(&ip("10.0.0.8"), "Music"));
+        assert!(list.is_allowed(&ip("10.0.0.8"), "Stream1"));
+    }
+}
@@ -0,0 +1,295 @@
+//! Compensation of the clock drift between a sender and the audio device of the recipient.
+//!
+//! The sample clock of the sender and the clock of the audio device never run at exactly the same rate. Left alone,
+//! the buffered audio slowly grows (sender faster) or shrinks until the buffer runs empty (device faster). The
+//! compensator follows the amount of buffered audio over time and adjusts the number of frames that are played per
+//! received frame by a tiny amount to keep the latency at its target. The correction is either applied by resampling
+//! (see [`DriftCompensator::rate_ratio`]) or, cheaper but less transparent, by dropping and repeating single frames.
+
+use std::time::Instant;
+
+use crate::{AudioBuffer, Samples};
+
+/// Time constant of the filter that smooths the measured latency, in seconds
+const LATENCY_SMOOTHING_S : f64 = 2.0;
+
+/// Proportional gain of the controller, correction per second of latency error
+const DRIFT_KP : f64 = 0.01;
+
+/// Integral gain of the controller, correction per second of latency error and second of time
+const DRIFT_KI : f64 = 0.0002;
+
+/// Largest correction that is applied. Audio clocks are usually within 100 ppm of their nominal rate.
+const DRIFT_MAX_CORRECTION : f64 = 0.001;
+
+
+// ****************************************
+//            Drift Compensation
+// ****************************************
+
+/// How the clock drift is compensated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DriftCompensation {
+    Off,
+    /// Resample the audio by a slowly adapting ratio
+    Resample,
+    /// Drop or repeat single frames
+    InsertDrop,
+}
+
+impl std::fmt::Display for DriftCompensation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriftCompensation::Off => write!(f, "off"),
+            DriftCompensation::Resample => write!(f, "resample"),
+            DriftCompensation::InsertDrop => write!(f, "insert/drop"),
+        }
+    }
+}
+
+/// State of a [`DriftCompensator`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DriftStats {
+    /// Correction of the playback rate in parts per million, positive if the sender runs faster than the device
+    pub correction_ppm : f64,
+    /// Smoothed latency in ms
+    pub latency_ms : f64,
+    /// Frames that were repeated in insert/drop mode
+    pub inserted : u64,
+    /// Frames that were dropped in insert/drop mode
+    pub dropped : u64,
+}
+
+
+// ****************************************
+//            Drift Compensator
+// ****************************************
+
+pub struct DriftCompensator {
+    mode : DriftCompensation,
+    /// Smoothed latency in seconds, `None` until the first measurement after a reset
+    latency : Option<f64>,
+    /// Integral part of the correction
+    integral : f64,
+    /// Fraction of input frames per output frame that is removed from the stream
+    correction : f64,
+    last_update : Option<Instant>,
+    /// Frames that are owed to (positive) or by (negative) the stream in insert/drop mode
+    phase : f64,
+    stats : DriftStats,
+}
+
+impl DriftCompensator {
+    pub fn new(mode : DriftCompensation) -> Self {
+        DriftCompensator {
+            mode,
+            latency : None,
+            integral : 0.0,
+            correction : 0.0,
+            last_update : None,
+            phase : 0.0,
+            stats : DriftStats::default(),
+        }
+    }
+
+    pub fn mode(&self) -> DriftCompensation {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode : DriftCompensation){
+        self.mode = mode;
+        self.reset();
+    }
+
+    pub fn stats(&self) -> DriftStats {
+        DriftStats {
+            correction_ppm : self.correction * 1e6,
+            latency_ms : self.latency.unwrap_or(0.0) * 1000.0,
+            ..self.stats
+        }
+    }
+
+    /// Start over, e.g. because a new stream starts or the format changed
+    pub fn reset(&mut self){
+        self.latency = None;
+        self.integral = 0.0;
+        self.correction = 0.0;
+        self.last_update = None;
+        self.phase = 0.0;
+    }
+
+    /// Stop measuring until the next call of [`DriftCompensator::update`], e.g. while the jitter buffer refills. The
+    /// correction is kept.
+    pub fn pause(&mut self){
+        self.last_update = None;
+    }
+
+    /// Feed a measurement of the buffered audio. `latency` and `target` are given in frames at `sample_rate`.
+    pub fn update(&mut self, latency : usize, target : f64, sample_rate : u32, now : Instant){
+        if self.mode == DriftCompensation::Off || sample_rate == 0 {
+            return;
+        }
+
+        let latency = latency as f64 / sample_rate as f64;
+        let target = target / sample_rate as f64;
+
+        let dt = match self.last_update {
+            None => 0.0,
+            Some(last) => now.duration_since(last).as_secs_f64(),
+        };
+        self.last_update = Some(now);
+
+        let smoothed = match self.latency {
+            None => latency,
+            Some(l) => l + (latency - l) * dt / (LATENCY_SMOOTHING_S + dt),
+        };
+        self.latency = Some(smoothed);
+
+        let error = smoothed - target;
+        self.integral = (self.integral + DRIFT_KI * error * dt).clamp(-DRIFT_MAX_CORRECTION, DRIFT_MAX_CORRECTION);
+        self.correction = (DRIFT_KP * error + self.integral).clamp(-DRIFT_MAX_CORRECTION, DRIFT_MAX_CORRECTION);
+    }
+
+    /// Factor by which the resampling ratio is corrected in resample mode, 1.0 in the other modes
+    pub fn rate_ratio(&self) -> f64 {
+        match self.mode {
+            DriftCompensation::Resample => 1.0 - self.correction,
+            _ => 1.0,
+        }
+    }
+
+    /// Drop or repeat a frame of `buf` in insert/drop mode, other modes leave `buf` untouched
+    pub fn process(&mut self, buf : AudioBuffer) -> AudioBuffer {
+        match self.mode {
+            DriftCompensation::Off | DriftCompensation::Resample => buf,
+            DriftCompensation::InsertDrop => {
+                self.phase += self.correction * buf.num_frames() as f64;
+                let mut buf = buf;
+                if self.phase >= 1.0 && buf.num_frames() > 1 {
+                    self.phase -= 1.0;
+                    self.stats.dropped += 1;
+                    drop_frame(&mut buf);
+                } else if self.phase <= -1.0 && !buf.is_empty() {
+                    self.phase += 1.0;
+                    self.stats.inserted += 1;
+                    repeat_frame(&mut buf);
+                }
+                buf
+            },
+        }
+    }
+}
+
+/// Remove the last frame of `buf`
+fn drop_frame(buf : &mut AudioBuffer){
+    let len = buf.len() - buf.num_channels;
+    match &mut buf.samples {
+        Samples::I16(s) => s.truncate(len),
+        Samples::I24(s) | Samples::I32(s) => s.truncate(len),
+        Samples::F32(s) => s.truncate(len),
+        Samples::F64(s) => s.truncate(len),
+    }
+}
+
+/// Play the last frame of `buf` twice
+fn repeat_frame(buf : &mut AudioBuffer){
+    let start = buf.len() - buf.num_channels;
+    match &mut buf.samples {
+        Samples::I16(s) => s.extend_from_within(start..),
+        Samples::I24(s) | Samples::I32(s) => s.extend_from_within(start..),
+        Samples::F32(s) => s.extend_from_within(start..),
+        Samples::F64(s) => s.extend_from_within(start..),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::SampleFormat;
+
+    const SAMPLE_RATE : u32 = 48000;
+    const TARGET : f64 = 960.0;
+
+    fn buffer(num_frames : usize) -> AudioBuffer {
+        let mut buf = AudioBuffer::new(SampleFormat::I16, num_frames, 2, SAMPLE_RATE);
+        if let Samples::I16(s) = &mut buf.samples {
+            s.iter_mut().enumerate().for_each(|(i, x)| *x = i as i16);
+        }
+        buf
+    }
+
+    #[test]
+    fn off_does_nothing(){
+        let mut dc = DriftCompensator::new(DriftCompensation::Off);
+        dc.update(48000, TARGET, SAMPLE_RATE, Instant::now());
+        assert_eq!(dc.stats().correction_ppm, 0.0);
+        assert_eq!(dc.rate_ratio(), 1.0);
+        assert_eq!(dc.process(buffer(256)), buffer(256));
+    }
+
+    #[test]
+    fn correction_is_clamped(){
+        let mut dc = DriftCompensator::new(DriftCompensation::Resample);
+        let start = Instant::now();
+        for n in 0..1000 {
+            dc.update(48000, TARGET, SAMPLE_RATE, start + Duration::from_millis(n * 100));
+        }
+        assert_eq!(dc.stats().correction_ppm, DRIFT_MAX_CORRECTION * 1e6);
+        assert_eq!(dc.rate_ratio(), 1.0 - DRIFT_MAX_CORRECTION);
+
+        for n in 1000..2000 {
+            dc.update(0, 48000.0, SAMPLE_RATE, start + Duration::from_millis(n * 100));
+        }
+        assert_eq!(dc.stats().correction_ppm, -DRIFT_MAX_CORRECTION * 1e6);
+        assert_eq!(dc.rate_ratio(), 1.0 + DRIFT_MAX_CORRECTION);
+    }
+
+    #[test]
+    fn converges_to_the_clock_drift(){
+        // the sender runs 100 ppm faster than the device
+        let drift = 100e-6;
+        let dt = 0.1;
+        let mut dc = DriftCompensator::new(DriftCompensation::Resample);
+        let start = Instant::now();
+        let mut latency = 0.03;
+        for n in 0..30000 {
+            dc.update((latency * SAMPLE_RATE as f64) as usize, TARGET, SAMPLE_RATE, start + Duration::from_secs_f64(n as f64 * dt));
+            latency += (drift - (1.0 - dc.rate_ratio())) * dt;
+        }
+        let stats = dc.stats();
+        assert!((stats.correction_ppm - 100.0).abs() < 5.0, "correction {} ppm", stats.correction_ppm);
+        assert!((stats.latency_ms - 20.0).abs() < 1.0, "latency {} ms", stats.latency_ms);
+    }
+
+    #[test]
+    fn insert_drop_follows_the_correction(){
+        let mut dc = DriftCompensator::new(DriftCompensation::InsertDrop);
+        assert_eq!(dc.rate_ratio(), 1.0);
+
+        // 100 ms above the target is corrected by 1000 ppm, one frame per 1000 frames
+        dc.update(TARGET as usize + 4800, TARGET, SAMPLE_RATE, Instant::now());
+        for _ in 0..3 {
+            assert_eq!(dc.process(buffer(256)).num_frames(), 256);
+        }
+        let dropped = dc.process(buffer(256));
+        assert_eq!(dropped.num_frames(), 255);
+        assert_eq!(dropped.samples, buffer(255).samples);
+        assert_eq!(dc.stats().dropped, 1);
+
+        dc.reset();
+        dc.update(0, TARGET + 4800.0, SAMPLE_RATE, Instant::now());
+        for _ in 0..3 {
+            dc.process(buffer(256));
+        }
+        let repeated = dc.process(buffer(256));
+        assert_eq!(repeated.num_frames(), 257);
+        match repeated.samples {
+            Samples::I16(s) => assert_eq!(&s[510..], &[510, 511, 510, 511]),
+            _ => unreachable!(),
+        }
+        assert_eq!(dc.stats().inserted, 1);
+    }
+}
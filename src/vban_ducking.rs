@@ -0,0 +1,120 @@
+//! Ducking of streams while a stream of higher priority is active, as used for announcements and intercoms.
+//!
+//! Streams are ranked by a priority list of stream names. While a stream receives packets, every stream of lower
+//! priority is attenuated or silenced. The attenuation fades in with the attack time and fades out with the release
+//! time once the priority stream stops.
+
+/// Time in ms after the last packet during which a stream still counts as active
+pub const DUCKING_HOLD_MS : u64 = 250;
+
+
+// ****************************************
+//             Ducking Config
+// ****************************************
+
+/// What happens to streams of lower priority
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuckingMode {
+    /// Attenuate them by the given number of dB
+    Attenuate(f32),
+    /// Silence them entirely
+    Pause,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuckingConfig {
+    /// Stream names from the highest to the lowest priority. Streams that are not listed rank below all listed ones.
+    pub priorities : Vec<String>,
+    pub mode : DuckingMode,
+    /// Duration of the fade to the ducked gain in ms
+    pub attack_ms : u32,
+    /// Duration of the fade back to the normal gain in ms
+    pub release_ms : u32,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        DuckingConfig {
+            priorities : Vec::new(),
+            mode : DuckingMode::Attenuate(20.0),
+            attack_ms : 50,
+            release_ms : 500,
+        }
+    }
+}
+
+impl DuckingConfig {
+    /// Rank of a stream, lower ranks have a higher priority
+    pub fn rank(&self, stream_name : &str) -> usize {
+        self.priorities.iter().position(|name| name == stream_name).unwrap_or(self.priorities.len())
+    }
+
+    /// Linear gain of a ducked stream
+    pub fn ducked_gain(&self) -> f64 {
+        match self.mode {
+            DuckingMode::Attenuate(db) => 10f64.powf(-db.abs() as f64 / 20.0),
+            DuckingMode::Pause => 0.0,
+        }
+    }
+
+    /// Gain of the stream `stream_name` while the stream with the highest priority that is active has rank
+    /// `active_rank`
+    pub fn gain(&self, stream_name : &str, active_rank : Option<usize>) -> f64 {
+        match active_rank {
+            Some(rank) if rank < self.rank(stream_name) => self.ducked_gain(),
+            _ => 1.0,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode : DuckingMode) -> DuckingConfig {
+        DuckingConfig {
+            priorities : vec![String::from("Announce"), String::from("Intercom")],
+            mode,
+            ..DuckingConfig::default()
+        }
+    }
+
+    #[test]
+    fn unlisted_streams_rank_lowest(){
+        let ducking = config(DuckingMode::Pause);
+        assert_eq!(ducking.rank("Announce"), 0);
+        assert_eq!(ducking.rank("Intercom"), 1);
+        assert_eq!(ducking.rank("Music"), 2);
+        assert_eq!(ducking.rank("Stream1"), ducking.rank("Music"));
+    }
+
+    #[test]
+    fn pause_silences_lower_priorities(){
+        let ducking = config(DuckingMode::Pause);
+        assert_eq!(ducking.ducked_gain(), 0.0);
+        assert_eq!(ducking.gain("Music", Some(0)), 0.0);
+        assert_eq!(ducking.gain("Intercom", Some(0)), 0.0);
+    }
+
+    #[test]
+    fn attenuation_is_given_in_db(){
+        assert!((config(DuckingMode::Attenuate(20.0)).ducked_gain() - 0.1).abs() < 1e-12);
+        assert!((config(DuckingMode::Attenuate(-20.0)).ducked_gain() - 0.1).abs() < 1e-12);
+        assert!((config(DuckingMode::Attenuate(6.0)).ducked_gain() - 0.501).abs() < 1e-3);
+        assert_eq!(config(DuckingMode::Attenuate(0.0)).ducked_gain(), 1.0);
+    }
+
+    #[test]
+    fn only_lower_priorities_are_ducked(){
+        let ducking = config(DuckingMode::Attenuate(20.0));
+        // no stream active
+        assert_eq!(ducking.gain("Music", None), 1.0);
+        // a stream is never ducked by itself or by streams of the same or a lower priority
+        assert_eq!(ducking.gain("Announce", Some(0)), 1.0);
+        assert_eq!(ducking.gain("Announce", Some(1)), 1.0);
+        assert_eq!(ducking.gain("Music", Some(2)), 1.0);
+        assert_eq!(ducking.gain("Intercom", Some(0)), ducking.ducked_gain());
+        assert_eq!(ducking.gain("Music", Some(1)), ducking.ducked_gain());
+    }
+}
@@ -0,0 +1,444 @@
+//! Jitter buffer that puts the packets of a stream back in order and releases them with a steady latency.
+//!
+//! Packets are stored in slots indexed by their frame counter (`nu_frame`). Playback starts once the buffer holds the
+//! target latency. Afterwards the audio device pulls one slot at a time, slots of packets that did not arrive in time
+//! are handed out as lost so that the caller can conceal them. If the buffer runs empty (underrun), playback pauses
+//! until the target latency is buffered again.
+//!
+//! In adaptive mode the target latency follows the measured interarrival jitter (as defined in RFC 3550): it grows
+//! as soon as the jitter increases and slowly shrinks back towards the configured latency when the network calms down.
+
+use std::{collections::VecDeque, time::Instant};
+
+/// Factor between the measured jitter and the latency that is needed to cover it
+const JITTER_LATENCY_FACTOR : f64 = 4.0;
+
+/// Smoothing of the jitter estimate, as in RFC 3550
+const JITTER_SMOOTHING : f64 = 1.0 / 16.0;
+
+/// Smoothing when the target latency shrinks, larger targets are taken over immediately
+const TARGET_SHRINK_SMOOTHING : f64 = 1.0 / 256.0;
+
+/// Number of packets above the target latency that are tolerated before packets are dropped to reduce the latency
+const LATENCY_HYSTERESIS_PACKETS : usize = 2;
+
+
+// ****************************************
+//           Jitter Buffer Config
+// ****************************************
+
+/// Settings of a [`JitterBuffer`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JitterBufferConfig {
+    /// Latency the buffer holds in ms. In adaptive mode this is the lower bound of the target latency.
+    pub latency_ms : u32,
+    /// Upper bound of the latency in ms, packets beyond it are dropped (overrun)
+    pub max_latency_ms : u32,
+    /// Adapt the target latency to the measured network jitter
+    pub adaptive : bool,
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        JitterBufferConfig {
+            latency_ms : 10,
+            max_latency_ms : 200,
+            adaptive : true,
+        }
+    }
+}
+
+/// Counters and current state of a [`JitterBuffer`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterBufferStats {
+    /// Number of times the buffer ran empty during playback
+    pub underruns : u64,
+    /// Number of times packets were dropped because the buffer exceeded the maximum latency
+    pub overruns : u64,
+    /// Packets that arrived after their slot was played
+    pub late : u64,
+    /// Audio currently buffered in ms, including slots of missing packets
+    pub latency_ms : f64,
+    /// Latency the buffer aims for in ms
+    pub target_ms : f64,
+    /// Smoothed interarrival jitter in ms
+    pub jitter_ms : f64,
+}
+
+
+// ****************************************
+//              Jitter Buffer
+// ****************************************
+
+/// Result of [`JitterBuffer::push`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PushResult {
+    Queued,
+    /// The slot of the packet has already been played
+    Late,
+    /// The slot of the packet is already filled
+    Duplicate,
+}
+
+/// Slot handed out by [`JitterBuffer::pop`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum JitterSlot {
+    Packet(Vec<u8>),
+    /// The packet of this slot did not arrive in time
+    Lost,
+}
+
+#[derive(PartialEq)]
+enum JitterState {
+    /// Waiting for the target latency to be buffered
+    Buffering,
+    Playing,
+}
+
+pub struct JitterBuffer {
+    config : JitterBufferConfig,
+    state : JitterState,
+    /// Frame counter of the first slot
+    next : Option<u32>,
+    /// Payloads in frame counter order, `None` for packets that have not arrived (yet)
+    slots : VecDeque<Option<Vec<u8>>>,
+    /// Samples per channel of the most recent packet
+    packet_samples : usize,
+    sample_rate : u32,
+    /// Target latency in samples per channel
+    target : f64,
+    /// Reference point for arrival times
+    epoch : Instant,
+    /// Transit time of the previous packet in seconds (arrival time minus media time)
+    last_transit : Option<f64>,
+    jitter : f64,
+    stats : JitterBufferStats,
+}
+
+impl JitterBuffer {
+    pub fn new(config : JitterBufferConfig) -> Self {
+        JitterBuffer {
+            config,
+            state : JitterState::Buffering,
+            next : None,
+            slots : VecDeque::new(),
+            packet_samples : 0,
+            sample_rate : 0,
+            target : 0.0,
+            epoch : Instant::now(),
+            last_transit : None,
+            jitter : 0.0,
+            stats : JitterBufferStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &JitterBufferConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config : JitterBufferConfig){
+        self.config = config;
+        self.target = self.ms_to_samples(config.latency_ms as f64);
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        JitterBufferStats {
+            latency_ms : self.samples_to_ms(self.buffered_samples() as f64),
+            target_ms : self.samples_to_ms(self.target),
+            jitter_ms : self.jitter * 1000.0,
+            ..self.stats
+        }
+    }
+
+    /// Drop all packets and start buffering from scratch, e.g. because the sender was restarted or the format changed.
+    pub fn reset(&mut self){
+        self.state = JitterState::Buffering;
+        self.next = None;
+        self.slots.clear();
+        self.last_transit = None;
+    }
+
+    /// Playback has started and the buffer is not refilling after an underrun
+    pub fn is_playing(&self) -> bool {
+        self.state == JitterState::Playing
+    }
+
+    /// Samples per channel that are buffered, including slots of missing packets
+    pub fn buffered_samples(&self) -> usize {
+        self.slots.len() * self.packet_samples
+    }
+
+    /// Samples per channel of the most recent packet
+    pub fn packet_samples(&self) -> usize {
+        self.packet_samples
+    }
+
+    /// Payload of the next slot, if it has arrived
+    pub fn peek(&self) -> Option<&[u8]> {
+        self.slots.front().and_then(|slot| slot.as_deref())
+    }
+
+    /// Insert the payload of a packet with `num_samples` samples per channel at `sample_rate` Hz.
+    pub fn push(&mut self, nu_frame : u32, payload : &[u8], num_samples : usize, sample_rate : u32, arrival : Instant) -> PushResult {
+        if sample_rate != self.sample_rate || num_samples != self.packet_samples {
+            self.sample_rate = sample_rate;
+            self.packet_samples = num_samples;
+            self.reset();
+            self.target = self.ms_to_samples(self.config.latency_ms as f64);
+        }
+
+        self.update_jitter(nu_frame, arrival);
+
+        let next = match self.next {
+            None => {
+                self.next = Some(nu_frame);
+                nu_frame
+            },
+            Some(n) => n,
+        };
+
+        let idx = nu_frame.wrapping_sub(next);
+        if idx > u32::MAX / 2 {
+            self.stats.late += 1;
+            return PushResult::Late;
+        }
+
+        let mut idx = idx as usize;
+        if idx >= self.max_slots() {
+            // the packet exceeds the maximum latency, keep only the target latency up to the packet
+            self.stats.overruns += 1;
+            let keep = (self.target_slots() - 1).min(idx);
+            let drop = idx - keep;
+            if drop >= self.slots.len() {
+                // the packet is so far ahead that everything buffered is obsolete
+                self.slots.clear();
+                self.next = Some(nu_frame);
+                idx = 0;
+            } else {
+                for _ in 0..drop {
+                    self.drop_front();
+                }
+                idx = keep;
+            }
+        }
+
+        if idx < self.slots.len() {
+            if self.slots[idx].is_some() {
+                return PushResult::Duplicate;
+            }
+            self.slots[idx] = Some(payload.to_vec());
+            return PushResult::Queued;
+        }
+
+        self.slots.resize(idx, None);
+        self.slots.push_back(Some(payload.to_vec()));
+        PushResult::Queued
+    }
+
+    /// Take the next slot. Returns `None` while the buffer is filling up to the target latency.
+    pub fn pop(&mut self) -> Option<JitterSlot> {
+        if self.state == JitterState::Buffering {
+            if self.slots.is_empty() || self.slots.len() < self.target_slots() {
+                return None;
+            }
+            self.state = JitterState::Playing;
+        }
+
+        // shrink the latency if the network allows for it
+        if self.config.adaptive && self.slots.len() > self.target_slots() + LATENCY_HYSTERESIS_PACKETS {
+            self.drop_front();
+        }
+
+        match self.slots.pop_front() {
+            None => {
+                self.stats.underruns += 1;
+                self.state = JitterState::Buffering;
+                None
+            },
+            Some(slot) => {
+                self.next = self.next.map(|n| n.wrapping_add(1));
+                match slot {
+                    Some(payload) => Some(JitterSlot::Packet(payload)),
+                    None => Some(JitterSlot::Lost),
+                }
+            }
+        }
+    }
+
+    fn drop_front(&mut self){
+        self.slots.pop_front();
+        self.next = self.next.map(|n| n.wrapping_add(1));
+    }
+
+    fn update_jitter(&mut self, nu_frame : u32, arrival : Instant){
+        let media_time = nu_frame as f64 * self.packet_samples as f64 / self.sample_rate as f64;
+        let transit = arrival.duration_since(self.epoch).as_secs_f64() - media_time;
+
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs();
+            // jumps of the frame counter are not network jitter
+            if d < self.config.max_latency_ms as f64 / 1000.0 {
+                self.jitter += (d - self.jitter) * JITTER_SMOOTHING;
+            }
+        }
+        self.last_transit = Some(transit);
+
+        if self.config.adaptive {
+            let min = self.ms_to_samples(self.config.latency_ms as f64);
+            let max = self.ms_to_samples(self.config.max_latency_ms as f64);
+            let desired = (self.packet_samples as f64 + JITTER_LATENCY_FACTOR * self.jitter * self.sample_rate as f64).clamp(min, max.max(min));
+            self.target = match desired > self.target {
+                true => desired,
+                false => self.target + (desired - self.target) * TARGET_SHRINK_SMOOTHING,
+            };
+        }
+    }
+
+    fn target_slots(&self) -> usize {
+        match self.packet_samples {
+            0 => 1,
+            n => (self.target / n as f64).ceil().max(1.0) as usize,
+        }
+    }
+
+    fn max_slots(&self) -> usize {
+        match self.packet_samples {
+            0 => 1,
+            n => (self.ms_to_samples(self.config.max_latency_ms as f64) / n as f64).ceil().max(1.0) as usize,
+        }
+    }
+
+    fn ms_to_samples(&self, ms : f64) -> f64 {
+        ms * self.sample_rate as f64 / 1000.0
+    }
+
+    fn samples_to_ms(&self, samples : f64) -> f64 {
+        match self.sample_rate {
+            0 => 0.0,
+            sr => samples * 1000.0 / sr as f64,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES : usize = 256;
+    const SAMPLE_RATE : u32 = 48000;
+
+    /// 20 ms are 4 packets of 256 samples at 48 kHz, 100 ms are 19 packets
+    fn buffer() -> JitterBuffer {
+        JitterBuffer::new(JitterBufferConfig { latency_ms : 20, max_latency_ms : 100, adaptive : false })
+    }
+
+    fn push(jb : &mut JitterBuffer, nu_frame : u32) -> PushResult {
+        jb.push(nu_frame, &[nu_frame as u8], SAMPLES, SAMPLE_RATE, Instant::now())
+    }
+
+    fn packet(nu_frame : u32) -> Option<JitterSlot> {
+        Some(JitterSlot::Packet(vec![nu_frame as u8]))
+    }
+
+    #[test]
+    fn reorders_packets(){
+        let mut jb = buffer();
+        for n in [0, 2, 1] {
+            assert_eq!(push(&mut jb, n), PushResult::Queued);
+            assert_eq!(jb.pop(), None);
+        }
+        push(&mut jb, 3);
+        assert_eq!(jb.stats().latency_ms.round(), 21.0);
+        for n in 0..4 {
+            assert_eq!(jb.pop(), packet(n));
+        }
+        assert!(jb.is_playing());
+    }
+
+    #[test]
+    fn missing_late_and_duplicate_packets(){
+        let mut jb = buffer();
+        for n in [0, 1, 3, 4] {
+            push(&mut jb, n);
+        }
+        assert_eq!(push(&mut jb, 4), PushResult::Duplicate);
+        assert_eq!(jb.pop(), packet(0));
+        assert_eq!(jb.pop(), packet(1));
+        assert_eq!(jb.pop(), Some(JitterSlot::Lost));
+        assert_eq!(push(&mut jb, 2), PushResult::Late);
+        assert_eq!(jb.pop(), packet(3));
+        assert_eq!(jb.stats().late, 1);
+    }
+
+    #[test]
+    fn underrun_refills_to_the_target_latency(){
+        let mut jb = buffer();
+        for n in 0..4 {
+            push(&mut jb, n);
+        }
+        for n in 0..4 {
+            assert_eq!(jb.pop(), packet(n));
+        }
+        assert_eq!(jb.pop(), None);
+        assert_eq!(jb.stats().underruns, 1);
+        assert!(!jb.is_playing());
+
+        for n in 4..7 {
+            push(&mut jb, n);
+            assert_eq!(jb.pop(), None);
+        }
+        push(&mut jb, 7);
+        assert_eq!(jb.pop(), packet(4));
+        assert_eq!(jb.stats().underruns, 1);
+    }
+
+    #[test]
+    fn overrun_drops_back_to_the_target_latency(){
+        let mut jb = buffer();
+        for n in 0..19 {
+            push(&mut jb, n);
+        }
+        assert_eq!(jb.stats().overruns, 0);
+        push(&mut jb, 19);
+        assert_eq!(jb.stats().overruns, 1);
+        assert_eq!(jb.buffered_samples(), 4 * SAMPLES);
+        assert_eq!(jb.pop(), packet(16));
+
+        // a packet beyond the maximum latency makes everything buffered obsolete
+        push(&mut jb, 1000);
+        assert_eq!(jb.stats().overruns, 2);
+        assert_eq!(jb.buffered_samples(), SAMPLES);
+        assert_eq!(jb.peek(), Some(&[1000u32 as u8][..]));
+    }
+
+    #[test]
+    fn format_change_resets(){
+        let mut jb = buffer();
+        for n in 0..4 {
+            push(&mut jb, n);
+        }
+        jb.pop();
+        jb.push(4, &[4], 128, SAMPLE_RATE, Instant::now());
+        assert!(!jb.is_playing());
+        assert_eq!(jb.buffered_samples(), 128);
+        assert_eq!(jb.packet_samples(), 128);
+    }
+
+    #[test]
+    fn adaptive_target_follows_jitter(){
+        let mut jb = JitterBuffer::new(JitterBufferConfig { latency_ms : 10, max_latency_ms : 200, adaptive : true });
+        let start = Instant::now();
+        let packet_time = SAMPLES as f64 / SAMPLE_RATE as f64;
+        for n in 0..200u32 {
+            // every other packet arrives 8 ms late
+            let delay = (n % 2) as f64 * 0.008;
+            let arrival = start + std::time::Duration::from_secs_f64(n as f64 * packet_time + delay);
+            jb.push(n, &[0], SAMPLES, SAMPLE_RATE, arrival);
+        }
+        let stats = jb.stats();
+        assert!(stats.jitter_ms > 6.0 && stats.jitter_ms < 10.0, "jitter {} ms", stats.jitter_ms);
+        assert!(stats.target_ms > 4.0 * stats.jitter_ms, "target {} ms", stats.target_ms);
+        assert!(stats.target_ms <= 200.0);
+    }
+}
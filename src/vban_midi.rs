@@ -0,0 +1,254 @@
+//! VBAN-SERIAL sub-protocol carrying MIDI, bridged to an ALSA sequencer client.
+//!
+//! Incoming VBAN-SERIAL/MIDI packets are played on the sequencer port of the bridge, events written to that port (e.g.
+//! by a MIDI controller connected with `aconnect`) are sent to the peer as VBAN-SERIAL/MIDI packets.
+
+use std::{ffi::CString, net::{IpAddr, UdpSocket}, os::fd::AsRawFd};
+use alsa::{poll::{self, pollfd, Descriptors}, seq::{MidiEvent, PortCap, PortType, Seq}, Direction};
+use log::{debug, error, info, trace};
+use crate::{Error, VBanProtocol, VBAN_BPSLIST, VBAN_DATA_MAX_SIZE, VBAN_PROTOCOL_MAX_SIZE, VBAN_STREAM_NAME_SIZE};
+use crate::vban_packet::{VbanPacketBuilder, VbanPacketRef};
+
+const VBAN_SERIAL_TYPE_MASK : u8 = 0xF0;
+const VBAN_SERIAL_MIDI : u8 = 0x10;
+
+/// Standard MIDI bit rate
+const MIDI_BPS : u32 = 31250;
+
+/// Size of the buffer for a single decoded sequencer event (large enough for sysex messages)
+const MIDI_EVENT_BUFFER_SIZE : usize = 1024;
+
+
+// ****************************************
+//            VBAN MIDI Bridge
+// ****************************************
+pub struct VbanMidiBridge {
+
+    socket : UdpSocket,
+
+    /// Receiver of captured MIDI events. `None` only plays incoming packets.
+    peer : Option<(IpAddr, u16)>,
+
+    /// Only incoming packets of this stream are played. `None` accepts all streams.
+    stream_name_filter : Option<String>,
+
+    seq : Seq,
+
+    port : i32,
+
+    /// Converts raw MIDI bytes into sequencer events
+    encoder : MidiEvent,
+
+    /// Converts sequencer events into raw MIDI bytes
+    decoder : MidiEvent,
+
+    /// Header template for outgoing packets
+    packet : VbanPacketBuilder,
+
+    nu_frame : u32,
+}
+
+impl VbanMidiBridge {
+
+    /// Create a VbanMidiBridge object.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_addr` - (IpAddr, u16) - Local IP address and port to bind to, incoming packets are received here
+    /// * `peer` - Option<(IpAddr, u16)> - IP address and port to send captured MIDI events to
+    /// * `stream_name` - String - Name of the outgoing stream (max 16 characters)
+    /// * `stream_name_filter` - Option<String> - Only play incoming packets of this stream
+    /// * `client_name` - &str - Name of the ALSA sequencer client
+    ///
+    /// # Returns
+    /// `Ok(VbanMidiBridge)` if successful, otherwise the `Error` that prevented the bridge from being set up.
+    ///
+    pub fn create(local_addr : (IpAddr, u16), peer : Option<(IpAddr, u16)>, stream_name : String, stream_name_filter : Option<String>, client_name : &str) -> Result<Self, Error> {
+
+        for name in [Some(&stream_name), stream_name_filter.as_ref()].into_iter().flatten() {
+            if name.len() > VBAN_STREAM_NAME_SIZE {
+                return Err(Error::InvalidStreamName(format!("stream name exceeds limit of {} chars", VBAN_STREAM_NAME_SIZE)));
+            }
+        }
+
+        let socket = match UdpSocket::bind(local_addr){
+            Ok(sock) => sock,
+            Err(e) => return Err(Error::SocketBind(e)),
+        };
+        if let Err(e) = socket.set_nonblocking(true) {
+            return Err(Error::Io(e));
+        }
+
+        let seq = Seq::open(None, None, true)?;
+        let client_name = match CString::new(client_name) {
+            Ok(n) => n,
+            Err(_) => return Err(Error::UnsupportedFormat(String::from("sequencer client name contains a NUL byte"))),
+        };
+        seq.set_client_name(&client_name)?;
+
+        let port = seq.create_simple_port(&client_name,
+            PortCap::READ | PortCap::SUBS_READ | PortCap::WRITE | PortCap::SUBS_WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION)?;
+
+        let encoder = MidiEvent::new(MIDI_EVENT_BUFFER_SIZE as u32)?;
+        let decoder = MidiEvent::new(0)?;
+        decoder.enable_running_status(false);
+
+        let bps_idx = VBAN_BPSLIST.iter().position(|bps| *bps == MIDI_BPS).unwrap_or(0) as u8;
+        let packet = VbanPacketBuilder::new()
+            .protocol(VBanProtocol::VbanProtocolSerial)
+            .sample_rate_index(bps_idx)
+            .format_nbs(0)
+            .format_nbc(0)
+            .format_bit(VBAN_SERIAL_MIDI)
+            .stream_name(&stream_name);
+
+        info!("VBAN MIDI bridge ready on sequencer port {}:{}.", seq.client_id()?, port);
+
+        Ok(Self {
+            socket,
+            peer,
+            stream_name_filter,
+            seq,
+            port,
+            encoder,
+            decoder,
+            packet,
+            nu_frame : 0,
+        })
+    }
+
+    /// Wait up to one second for incoming packets or sequencer events and forward them.
+    pub fn handle(&mut self){
+        let mut fds = match (&self.seq, Some(Direction::Capture)).get() {
+            Ok(fds) => fds,
+            Err(e) => {
+                error!("Could not get sequencer poll descriptors: {e}");
+                return;
+            }
+        };
+        fds.push(pollfd { fd : self.socket.as_raw_fd(), events : poll::Flags::IN.bits(), revents : 0 });
+
+        if let Err(e) = poll::poll(&mut fds, 1000) {
+            error!("Error while polling: {e}");
+            return;
+        }
+
+        self.receive_packets();
+        self.capture_events();
+    }
+
+    /// Play all pending VBAN-SERIAL/MIDI packets on the sequencer port.
+    fn receive_packets(&mut self){
+        let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+
+        while let Ok((size, addr)) = self.socket.recv_from(&mut buf) {
+            let packet = match VbanPacketRef::parse(&buf[..size]) {
+                Ok(p) => p,
+                Err(e) => {
+                    debug!("Discarding malformed packet from {addr} ({e}).");
+                    continue;
+                }
+            };
+
+            if packet.protocol() != VBanProtocol::VbanProtocolSerial || packet.format_bit() & VBAN_SERIAL_TYPE_MASK != VBAN_SERIAL_MIDI {
+                trace!("Discarding packet from {addr} because it is not VBAN-SERIAL/MIDI.");
+                continue;
+            }
+
+            if let Some(name) = &self.stream_name_filter {
+                if name != packet.stream_name() {
+                    trace!("Discarding MIDI packet of stream {} from {addr}.", packet.stream_name());
+                    continue;
+                }
+            }
+
+            trace!("Received {} MIDI bytes from {addr}", packet.payload().len());
+            if let Err(e) = self.play(packet.payload()) {
+                error!("Could not forward MIDI data to the sequencer: {e}");
+            }
+        }
+    }
+
+    fn play(&mut self, data : &[u8]) -> Result<(), Error> {
+        let mut pos = 0;
+        while pos < data.len() {
+            let (consumed, event) = self.encoder.encode(&data[pos..])?;
+            if consumed == 0 {
+                break;
+            }
+            pos += consumed;
+
+            if let Some(mut ev) = event {
+                ev.set_source(self.port);
+                ev.set_subs();
+                ev.set_direct();
+                self.seq.event_output(&mut ev)?;
+            }
+        }
+        self.seq.drain_output()?;
+        Ok(())
+    }
+
+    /// Send all pending sequencer events as VBAN-SERIAL/MIDI packets.
+    fn capture_events(&mut self){
+        let mut midi = Vec::new();
+        let mut event_buf = [0u8; MIDI_EVENT_BUFFER_SIZE];
+
+        {
+            let mut input = self.seq.input();
+            loop {
+                match input.event_input_pending(true) {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(e) => {
+                        error!("Error while checking for sequencer events: {e}");
+                        break;
+                    }
+                }
+
+                let mut ev = match input.event_input() {
+                    Ok(ev) => ev,
+                    Err(e) => {
+                        error!("Error while reading sequencer event: {e}");
+                        break;
+                    }
+                };
+
+                match self.decoder.decode(&mut event_buf, &mut ev) {
+                    Ok(n) => midi.extend_from_slice(&event_buf[..n]),
+                    Err(_) => trace!("Ignoring sequencer event {:?} without MIDI representation", ev.get_type()),
+                }
+            }
+        }
+
+        if midi.is_empty() {
+            return;
+        }
+
+        let peer = match self.peer {
+            None => {
+                trace!("Discarding {} captured MIDI bytes because no peer is set.", midi.len());
+                return;
+            },
+            Some(peer) => peer,
+        };
+
+        for chunk in midi.chunks(VBAN_DATA_MAX_SIZE) {
+            let packet = match self.packet.clone().nu_frame(self.nu_frame).build(chunk) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Could not compose VBAN packet: {e}");
+                    return;
+                }
+            };
+
+            match self.socket.send_to(packet.as_bytes(), peer){
+                Ok(bytes) => trace!("Sent {bytes} bytes of MIDI data"),
+                Err(e) => error!("Error while sending MIDI data: {e}"),
+            }
+
+            self.nu_frame = self.nu_frame.wrapping_add(1);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Multicast and broadcast settings of the UDP sockets of senders and recipients.
+//!
+//! A sender reaches every recipient in the network at once by sending to a multicast group (e.g. `239.0.0.100`) or
+//! to the broadcast address of the subnet (e.g. `192.168.1.255`). Recipients receive multicast packets only after
+//! joining the group. Both sides can pick the network interface that carries the group; IPv4 interfaces are given by
+//! one of their addresses, IPv6 interfaces by their index. Interface names are looked up in `/sys/class/net`.
+
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::str::FromStr;
+
+use socket2::{InterfaceIndexOrAddress, SockRef};
+
+use crate::Error;
+
+/// Hops a multicast packet may travel by default, 1 keeps it in the local network
+pub const MULTICAST_DEFAULT_TTL : u32 = 1;
+
+
+// ****************************************
+//           Multicast Interface
+// ****************************************
+
+/// Network interface that sends or receives multicast packets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MulticastInterface {
+    /// Let the operating system choose the interface
+    #[default]
+    Default,
+    /// The IPv4 interface with this address
+    Addr(Ipv4Addr),
+    /// The interface with this index
+    Index(u32),
+}
+
+impl FromStr for MulticastInterface {
+    type Err = Error;
+
+    /// Parse an IPv4 address, an interface index or an interface name like `eth0`
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<Ipv4Addr>() {
+            return Ok(MulticastInterface::Addr(addr));
+        }
+        if let Ok(index) = s.parse::<u32>() {
+            return Ok(MulticastInterface::Index(index));
+        }
+        if s.is_empty() || s.contains('/') {
+            return Err(Error::InvalidAddress(format!("{s} is not a network interface")));
+        }
+        match std::fs::read_to_string(format!("/sys/class/net/{s}/ifindex")).ok().and_then(|index| index.trim().parse::<u32>().ok()) {
+            Some(index) => Ok(MulticastInterface::Index(index)),
+            None => Err(Error::InvalidAddress(format!("network interface {s} not found"))),
+        }
+    }
+}
+
+impl std::fmt::Display for MulticastInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MulticastInterface::Default => write!(f, "default interface"),
+            MulticastInterface::Addr(addr) => write!(f, "interface {addr}"),
+            MulticastInterface::Index(index) => write!(f, "interface #{index}"),
+        }
+    }
+}
+
+
+// ****************************************
+//            Multicast Config
+// ****************************************
+
+/// How a sender sends to a multicast group
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MulticastConfig {
+    /// Number of routers a packet may pass (hop limit for IPv6)
+    pub ttl : u32,
+    /// Interface the packets leave on
+    pub interface : MulticastInterface,
+    /// Deliver the packets to recipients on the sending host as well
+    pub loopback : bool,
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        MulticastConfig {
+            ttl : MULTICAST_DEFAULT_TTL,
+            interface : MulticastInterface::Default,
+            loopback : true,
+        }
+    }
+}
+
+/// Prepare `socket` for sending to the multicast group `group`
+pub fn configure_sender(socket : &UdpSocket, group : IpAddr, config : &MulticastConfig) -> Result<(), Error> {
+    if !group.is_multicast() {
+        return Err(Error::InvalidAddress(format!("{group} is not a multicast group")));
+    }
+
+    let sock = SockRef::from(socket);
+    let result = match group {
+        IpAddr::V4(_) => {
+            let interface = match config.interface {
+                MulticastInterface::Default => None,
+                MulticastInterface::Addr(addr) => Some(addr),
+                MulticastInterface::Index(_) => return Err(Error::InvalidAddress(String::from("IPv4 multicast is sent on an interface given by its address"))),
+            };
+            sock.set_multicast_ttl_v4(config.ttl)
+                .and_then(|_| sock.set_multicast_loop_v4(config.loopback))
+                .and_then(|_| match interface {
+                    None => Ok(()),
+                    Some(addr) => sock.set_multicast_if_v4(&addr),
+                })
+        },
+        IpAddr::V6(_) => {
+            let interface = match config.interface {
+                MulticastInterface::Default => 0,
+                MulticastInterface::Index(index) => index,
+                MulticastInterface::Addr(_) => return Err(Error::InvalidAddress(String::from("IPv6 multicast is sent on an interface given by its index or name"))),
+            };
+            sock.set_multicast_hops_v6(config.ttl)
+                .and_then(|_| sock.set_multicast_loop_v6(config.loopback))
+                .and_then(|_| sock.set_multicast_if_v6(interface))
+        },
+    };
+
+    result.map_err(Error::Io)
+}
+
+/// Receive the packets sent to the multicast group `group` on `socket`
+pub fn join(socket : &UdpSocket, group : IpAddr, interface : MulticastInterface) -> Result<(), Error> {
+    if !group.is_multicast() {
+        return Err(Error::InvalidAddress(format!("{group} is not a multicast group")));
+    }
+
+    let result = match (group, interface) {
+        (IpAddr::V4(group), MulticastInterface::Default) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED),
+        (IpAddr::V4(group), MulticastInterface::Addr(addr)) => socket.join_multicast_v4(&group, &addr),
+        (IpAddr::V4(group), MulticastInterface::Index(index)) => SockRef::from(socket).join_multicast_v4_n(&group, &InterfaceIndexOrAddress::Index(index)),
+        (IpAddr::V6(group), MulticastInterface::Default) => socket.join_multicast_v6(&group, 0),
+        (IpAddr::V6(group), MulticastInterface::Index(index)) => socket.join_multicast_v6(&group, index),
+        (IpAddr::V6(_), MulticastInterface::Addr(_)) => return Err(Error::InvalidAddress(String::from("IPv6 groups are joined on an interface given by its index or name"))),
+    };
+
+    result.map_err(Error::Io)
+}
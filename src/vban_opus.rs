@@ -0,0 +1,593 @@
+//! Opus encoder and decoder for VBAN streams with up to eight channels.
+//!
+//! Streams are coded with the Opus multistream API using channel mapping family 1 (Vorbis channel order), which covers
+//! mono and stereo as well as the usual surround layouts like 5.1 and 7.1. A multistream packet with a single stream is
+//! identical to a plain Opus packet, so mono and stereo streams stay compatible with other VBAN implementations.
+//!
+//! Samples on the wire and at the audio devices are interleaved in WAVE order (FL, FR, FC, LFE, BL, BR, SL, SR), which
+//! is what VBAN PCM streams and PipeWire use. They are reordered to and from Vorbis order inside the encoder and decoder.
+
+use std::{ffi::CStr, ptr};
+use audiopus_sys as ffi;
+
+use crate::{Error, VBanSampleRates, VBAN_PACKET_MAX_SAMPLES};
+
+/// Maximum number of channels covered by channel mapping family 1
+pub const OPUS_CHANNELS_MAX_NB : usize = 8;
+
+/// Default bitrate of a stereo stream, streams with more channels get a proportionally higher bitrate
+pub const OPUS_DEFAULT_BITRATE : i32 = 320000;
+
+/// Highest useful bitrate per channel, libopus caps higher bitrates anyway
+pub const OPUS_MAX_BITRATE_PER_CHANNEL : i32 = 500000;
+
+/// Channel mapping family with Vorbis channel order
+const OPUS_MAPPING_FAMILY_VORBIS : i32 = 1;
+
+/// Layout of the Opus streams for each channel count: (streams, coupled streams, mapping), as in RFC 7845 section 5.1.1.2
+const VORBIS_LAYOUTS : [(i32, i32, &[u8]); OPUS_CHANNELS_MAX_NB] = [
+    (1, 0, &[0]),
+    (1, 1, &[0, 1]),
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
+/// Channel `i` in Vorbis order is channel `WAVE_TO_VORBIS[n-1][i]` in WAVE order
+const WAVE_TO_VORBIS : [&[usize]; OPUS_CHANNELS_MAX_NB] = [
+    &[0],
+    &[0, 1],
+    &[0, 2, 1],
+    &[0, 1, 2, 3],
+    &[0, 2, 1, 3, 4],
+    &[0, 2, 1, 4, 5, 3],
+    &[0, 2, 1, 5, 6, 4, 3],
+    &[0, 2, 1, 6, 7, 4, 5, 3],
+];
+
+
+// ****************************************
+//              Opus Error
+// ****************************************
+
+/// Error code returned by libopus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpusError(pub i32);
+
+impl std::fmt::Display for OpusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // opus_strerror returns a pointer to a static string for every code
+        let msg = unsafe { CStr::from_ptr(ffi::opus_strerror(self.0)) };
+        write!(f, "{} ({})", msg.to_string_lossy(), self.0)
+    }
+}
+
+impl std::error::Error for OpusError {}
+
+fn check(code : i32) -> Result<i32, OpusError> {
+    match code {
+        c if c < 0 => Err(OpusError(c)),
+        c => Ok(c),
+    }
+}
+
+fn layout(channels : usize) -> Result<(i32, i32, &'static [u8]), OpusError> {
+    match channels {
+        1..=OPUS_CHANNELS_MAX_NB => Ok(VORBIS_LAYOUTS[channels - 1]),
+        _ => Err(OpusError(ffi::OPUS_BAD_ARG)),
+    }
+}
+
+
+// ****************************************
+//              Opus Config
+// ****************************************
+
+/// Coding mode of the encoder
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpusApplication {
+    /// Best quality for most non-voice signals like music
+    Audio,
+    /// Best quality for voice signals
+    Voip,
+    /// Lowest possible coding delay, disables the speech-optimized mode
+    LowDelay,
+}
+
+impl From<OpusApplication> for i32 {
+    fn from(value : OpusApplication) -> Self {
+        match value {
+            OpusApplication::Audio => ffi::OPUS_APPLICATION_AUDIO,
+            OpusApplication::Voip => ffi::OPUS_APPLICATION_VOIP,
+            OpusApplication::LowDelay => ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+        }
+    }
+}
+
+/// Hint for the type of signal that is encoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpusSignal {
+    Auto,
+    Voice,
+    Music,
+}
+
+impl From<OpusSignal> for i32 {
+    fn from(value : OpusSignal) -> Self {
+        match value {
+            OpusSignal::Auto => ffi::OPUS_AUTO,
+            OpusSignal::Voice => ffi::OPUS_SIGNAL_VOICE,
+            OpusSignal::Music => ffi::OPUS_SIGNAL_MUSIC,
+        }
+    }
+}
+
+/// Audio bandwidth of the encoded signal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpusBandwidth {
+    /// Chosen by the encoder depending on bitrate and signal
+    Auto,
+    /// 4 kHz passband
+    Narrowband,
+    /// 6 kHz passband
+    Mediumband,
+    /// 8 kHz passband
+    Wideband,
+    /// 12 kHz passband
+    Superwideband,
+    /// 20 kHz passband
+    Fullband,
+}
+
+impl From<OpusBandwidth> for i32 {
+    fn from(value : OpusBandwidth) -> Self {
+        match value {
+            OpusBandwidth::Auto => ffi::OPUS_AUTO,
+            OpusBandwidth::Narrowband => ffi::OPUS_BANDWIDTH_NARROWBAND,
+            OpusBandwidth::Mediumband => ffi::OPUS_BANDWIDTH_MEDIUMBAND,
+            OpusBandwidth::Wideband => ffi::OPUS_BANDWIDTH_WIDEBAND,
+            OpusBandwidth::Superwideband => ffi::OPUS_BANDWIDTH_SUPERWIDEBAND,
+            OpusBandwidth::Fullband => ffi::OPUS_BANDWIDTH_FULLBAND,
+        }
+    }
+}
+
+/// Duration of the audio in one Opus packet. Opus also knows frames of 40 and 60 ms, but they exceed the VBAN limit
+/// of 256 samples per packet at every sample rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpusFrameDuration {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    Ms20,
+}
+
+impl OpusFrameDuration {
+    /// Number of samples per channel in one frame at the given sample rate
+    pub fn num_samples(&self, sample_rate : u32) -> usize {
+        let tenth_ms = match self {
+            OpusFrameDuration::Ms2_5 => 25,
+            OpusFrameDuration::Ms5 => 50,
+            OpusFrameDuration::Ms10 => 100,
+            OpusFrameDuration::Ms20 => 200,
+        };
+        (sample_rate as usize * tenth_ms) / 10000
+    }
+}
+
+impl std::fmt::Display for OpusFrameDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpusFrameDuration::Ms2_5 => write!(f, "2.5 ms"),
+            OpusFrameDuration::Ms5 => write!(f, "5 ms"),
+            OpusFrameDuration::Ms10 => write!(f, "10 ms"),
+            OpusFrameDuration::Ms20 => write!(f, "20 ms"),
+        }
+    }
+}
+
+/// Settings of an [`OpusEncoder`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpusConfig {
+    /// Total bitrate of all channels in bits per second. `None` uses [`OPUS_DEFAULT_BITRATE`] scaled with the number of channels.
+    pub bitrate : Option<i32>,
+    /// Variable (true) or constant (false) bitrate
+    pub vbr : bool,
+    /// Computational complexity from 0 (fastest) to 10 (best quality)
+    pub complexity : u8,
+    /// Duration of one packet. VBAN limits a packet to 256 samples, so long frames require low sample rates.
+    pub frame_duration : OpusFrameDuration,
+    pub application : OpusApplication,
+    pub signal : OpusSignal,
+    pub bandwidth : OpusBandwidth,
+    /// Add redundant data to each packet that lets the recipient recover the previous packet if it was lost. Only
+    /// effective at bitrates where Opus uses its speech (SILK) layer, [`stream_encoder`] rejects it for frames shorter
+    /// than 10 ms.
+    pub inband_fec : bool,
+    /// Expected packet loss in percent, the encoder spends more bits on redundancy the higher it is
+    pub packet_loss_perc : u8,
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        OpusConfig {
+            bitrate : None,
+            vbr : true,
+            complexity : 10,
+            frame_duration : OpusFrameDuration::Ms5,
+            application : OpusApplication::Audio,
+            signal : OpusSignal::Auto,
+            bandwidth : OpusBandwidth::Auto,
+            inband_fec : false,
+            packet_loss_perc : 0,
+        }
+    }
+}
+
+
+// ****************************************
+//              Opus Encoder
+// ****************************************
+
+#[derive(Debug)]
+pub struct OpusEncoder {
+    state : *mut ffi::OpusMSEncoder,
+    channels : usize,
+    /// Input samples in Vorbis order
+    reordered : Vec<i16>,
+}
+
+// libopus encoder states are self-contained and may be moved between threads
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+
+    /// Create an encoder for `channels` (1-8) interleaved channels at `sample_rate` Hz (8, 12, 16, 24 or 48 kHz).
+    pub fn new(sample_rate : u32, channels : usize, config : &OpusConfig) -> Result<Self, OpusError> {
+        let (expected_streams, expected_coupled, _) = layout(channels)?;
+
+        let mut streams = 0;
+        let mut coupled = 0;
+        let mut mapping = [0u8; OPUS_CHANNELS_MAX_NB];
+        let mut error = 0;
+        let state = unsafe {
+            ffi::opus_multistream_surround_encoder_create(sample_rate as i32, channels as i32, OPUS_MAPPING_FAMILY_VORBIS,
+                &mut streams, &mut coupled, mapping.as_mut_ptr(), config.application.into(), &mut error)
+        };
+        check(error)?;
+        if state.is_null() {
+            return Err(OpusError(ffi::OPUS_ALLOC_FAIL));
+        }
+
+        let mut encoder = OpusEncoder { state, channels, reordered : Vec::new() };
+        // the decoder relies on the layout from the specification, it must not differ from what libopus chose
+        if streams != expected_streams || coupled != expected_coupled {
+            return Err(OpusError(ffi::OPUS_INTERNAL_ERROR));
+        }
+
+        encoder.set_bitrate(config.bitrate.unwrap_or(OPUS_DEFAULT_BITRATE / 2 * channels as i32))?;
+        encoder.set_vbr(config.vbr)?;
+        encoder.set_complexity(config.complexity)?;
+        encoder.set_signal(config.signal)?;
+        encoder.set_bandwidth(config.bandwidth)?;
+        encoder.set_inband_fec(config.inband_fec)?;
+        encoder.set_packet_loss_perc(config.packet_loss_perc)?;
+        Ok(encoder)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Set the total bitrate of all streams in bits per second.
+    pub fn set_bitrate(&mut self, bitrate : i32) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_BITRATE_REQUEST, bitrate)
+    }
+
+    /// Switch between variable (true) and constant (false) bitrate.
+    pub fn set_vbr(&mut self, vbr : bool) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_VBR_REQUEST, vbr as i32)
+    }
+
+    /// Set the computational complexity from 0 (fastest) to 10 (best quality).
+    pub fn set_complexity(&mut self, complexity : u8) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity as i32)
+    }
+
+    pub fn set_signal(&mut self, signal : OpusSignal) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_SIGNAL_REQUEST, signal.into())
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth : OpusBandwidth) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_BANDWIDTH_REQUEST, bandwidth.into())
+    }
+
+    pub fn set_inband_fec(&mut self, fec : bool) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_INBAND_FEC_REQUEST, fec as i32)
+    }
+
+    /// Set the expected packet loss in percent (0-100).
+    pub fn set_packet_loss_perc(&mut self, percent : u8) -> Result<(), OpusError> {
+        self.ctl(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, percent as i32)
+    }
+
+    fn ctl(&mut self, request : i32, value : i32) -> Result<(), OpusError> {
+        check(unsafe { ffi::opus_multistream_encoder_ctl(self.state, request, value) })?;
+        Ok(())
+    }
+
+    /// Encode one frame of interleaved samples into `output`.
+    ///
+    /// # Returns
+    /// The number of bytes written to `output`.
+    pub fn encode(&mut self, input : &[i16], output : &mut [u8]) -> Result<usize, OpusError> {
+        let order = WAVE_TO_VORBIS[self.channels - 1];
+        self.reordered.clear();
+        for frame in input.chunks_exact(self.channels) {
+            self.reordered.extend(order.iter().map(|ch| frame[*ch]));
+        }
+
+        let frame_size = (self.reordered.len() / self.channels) as i32;
+        let len = unsafe {
+            ffi::opus_multistream_encode(self.state, self.reordered.as_ptr(), frame_size, output.as_mut_ptr(), output.len() as i32)
+        };
+        Ok(check(len)? as usize)
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_encoder_destroy(self.state) };
+    }
+}
+
+/// Create an Opus encoder for a VBAN stream and make sure that a frame fits into a VBAN packet.
+pub fn stream_encoder(sample_rate : VBanSampleRates, numch : u8, config : &OpusConfig) -> Result<OpusEncoder, Error> {
+    let sr = match sample_rate {
+        VBanSampleRates::SampleRate8000Hz => 8000,
+        VBanSampleRates::SampleRate12000Hz => 12000,
+        VBanSampleRates::SampleRate16000Hz => 16000,
+        VBanSampleRates::SampleRate24000Hz => 24000,
+        VBanSampleRates::SampleRate48000Hz => 48000,
+        _ => return Err(Error::UnsupportedFormat(format!("encoder OPUS does not support sample rate {}", sample_rate)))
+    };
+
+    let frame_size = config.frame_duration.num_samples(sr);
+    if frame_size > VBAN_PACKET_MAX_SAMPLES {
+        return Err(Error::UnsupportedFormat(format!("Opus frames of {} ({} samples at {} Hz) exceed the limit of {} samples", config.frame_duration, frame_size, sr, VBAN_PACKET_MAX_SAMPLES)));
+    }
+
+    // Opus ignores the FEC setting for shorter frames
+    match config.frame_duration {
+        OpusFrameDuration::Ms2_5 | OpusFrameDuration::Ms5 if config.inband_fec => {
+            return Err(Error::UnsupportedFormat(format!("Opus in-band FEC requires frames of 10 ms or more (found {}), which fit into a VBAN packet at 24 kHz or lower", config.frame_duration)));
+        },
+        _ => (),
+    }
+
+    Ok(OpusEncoder::new(sr, numch as usize, config)?)
+}
+
+
+// ****************************************
+//              Opus Decoder
+// ****************************************
+
+#[derive(Debug)]
+pub struct OpusDecoder {
+    state : *mut ffi::OpusMSDecoder,
+    channels : usize,
+    /// Decoded samples in Vorbis order
+    decoded : Vec<i16>,
+}
+
+// libopus decoder states are self-contained and may be moved between threads
+unsafe impl Send for OpusDecoder {}
+
+impl OpusDecoder {
+
+    /// Create a decoder for `channels` (1-8) interleaved channels at `sample_rate` Hz (8, 12, 16, 24 or 48 kHz).
+    pub fn new(sample_rate : u32, channels : usize) -> Result<Self, OpusError> {
+        let (streams, coupled, mapping) = layout(channels)?;
+
+        let mut error = 0;
+        let state = unsafe {
+            ffi::opus_multistream_decoder_create(sample_rate as i32, channels as i32, streams, coupled, mapping.as_ptr(), &mut error)
+        };
+        check(error)?;
+        if state.is_null() {
+            return Err(OpusError(ffi::OPUS_ALLOC_FAIL));
+        }
+
+        Ok(OpusDecoder { state, channels, decoded : Vec::new() })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Decode a packet into `output`, which must have room for the interleaved samples of the whole frame.
+    ///
+    /// # Returns
+    /// The number of decoded samples per channel.
+    pub fn decode(&mut self, input : &[u8], output : &mut [i16]) -> Result<usize, OpusError> {
+        self.decode_frame(input, output, false)
+    }
+
+    /// Recover the frame before `input` from the redundant data in `input`. Falls back to concealment if `input` carries no
+    /// redundant data. `output` must match the duration of the lost frame.
+    pub fn decode_fec(&mut self, input : &[u8], output : &mut [i16]) -> Result<usize, OpusError> {
+        self.decode_frame(input, output, true)
+    }
+
+    /// Fill `output` with an extrapolation of the previous frames in place of a lost frame.
+    pub fn conceal(&mut self, output : &mut [i16]) -> Result<usize, OpusError> {
+        self.decode_frame(&[], output, false)
+    }
+
+    fn decode_frame(&mut self, input : &[u8], output : &mut [i16], fec : bool) -> Result<usize, OpusError> {
+        let data = match input.is_empty() {
+            true => ptr::null(),
+            false => input.as_ptr(),
+        };
+
+        self.decoded.resize(output.len(), 0);
+        let frame_size = (output.len() / self.channels) as i32;
+        let num_samples = check(unsafe {
+            ffi::opus_multistream_decode(self.state, data, input.len() as i32, self.decoded.as_mut_ptr(), frame_size, fec as i32)
+        })? as usize;
+
+        let order = WAVE_TO_VORBIS[self.channels - 1];
+        for (out, dec) in output.chunks_exact_mut(self.channels).zip(self.decoded.chunks_exact(self.channels)).take(num_samples) {
+            for (vorbis_ch, wave_ch) in order.iter().enumerate() {
+                out[*wave_ch] = dec[vorbis_ch];
+            }
+        }
+        Ok(num_samples)
+    }
+}
+
+impl Drop for OpusDecoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_decoder_destroy(self.state) };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE : u32 = 48000;
+    const FRAME : usize = 480;
+
+    /// Frequency of the test tone of WAVE channel `ch`. The LFE channel (3) of surround layouts is coded with a low
+    /// bandwidth and gets a low tone.
+    fn tone(ch : usize, channels : usize) -> f64 {
+        match (ch, channels) {
+            (3, 6..) => 70.0,
+            _ => 400.0 * (ch + 1) as f64 + 37.0,
+        }
+    }
+
+    /// Power of `freq` in one channel of interleaved `samples` (Goertzel)
+    fn power(samples : &[i16], channels : usize, ch : usize, freq : f64) -> f64 {
+        let coeff = 2.0 * (2.0 * std::f64::consts::PI * freq / SAMPLE_RATE as f64).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for frame in samples.chunks_exact(channels) {
+            let s0 = frame[ch] as f64 + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        s1 * s1 + s2 * s2 - coeff * s1 * s2
+    }
+
+    /// Encode a different tone on every channel and check that each tone comes out on its own channel
+    fn round_trip(channels : usize){
+        let config = OpusConfig { frame_duration : OpusFrameDuration::Ms10, ..OpusConfig::default() };
+        let mut encoder = OpusEncoder::new(SAMPLE_RATE, channels, &config).unwrap();
+        let mut decoder = OpusDecoder::new(SAMPLE_RATE, channels).unwrap();
+        assert_eq!(encoder.channels(), channels);
+        assert_eq!(decoder.channels(), channels);
+
+        let mut decoded = Vec::new();
+        let mut packet = [0u8; 4000];
+        for n in 0..50 {
+            let input : Vec<i16> = (0..FRAME * channels).map(|i| {
+                let t = (n * FRAME + i / channels) as f64 / SAMPLE_RATE as f64;
+                (8000.0 * (2.0 * std::f64::consts::PI * tone(i % channels, channels) * t).sin()) as i16
+            }).collect();
+
+            let len = encoder.encode(&input, &mut packet).unwrap();
+            assert!(len > 0);
+
+            let mut output = vec![0i16; FRAME * channels];
+            assert_eq!(decoder.decode(&packet[..len], &mut output).unwrap(), FRAME);
+            decoded.extend(output);
+        }
+
+        // skip the start, the codec needs a few frames to settle
+        let settled = &decoded[20 * FRAME * channels..];
+        for ch in 0..channels {
+            let own = power(settled, channels, ch, tone(ch, channels));
+            for other in (0..channels).filter(|other| *other != ch) {
+                assert!(own > 100.0 * power(settled, channels, ch, tone(other, channels)),
+                    "{channels} channels: channel {ch} carries the tone of channel {other}");
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_mono(){
+        round_trip(1);
+    }
+
+    #[test]
+    fn round_trip_stereo(){
+        round_trip(2);
+    }
+
+    #[test]
+    fn round_trip_5_1(){
+        round_trip(6);
+    }
+
+    #[test]
+    fn round_trip_7_1(){
+        round_trip(8);
+    }
+
+    #[test]
+    fn invalid_channel_counts(){
+        assert!(OpusEncoder::new(SAMPLE_RATE, 0, &OpusConfig::default()).is_err());
+        assert!(OpusEncoder::new(SAMPLE_RATE, OPUS_CHANNELS_MAX_NB + 1, &OpusConfig::default()).is_err());
+        assert!(OpusDecoder::new(SAMPLE_RATE, 0).is_err());
+        assert!(OpusDecoder::new(SAMPLE_RATE, OPUS_CHANNELS_MAX_NB + 1).is_err());
+    }
+
+    #[test]
+    fn states_move_between_threads(){
+        let mut encoder = OpusEncoder::new(SAMPLE_RATE, 6, &OpusConfig::default()).unwrap();
+        let mut decoder = OpusDecoder::new(SAMPLE_RATE, 6).unwrap();
+        let (packet, encoder) = std::thread::spawn(move || {
+            let mut packet = [0u8; 4000];
+            let len = encoder.encode(&[0; 240 * 6], &mut packet).unwrap();
+            (packet[..len].to_vec(), encoder)
+        }).join().unwrap();
+        drop(encoder);
+
+        let mut output = [0i16; 240 * 6];
+        assert_eq!(decoder.decode(&packet, &mut output).unwrap(), 240);
+        assert_eq!(decoder.conceal(&mut output).unwrap(), 240);
+
+        // creating and dropping many states must neither leak nor crash
+        for channels in 1..=OPUS_CHANNELS_MAX_NB {
+            for _ in 0..50 {
+                drop(OpusEncoder::new(SAMPLE_RATE, channels, &OpusConfig::default()).unwrap());
+                drop(OpusDecoder::new(SAMPLE_RATE, channels).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn stream_encoder_limits_frames_to_vban_packets(){
+        let long = OpusConfig { frame_duration : OpusFrameDuration::Ms10, ..OpusConfig::default() };
+        assert!(stream_encoder(VBanSampleRates::SampleRate48000Hz, 2, &long).is_err());
+        assert!(stream_encoder(VBanSampleRates::SampleRate24000Hz, 2, &long).is_ok());
+        assert!(stream_encoder(VBanSampleRates::SampleRate8000Hz, 2, &long).is_ok());
+        assert!(stream_encoder(VBanSampleRates::SampleRate16000Hz, 2, &long).is_ok());
+        assert!(stream_encoder(VBanSampleRates::SampleRate44100Hz, 2, &OpusConfig::default()).is_err());
+    }
+
+    #[test]
+    fn stream_encoder_rejects_fec_with_short_frames(){
+        let fec = OpusConfig { inband_fec : true, packet_loss_perc : 10, ..OpusConfig::default() };
+        assert!(stream_encoder(VBanSampleRates::SampleRate48000Hz, 2, &fec).is_err());
+        assert!(stream_encoder(VBanSampleRates::SampleRate48000Hz, 2, &OpusConfig { frame_duration : OpusFrameDuration::Ms2_5, ..fec }).is_err());
+        assert!(stream_encoder(VBanSampleRates::SampleRate24000Hz, 2, &OpusConfig { frame_duration : OpusFrameDuration::Ms10, ..fec }).is_ok());
+        assert!(stream_encoder(VBanSampleRates::SampleRate12000Hz, 2, &OpusConfig { frame_duration : OpusFrameDuration::Ms20, ..fec }).is_ok());
+    }
+}
@@ -0,0 +1,486 @@
+//! Parsing and composition of VBAN packets.
+//!
+//! [`VbanPacketRef`] validates a received datagram and gives typed access to its header fields and payload without
+//! copying anything. [`VbanPacketBuilder`] composes packets for sending, which are returned as an owned [`VbanPacket`].
+
+use std::str::{from_utf8, Utf8Error};
+use byteorder::{ByteOrder, LittleEndian};
+use crate::{VBanBitResolution, VBanCodec, VBanHeader, VBanProtocol, VBanSampleRates, VBAN_CHANNELS_MAX_NB, VBAN_DATA_MAX_SIZE, VBAN_HEADER_SIZE, VBAN_PACKET_COUNTER_BYTES, VBAN_SAMPLES_MAX_NB, VBAN_SRLIST, VBAN_SR_MASK, VBAN_STREAM_NAME_SIZE};
+
+const VBAN_PREAMBLE : [u8; 4] = *b"VBAN";
+
+/// Size of the header including the frame counter
+const HEADER_LEN : usize = VBAN_HEADER_SIZE + VBAN_PACKET_COUNTER_BYTES;
+
+
+// ****************************************
+//            VBAN Packet Error
+// ****************************************
+
+/// Reasons for which a buffer cannot be parsed as, or composed into, a VBAN packet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VbanPacketError {
+    /// The buffer is shorter than a VBAN header (contains the length of the buffer)
+    TooShort(usize),
+    /// The first four bytes are not "VBAN"
+    BadPreamble([u8; 4]),
+    /// The sample rate index of an audio packet is out of range
+    InvalidSampleRate(u8),
+    /// The payload exceeds the maximum VBAN data size (contains the length of the payload)
+    PayloadTooLarge(usize),
+    /// The stream name exceeds 16 bytes (contains the length of the name)
+    StreamNameTooLong(usize),
+    /// The stream name is not valid UTF-8
+    InvalidStreamName(Utf8Error),
+    /// The number of samples per packet is not within 1..=256
+    InvalidSampleCount(usize),
+    /// The number of channels is not within 1..=256
+    InvalidChannelCount(usize),
+    /// The PCM payload does not hold whole frames of the header's format (contains the length of the payload)
+    InvalidPayloadSize(usize),
+}
+
+impl std::fmt::Display for VbanPacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VbanPacketError::TooShort(len) => write!(f, "packet of {len} bytes is shorter than a VBAN header ({HEADER_LEN} bytes)"),
+            VbanPacketError::BadPreamble(preamble) => write!(f, "bad preamble {:?}", preamble),
+            VbanPacketError::InvalidSampleRate(idx) => write!(f, "invalid sample rate index {idx}"),
+            VbanPacketError::PayloadTooLarge(len) => write!(f, "payload of {len} bytes exceeds the limit of {VBAN_DATA_MAX_SIZE} bytes"),
+            VbanPacketError::StreamNameTooLong(len) => write!(f, "stream name of {len} bytes exceeds the limit of {VBAN_STREAM_NAME_SIZE} bytes"),
+            VbanPacketError::InvalidStreamName(e) => write!(f, "stream name is not valid UTF-8 ({e})"),
+            VbanPacketError::InvalidSampleCount(n) => write!(f, "invalid number of samples {n} (must be 1 to {VBAN_SAMPLES_MAX_NB})"),
+            VbanPacketError::InvalidChannelCount(n) => write!(f, "invalid number of channels {n} (must be 1 to {VBAN_CHANNELS_MAX_NB})"),
+            VbanPacketError::InvalidPayloadSize(len) => write!(f, "payload of {len} bytes does not match the sample format of the header"),
+        }
+    }
+}
+
+impl std::error::Error for VbanPacketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VbanPacketError::InvalidStreamName(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+// ****************************************
+//           VBAN Packet (borrowed)
+// ****************************************
+
+/// A validated VBAN packet borrowed from a receive buffer.
+///
+/// The header fields are decoded on access. Fields whose meaning depends on the sub-protocol are also available in
+/// their raw form (`format_sr`, `format_nbs`, `format_nbc`, `format_bit`), named as in the VBAN specification.
+#[derive(Clone, Copy, Debug)]
+pub struct VbanPacketRef<'a> {
+    data : &'a [u8],
+    stream_name : &'a str,
+}
+
+impl<'a> VbanPacketRef<'a> {
+
+    /// Parse and validate a datagram.
+    ///
+    /// The stream name is cut at the first NUL byte. The sample rate index is only validated for audio packets, since
+    /// the other sub-protocols use the field differently.
+    pub fn parse(data : &'a [u8]) -> Result<Self, VbanPacketError> {
+        if data.len() < HEADER_LEN {
+            return Err(VbanPacketError::TooShort(data.len()));
+        }
+
+        if data[..4] != VBAN_PREAMBLE {
+            return Err(VbanPacketError::BadPreamble([data[0], data[1], data[2], data[3]]));
+        }
+
+        let payload_len = data.len() - HEADER_LEN;
+        if payload_len > VBAN_DATA_MAX_SIZE {
+            return Err(VbanPacketError::PayloadTooLarge(payload_len));
+        }
+
+        if VBanProtocol::from(data[4]) == VBanProtocol::VbanProtocolAudio && (data[4] & VBAN_SR_MASK) as usize >= VBAN_SRLIST.len() {
+            return Err(VbanPacketError::InvalidSampleRate(data[4] & VBAN_SR_MASK));
+        }
+
+        let name = &data[8..8 + VBAN_STREAM_NAME_SIZE];
+        let name_len = name.iter().position(|b| *b == 0).unwrap_or(VBAN_STREAM_NAME_SIZE);
+        let stream_name = match from_utf8(&name[..name_len]) {
+            Ok(s) => s,
+            Err(e) => return Err(VbanPacketError::InvalidStreamName(e)),
+        };
+
+        Ok(Self { data, stream_name })
+    }
+
+    pub fn protocol(&self) -> VBanProtocol {
+        VBanProtocol::from(self.data[4])
+    }
+
+    /// Sample rate of an audio packet. Returns `SampleRateNotSupported` for indices outside of the VBAN list, which can
+    /// only happen for non-audio packets.
+    pub fn sample_rate(&self) -> VBanSampleRates {
+        match VBAN_SRLIST.get(self.sample_rate_index() as usize) {
+            Some(rate) => VBanSampleRates::from(*rate),
+            None => VBanSampleRates::SampleRateNotSupported,
+        }
+    }
+
+    /// Lower five bits of the first format byte (sample rate or bit rate index, depending on the sub-protocol)
+    pub fn sample_rate_index(&self) -> u8 {
+        self.data[4] & VBAN_SR_MASK
+    }
+
+    /// Number of samples per channel (1-256)
+    pub fn num_samples(&self) -> usize {
+        self.data[5] as usize + 1
+    }
+
+    /// Number of channels (1-256)
+    pub fn num_channels(&self) -> usize {
+        self.data[6] as usize + 1
+    }
+
+    pub fn bit_resolution(&self) -> VBanBitResolution {
+        VBanBitResolution::from(self.data[7])
+    }
+
+    pub fn codec(&self) -> VBanCodec {
+        VBanCodec::from(self.data[7])
+    }
+
+    pub fn format_sr(&self) -> u8 {
+        self.data[4]
+    }
+
+    pub fn format_nbs(&self) -> u8 {
+        self.data[5]
+    }
+
+    pub fn format_nbc(&self) -> u8 {
+        self.data[6]
+    }
+
+    pub fn format_bit(&self) -> u8 {
+        self.data[7]
+    }
+
+    /// Stream name without trailing NUL bytes
+    pub fn stream_name(&self) -> &'a str {
+        self.stream_name
+    }
+
+    pub fn nu_frame(&self) -> u32 {
+        LittleEndian::read_u32(&self.data[VBAN_HEADER_SIZE..HEADER_LEN])
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[HEADER_LEN..]
+    }
+
+    /// The complete packet including the header
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Copy the packet into an owned [`VbanPacket`].
+    pub fn to_packet(&self) -> VbanPacket {
+        VbanPacket { data : self.data.to_vec() }
+    }
+}
+
+
+// ****************************************
+//            VBAN Packet (owned)
+// ****************************************
+
+/// An owned, valid VBAN packet as produced by [`VbanPacketBuilder`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct VbanPacket {
+    data : Vec<u8>,
+}
+
+impl VbanPacket {
+
+    pub fn as_packet_ref(&self) -> VbanPacketRef<'_> {
+        VbanPacketRef::parse(&self.data).expect("VbanPacket always holds a valid packet")
+    }
+
+    /// The complete packet including the header, ready to be sent
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[HEADER_LEN..]
+    }
+}
+
+impl TryFrom<&[u8]> for VbanPacket {
+    type Error = VbanPacketError;
+
+    fn try_from(data : &[u8]) -> Result<Self, Self::Error> {
+        VbanPacketRef::parse(data).map(|p| p.to_packet())
+    }
+}
+
+
+// ****************************************
+//           VBAN Packet Builder
+// ****************************************
+
+/// Builder for [`VbanPacket`]s.
+///
+/// Defaults to an audio packet with one sample of one channel, 48 kHz, 16 bit PCM and an empty stream name. The
+/// builder is `Clone`, so a sender can keep a configured template and only set the per-packet fields.
+#[derive(Clone, Debug)]
+pub struct VbanPacketBuilder {
+    format_sr : u8,
+    num_samples : usize,
+    num_channels : usize,
+    format_bit : u8,
+    stream_name : String,
+    nu_frame : u32,
+}
+
+impl Default for VbanPacketBuilder {
+    fn default() -> Self {
+        Self {
+            format_sr : VBanProtocol::VbanProtocolAudio as u8 | <VBanSampleRates as Into<u8>>::into(VBanSampleRates::SampleRate48000Hz),
+            num_samples : 1,
+            num_channels : 1,
+            format_bit : VBanBitResolution::VbanBitfmt16Int as u8,
+            stream_name : String::new(),
+            nu_frame : 0,
+        }
+    }
+}
+
+impl VbanPacketBuilder {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn protocol(mut self, protocol : VBanProtocol) -> Self {
+        self.format_sr = (self.format_sr & VBAN_SR_MASK) | protocol as u8;
+        self
+    }
+
+    /// Set the sample rate of an audio packet. `SampleRateNotSupported` makes [`build`](Self::build) fail.
+    pub fn sample_rate(self, sample_rate : VBanSampleRates) -> Self {
+        self.sample_rate_index(sample_rate.into())
+    }
+
+    /// Set the raw index of the first format byte, e.g. the bit rate index of serial and text packets
+    pub fn sample_rate_index(mut self, idx : u8) -> Self {
+        self.format_sr = (self.format_sr & !VBAN_SR_MASK) | (idx & VBAN_SR_MASK);
+        self
+    }
+
+    /// Number of samples per channel (1-256)
+    pub fn num_samples(mut self, num_samples : usize) -> Self {
+        self.num_samples = num_samples;
+        self
+    }
+
+    /// Number of channels (1-256)
+    pub fn num_channels(mut self, num_channels : usize) -> Self {
+        self.num_channels = num_channels;
+        self
+    }
+
+    /// Set the raw `format_nbs` byte, for sub-protocols that don't carry a sample count
+    pub fn format_nbs(self, value : u8) -> Self {
+        self.num_samples(value as usize + 1)
+    }
+
+    /// Set the raw `format_nbc` byte, for sub-protocols that don't carry a channel count
+    pub fn format_nbc(self, value : u8) -> Self {
+        self.num_channels(value as usize + 1)
+    }
+
+    pub fn bit_resolution(mut self, resolution : VBanBitResolution) -> Self {
+        let bits : u8 = resolution.into();
+        self.format_bit = (self.format_bit & !crate::VBAN_BIT_RESOLUTION_MASK) | (bits & crate::VBAN_BIT_RESOLUTION_MASK);
+        self
+    }
+
+    pub fn codec(mut self, codec : &VBanCodec) -> Self {
+        self.format_bit = (self.format_bit & !crate::VBAN_CODEC_MASK) | codec.format_bits();
+        self
+    }
+
+    /// Set the raw `format_bit` byte, overriding bit resolution and codec
+    pub fn format_bit(mut self, value : u8) -> Self {
+        self.format_bit = value;
+        self
+    }
+
+    pub fn stream_name(mut self, name : &str) -> Self {
+        self.stream_name = name.to_string();
+        self
+    }
+
+    pub fn nu_frame(mut self, nu_frame : u32) -> Self {
+        self.nu_frame = nu_frame;
+        self
+    }
+
+    /// Validate the header fields and compose a packet carrying `payload`.
+    pub fn build(&self, payload : &[u8]) -> Result<VbanPacket, VbanPacketError> {
+        if self.stream_name.len() > VBAN_STREAM_NAME_SIZE {
+            return Err(VbanPacketError::StreamNameTooLong(self.stream_name.len()));
+        }
+        if payload.len() > VBAN_DATA_MAX_SIZE {
+            return Err(VbanPacketError::PayloadTooLarge(payload.len()));
+        }
+        if self.num_samples == 0 || self.num_samples > VBAN_SAMPLES_MAX_NB as usize {
+            return Err(VbanPacketError::InvalidSampleCount(self.num_samples));
+        }
+        if self.num_channels == 0 || self.num_channels > VBAN_CHANNELS_MAX_NB {
+            return Err(VbanPacketError::InvalidChannelCount(self.num_channels));
+        }
+        if VBanProtocol::from(self.format_sr) == VBanProtocol::VbanProtocolAudio && (self.format_sr & VBAN_SR_MASK) as usize >= VBAN_SRLIST.len() {
+            return Err(VbanPacketError::InvalidSampleRate(self.format_sr & VBAN_SR_MASK));
+        }
+
+        let mut stream_name = [0u8; VBAN_STREAM_NAME_SIZE];
+        stream_name[..self.stream_name.len()].copy_from_slice(self.stream_name.as_bytes());
+
+        let hdr = VBanHeader {
+            preamble : VBAN_PREAMBLE,
+            sample_rate : self.format_sr,
+            num_samples : (self.num_samples - 1) as u8,
+            num_channels : (self.num_channels - 1) as u8,
+            sample_format : self.format_bit,
+            stream_name,
+            nu_frame : self.nu_frame
+        };
+        let hdr : [u8; HEADER_LEN] = hdr.into();
+
+        let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+        data.extend_from_slice(&hdr);
+        data.extend_from_slice(payload);
+
+        Ok(VbanPacket { data })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio_packet(payload : &[u8]) -> Vec<u8> {
+        VbanPacketBuilder::new().stream_name("Stream1").num_samples(2).num_channels(2).build(payload).unwrap().into_bytes()
+    }
+
+    #[test]
+    fn round_trip(){
+        let packet = VbanPacketBuilder::new()
+            .sample_rate(VBanSampleRates::SampleRate44100Hz)
+            .num_samples(256)
+            .num_channels(256)
+            .bit_resolution(VBanBitResolution::VbanBitfmt24Int)
+            .stream_name("Stream1")
+            .nu_frame(0xDEADBEEF)
+            .build(&[1, 2, 3])
+            .unwrap();
+
+        let parsed = VbanPacketRef::parse(packet.as_bytes()).unwrap();
+        assert_eq!(parsed.protocol(), VBanProtocol::VbanProtocolAudio);
+        assert_eq!(parsed.sample_rate(), VBanSampleRates::SampleRate44100Hz);
+        assert_eq!(parsed.num_samples(), 256);
+        assert_eq!(parsed.num_channels(), 256);
+        assert_eq!(parsed.bit_resolution(), VBanBitResolution::VbanBitfmt24Int);
+        assert!(matches!(parsed.codec(), VBanCodec::VbanCodecPcm));
+        assert_eq!(parsed.stream_name(), "Stream1");
+        assert_eq!(parsed.nu_frame(), 0xDEADBEEF);
+        assert_eq!(parsed.payload(), &[1, 2, 3]);
+        assert_eq!(parsed.to_packet(), packet);
+        assert_eq!(VbanPacket::try_from(packet.as_bytes()), Ok(packet));
+    }
+
+    #[test]
+    fn too_short(){
+        let data = audio_packet(&[]);
+        assert_eq!(VbanPacketRef::parse(&data[..HEADER_LEN - 1]).unwrap_err(), VbanPacketError::TooShort(HEADER_LEN - 1));
+        assert_eq!(VbanPacketRef::parse(&[]).unwrap_err(), VbanPacketError::TooShort(0));
+        assert!(VbanPacketRef::parse(&data[..HEADER_LEN]).is_ok());
+    }
+
+    #[test]
+    fn bad_preamble(){
+        let mut data = audio_packet(&[0; 8]);
+        data[..4].copy_from_slice(b"VBAM");
+        assert_eq!(VbanPacketRef::parse(&data).unwrap_err(), VbanPacketError::BadPreamble(*b"VBAM"));
+    }
+
+    #[test]
+    fn payload_too_large(){
+        let mut data = audio_packet(&[0; VBAN_DATA_MAX_SIZE]);
+        assert!(VbanPacketRef::parse(&data).is_ok());
+        data.push(0);
+        assert_eq!(VbanPacketRef::parse(&data).unwrap_err(), VbanPacketError::PayloadTooLarge(VBAN_DATA_MAX_SIZE + 1));
+    }
+
+    #[test]
+    fn invalid_sample_rate(){
+        let mut data = audio_packet(&[]);
+        data[4] = VBanProtocol::VbanProtocolAudio as u8 | VBAN_SRLIST.len() as u8;
+        assert_eq!(VbanPacketRef::parse(&data).unwrap_err(), VbanPacketError::InvalidSampleRate(VBAN_SRLIST.len() as u8));
+
+        // other sub-protocols use the field for something else
+        data[4] = VBanProtocol::VbanProtocolSerial as u8 | VBAN_SR_MASK;
+        let parsed = VbanPacketRef::parse(&data).unwrap();
+        assert_eq!(parsed.sample_rate_index(), VBAN_SR_MASK);
+        assert_eq!(parsed.sample_rate(), VBanSampleRates::SampleRateNotSupported);
+    }
+
+    #[test]
+    fn stream_names(){
+        let mut data = audio_packet(&[]);
+        data[8..8 + VBAN_STREAM_NAME_SIZE].copy_from_slice(b"Sixteen byte nam");
+        assert_eq!(VbanPacketRef::parse(&data).unwrap().stream_name(), "Sixteen byte nam");
+
+        // the name ends at the first NUL byte, whatever follows it
+        data[8 + 3] = 0;
+        assert_eq!(VbanPacketRef::parse(&data).unwrap().stream_name(), "Six");
+
+        data[8] = 0xFF;
+        assert!(matches!(VbanPacketRef::parse(&data), Err(VbanPacketError::InvalidStreamName(_))));
+    }
+
+    #[test]
+    fn builder_rejects_invalid_fields(){
+        let builder = VbanPacketBuilder::new();
+        assert_eq!(builder.clone().stream_name("Seventeen bytes!!").build(&[]).unwrap_err(), VbanPacketError::StreamNameTooLong(17));
+        assert_eq!(builder.build(&[0; VBAN_DATA_MAX_SIZE + 1]).unwrap_err(), VbanPacketError::PayloadTooLarge(VBAN_DATA_MAX_SIZE + 1));
+        assert_eq!(builder.clone().num_samples(0).build(&[]).unwrap_err(), VbanPacketError::InvalidSampleCount(0));
+        assert_eq!(builder.clone().num_samples(257).build(&[]).unwrap_err(), VbanPacketError::InvalidSampleCount(257));
+        assert_eq!(builder.clone().num_channels(0).build(&[]).unwrap_err(), VbanPacketError::InvalidChannelCount(0));
+        assert_eq!(builder.clone().num_channels(257).build(&[]).unwrap_err(), VbanPacketError::InvalidChannelCount(257));
+        assert_eq!(builder.clone().sample_rate(VBanSampleRates::SampleRateNotSupported).build(&[]).unwrap_err(), VbanPacketError::InvalidSampleRate(VBAN_SR_MASK));
+        assert!(builder.protocol(VBanProtocol::VbanProtocolTxt).sample_rate_index(VBAN_SR_MASK).build(&[]).is_ok());
+    }
+
+    #[test]
+    fn raw_format_fields(){
+        let packet = VbanPacketBuilder::new()
+            .protocol(VBanProtocol::VbanProtocolService)
+            .format_nbs(0)
+            .format_nbc(0)
+            .format_bit(0x55)
+            .build(&[])
+            .unwrap();
+        let parsed = packet.as_packet_ref();
+        assert_eq!(parsed.format_sr() & !VBAN_SR_MASK, VBanProtocol::VbanProtocolService as u8);
+        assert_eq!((parsed.format_nbs(), parsed.format_nbc(), parsed.format_bit()), (0, 0, 0x55));
+    }
+}
@@ -1,345 +1,1374 @@
-
-use std::{net::{IpAddr, UdpSocket}, process::Command, str::from_utf8, time::{ Duration, Instant}, usize};
-use byteorder::{ByteOrder, LittleEndian};
-use opus::{Channels, Decoder};
+use std::{collections::{HashMap, HashSet, VecDeque}, net::{IpAddr, SocketAddr, UdpSocket}, process::Command, time::{ Duration, Instant}};
 use log::{debug};
 use log::{trace, error, info, warn};
-use crate::{VBanSampleRates, VBanBitResolution,VBAN_STREAM_NAME_SIZE, PlayerState, AlsaSink, VBAN_PACKET_MAX_LEN_BYTES, VBanCodec, VBanProtocol, VBanHeader, VBAN_PACKET_HEADER_BYTES, VBAN_PACKET_COUNTER_BYTES, VBAN_SRLIST, VbanSink};
+use crate::{Error, VBanSampleRates, VBanBitResolution,VBAN_STREAM_NAME_SIZE, PlayerState, AlsaSink, VBAN_PACKET_MAX_LEN_BYTES, VBanCodec, VBanProtocol, VBAN_SRLIST, VbanSink, AudioBuffer, Samples, SampleFormat};
+use crate::vban_stats::{FrameStatus, FrameTracker, RejectedStats, VbanStreamStats};
+use crate::vban_opus::{OpusDecoder, OPUS_CHANNELS_MAX_NB};
+use crate::vban_jitter::{JitterBuffer, JitterBufferConfig, JitterBufferStats, JitterSlot, PushResult};
+use crate::vban_drift::{DriftCompensation, DriftCompensator, DriftStats};
+use crate::vban_resample::{ResampleQuality, Resampler};
+use crate::vban_routing::{ChannelMatrix, ChannelRouting};
+use crate::vban_ducking::{DuckingConfig, DUCKING_HOLD_MS};
+use crate::vban_access::{AccessList, AccessRule};
+use crate::vban_multicast::{self, MulticastInterface};
+use crate::vban_packet::{VbanPacketRef, VbanPacketError};
+use crate::vban_text::{TextHandler, VbanText};
+use crate::vban_service::{self, VbanPing0, VBAN_DEVICE_RECEPTOR, VBAN_FEATURE_AUDIO, VBAN_FEATURE_TXT};
 
+/// Audio in ms that is kept in the audio device, the jitter buffer holds the rest
+const DEVICE_FILL_MS : u32 = 5;
 
-pub struct VbanRecipient {
+/// Interval in which the jitter buffer is checked for audio to play while no packets arrive
+const PLAYOUT_INTERVAL : Duration = Duration::from_millis(2);
 
-    socket : UdpSocket,
+/// Interval in which the socket is checked while no stream is playing
+const IDLE_INTERVAL : Duration = Duration::from_secs(1);
 
-    sample_rate : Option<VBanSampleRates>,
+/// Seconds without audio after which a stream is removed from the mix
+const STREAM_TIMEOUT_S : u64 = 2;
 
-    num_channels : Option<u8>,
+/// Number of streams that are tracked at the same time, so that packets from spoofed or random addresses cannot
+/// exhaust the memory. Further streams are ignored until others time out.
+const MAX_STREAMS : usize = 256;
 
-    /// Definition of bitwidth (16, 24, 32) and integer/float type
-    sample_format : Option<VBanBitResolution>, 
+/// Duration in ms of the fade when a stream joins the mix or its gain changes
+const FADE_MS : u32 = 10;
 
-    stream_name : Option<[u8;VBAN_STREAM_NAME_SIZE]>,
 
-    nu_frame : u32,
+// ****************************************
+//             Sender Policy
+// ****************************************
 
-    state : PlayerState,
+/// Which senders are played while several of them send audio
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SenderPolicy {
+    /// Mix the streams of all senders
+    #[default]
+    MixAll,
+    /// The first sender plays until it stops sending, the others are ignored meanwhile
+    FirstWins,
+    /// A new sender takes over and the previous one fades out over `crossfade_ms`. The previous sender is ignored
+    /// until it stops sending.
+    LatestWins { crossfade_ms : u32 },
+    /// Only the sender with this address plays
+    Fixed(IpAddr),
+}
 
-    timer : Instant,
+impl std::fmt::Display for SenderPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SenderPolicy::MixAll => write!(f, "mix all senders"),
+            SenderPolicy::FirstWins => write!(f, "first sender wins"),
+            SenderPolicy::LatestWins { crossfade_ms } => write!(f, "latest sender wins ({crossfade_ms} ms crossfade)"),
+            SenderPolicy::Fixed(addr) => write!(f, "only sender {addr}"),
+        }
+    }
+}
 
-    sink : Option<AlsaSink>,
 
+// ****************************************
+//             VBAN Recipient
+// ****************************************
+
+
+/// Settings that apply to the playback of every stream
+struct PlaybackConfig {
+    /// Audio device of streams that have no entry in `devices`
     sink_name : String,
 
+    /// Audio device by stream name
+    devices : HashMap<String, String>,
+
     silence : u32,
 
+    jitter : JitterBufferConfig,
+
+    drift : DriftCompensation,
+
+    /// Rate the audio device is opened with, `None` to use the rate of the stream
+    output_rate : Option<u32>,
+
+    resample_quality : ResampleQuality,
+
+    routing : ChannelRouting,
+
+    /// Linear gain by stream name, streams without an entry are mixed at unity gain
+    gains : HashMap<String, f64>,
+
+    /// Names of the streams that are muted
+    muted : HashSet<String>,
+
+    /// Ducking of streams of lower priority, `None` to mix all streams at their gain
+    ducking : Option<DuckingConfig>,
+}
+
+impl PlaybackConfig {
+    fn device(&self, stream_name : &str) -> &str {
+        match self.devices.get(stream_name) {
+            Some(device) => device,
+            None => &self.sink_name,
+        }
+    }
+
+    fn gain(&self, stream_name : &str) -> f64 {
+        match self.muted.contains(stream_name) {
+            true => 0.0,
+            false => self.gains.get(stream_name).copied().unwrap_or(1.0),
+        }
+    }
+}
+
+
+pub struct VbanRecipient {
+
+    socket : UdpSocket,
+
+    stream_name : Option<String>,
+
+    /// Senders that are accepted
+    access : AccessList,
+
+    /// Packets that were rejected by `access`
+    rejected : RejectedStats,
+
+    /// Which of several senders are played
+    policy : SenderPolicy,
+
+    /// Stream name and address of the sender that is played unless `policy` mixes all senders
+    active : Option<(String, SocketAddr)>,
+
+    /// Playback of all audio streams that passed the stream name filter, by stream name and sender address
+    streams : HashMap<(String, SocketAddr), VbanStream>,
+
+    /// Audio devices the playing streams are mixed into, by device name
+    outputs : HashMap<String, VbanOutput>,
+
+    /// `Playing` while any stream is playing
+    state : PlayerState,
+
+    config : PlaybackConfig,
+
     command : Option<Command>,
 
-    decoder : Option<Decoder>
+    text_handler : Option<TextHandler>,
+
+    /// Sent in reply to VBAN-SERVICE pings
+    identity : VbanPing0
 }
 
 impl VbanRecipient {
 
-    pub fn create(ip_addr : IpAddr, port: u16, stream_name : Option<String>, numch : Option<u8>, sample_rate : Option<VBanSampleRates>, sink_name : String, silence : Option<u32>) -> Option<Self> {
+    /// Create a recipient that plays incoming streams on `sink_name`. Channel count and sample rate are taken from
+    /// the streams, `sample_rate` is announced as the preferred rate in reply to VBAN-SERVICE pings.
+    pub fn create(ip_addr : IpAddr, port: u16, stream_name : Option<String>, _numch : Option<u8>, sample_rate : Option<VBanSampleRates>, sink_name : String, silence : Option<u32>) -> Result<Self, Error> {
 
-        let sn: Option<[u8; 16]> = match stream_name {
+        let sn = match stream_name {
             None => None,
             Some(name) => {
                 if name.len() > VBAN_STREAM_NAME_SIZE {
-                    dbg!("Stream name exceeds the limit of {} characters", VBAN_STREAM_NAME_SIZE);
-                    return None;
-                }
-                let mut sn: [u8; 16] = [0u8; 16];
-                for (idx, b) in name.bytes().enumerate(){
-                    if idx >= VBAN_STREAM_NAME_SIZE {
-                        break;
-                    }
-                    sn[idx] = b;
+                    return Err(Error::InvalidStreamName(format!("stream name exceeds the limit of {} characters", VBAN_STREAM_NAME_SIZE)));
                 }
-                Some(sn)
+                Some(name)
             }
         };
-        
+
+        let mut identity = VbanPing0::new(VBAN_DEVICE_RECEPTOR, VBAN_FEATURE_AUDIO | VBAN_FEATURE_TXT, "rvban");
+        identity.device_name = sink_name.clone();
+        if let Some(sr) = sample_rate.and_then(|sr| VBAN_SRLIST.get(sr as usize)) {
+            identity.prefered_rate = *sr;
+        }
+
         let to_addr = (ip_addr, port);
         let result  = VbanRecipient{
             socket :  match UdpSocket::bind(to_addr){
                 Ok(sock) => sock,
-                Err(_) => {
-                    dbg!("Could not create socket");
-                    return None;
-                },
+                Err(e) => return Err(Error::SocketBind(e)),
             },
-            
-            sample_rate : sample_rate,
-            
-            num_channels : numch,
-            
-            sample_format : None,
-            
+
             stream_name : sn,
 
-            nu_frame : 0,
-            
+            access : AccessList::new(),
+
+            rejected : RejectedStats::default(),
+
+            policy : SenderPolicy::default(),
+
+            active : None,
+
+            streams : HashMap::new(),
+
+            outputs : HashMap::new(),
+
             state : PlayerState::Idle,
 
-            timer : Instant::now(),
+            config : PlaybackConfig {
+                sink_name,
+
+                devices : HashMap::new(),
+
+                silence : silence.unwrap_or_default(),
+
+                jitter : JitterBufferConfig::default(),
+
+                drift : DriftCompensation::Off,
+
+                output_rate : None,
+
+                resample_quality : ResampleQuality::default(),
 
-            sink : None,
+                routing : ChannelRouting::default(),
 
-            sink_name,
+                gains : HashMap::new(),
 
-            silence : match silence {
-                None => 0,
-                Some(val) => val,
+                muted : HashSet::new(),
+
+                ducking : None,
             },
 
             command : None,
 
-            decoder : None
+            text_handler : None,
+
+            identity
         };
 
-        result.socket.set_read_timeout(Some(Duration::new(1, 0))).expect("Could not set timeout of socket");
+        if let Err(e) = result.socket.set_read_timeout(Some(IDLE_INTERVAL)) {
+            return Err(Error::Io(e));
+        }
 
         info!("VBAN recepipient ready. Waiting for incoming audio packets...");
-        Some(result)
+        Ok(result)
     }
-    
+
 
     pub fn handle(&mut self){
         let mut buf :[u8; VBAN_PACKET_MAX_LEN_BYTES] = [0; VBAN_PACKET_MAX_LEN_BYTES];
-        
-        // close PCM after 2 seconds of not receiving any audio data
-        if self.state == PlayerState::Playing && self.timer.elapsed().as_secs() > 2 {
-            self.state = PlayerState::Idle;
-            
-            match &self.sink{
-                None => error!("Something's wrong. Expected to find a pcm but it is unitialized."),
-                Some(sink) => {
-                    match sink.pcm.drain(){
-                        Err(errno) => error!("Error while draining pcm: {errno}"),
-                        Ok(()) => (),
-                    }
-                    match sink.pcm.drop(){
-                        Err(errno) => error!("Error while closing pcm: {errno}"),
-                        Ok(()) => debug!("Audio device released"),
-                    }
-                    self.sink = None;
+
+        // remove streams after 2 seconds of not receiving any audio data, this also lets senders in again that were
+        // locked out by the sender policy
+        let command = &mut self.command;
+        self.streams.retain(|_, stream| {
+            if stream.timer.elapsed().as_secs() <= STREAM_TIMEOUT_S {
+                return true;
+            }
+
+            if stream.state == PlayerState::Playing {
+                stream.stop();
+                match command {
+                    None => (),
+                    Some(cmd) => _ = cmd.arg("playback_stopped").output(),
                 }
             }
-            match &mut self.command {
-                None => (),
-                Some(cmd) => _ = cmd.arg("playback_stopped").output(),
+
+            let stats = stream.tracker.stats();
+            debug!("Stream {} from {}: {} received, {} lost, {} duplicate, {} out of order, {} late, {} malformed, {} restarts",
+                stats.stream_name, stats.addr, stats.received, stats.lost, stats.duplicate, stats.out_of_order, stats.late, stats.malformed, stats.restarts);
+            false
+        });
+
+        if let Some(key) = &self.active {
+            if !self.streams.get(key).is_some_and(|stream| stream.state == PlayerState::Playing) {
+                debug!("Sender {} of stream {} is gone.", key.1, key.0);
+                self.active = None;
+            }
+        }
+
+        // close the audio devices no stream plays on anymore
+        let streams = &self.streams;
+        self.outputs.retain(|device, output| {
+            let used = streams.values().any(|stream| stream.state == PlayerState::Playing && stream.sink_name == *device);
+            if !used {
+                output.close(device);
+            }
+            used
+        });
+
+        if let Ok((size, addr)) = self.socket.recv_from(&mut buf) {
+            trace!("UDP packet len {} from {}", size, addr);
+            self.receive(&buf[..size], addr);
+        }
+
+        self.update_ducking();
+
+        for (device, output) in self.outputs.iter_mut() {
+            let mut streams : Vec<&mut VbanStream> = self.streams.values_mut()
+                .filter(|stream| stream.state == PlayerState::Playing && stream.attached && stream.sink_name == *device)
+                .collect();
+            output.play(&mut streams);
+        }
+
+        // senders that were taken over leave once they faded out
+        for stream in self.streams.values_mut() {
+            if stream.fading_out && stream.gain <= 0.0 {
+                stream.stop();
+                stream.locked_out = true;
             }
         }
 
-        let packet = self.socket.recv_from(&mut buf);
-        
-        let size = match packet {
-            Ok((size, _addr)) => {
-                size
+        let state = match self.streams.values().any(|stream| stream.state == PlayerState::Playing) {
+            true => PlayerState::Playing,
+            false => PlayerState::Idle,
+        };
+        if state != self.state {
+            self.set_state(state);
+        }
+    }
+
+    /// Process a received datagram. Audio packets are handed to the playback of their stream.
+    fn receive(&mut self, data : &[u8], addr : SocketAddr){
+        let packet = match VbanPacketRef::parse(data) {
+            Ok(p) => p,
+            Err(VbanPacketError::BadPreamble(_)) => {
+                debug!("Got UDP packet that is not VBAN");
+                return;
             },
-            _ => return,
+            Err(e) => {
+                debug!("Discarding malformed VBAN packet ({e}).");
+                return;
+            }
         };
 
-        trace!("UDP packet len {} from {}", size, packet.unwrap().1);
-
-        if buf[..4] == *b"VBAN" {
-            
-            let head : [u8; 28] = buf[0..28].try_into().unwrap();
-            let head = VBanHeader::from(head);
-            
-            self.sample_format = Some(head.sample_format.into());
-            
-            let num_samples: u16 = head.num_samples as u16 + 1;
-            if num_samples > crate::VBAN_SAMPLES_MAX_NB {
-                debug!("Number of samples exceeds maximum of {} (found {}).", crate::VBAN_SAMPLES_MAX_NB, num_samples);
+        if !self.access.is_allowed(&addr.ip(), packet.stream_name()) {
+            match self.rejected.count(addr.ip()) {
+                true => warn!("Rejecting packets of stream {} from {addr}.", packet.stream_name()),
+                false => trace!("Rejected packet of stream {} from {addr}.", packet.stream_name()),
+            }
+            return;
+        }
+
+        let protocol = packet.protocol();
+        if protocol == VBanProtocol::VbanProtocolTxt {
+            self.handle_text(&packet, addr);
+            return;
+        }
+        if protocol == VBanProtocol::VbanProtocolService {
+            if let Err(e) = vban_service::reply_to_ping(&packet, addr, &self.socket, &self.identity) {
+                error!("Could not answer service request from {addr}: {e}");
+            }
+            return;
+        }
+        if protocol != VBanProtocol::VbanProtocolAudio {
+            debug!("Discarding packet with protocol {:?} because it is not supported.", protocol);
+            return;
+        }
+
+        let name_incoming = packet.stream_name();
+        match &self.stream_name {
+            Some(name) if name != name_incoming => {
+                debug!("Discarding packet because stream names don't match (found {name_incoming}.");
+                return;
+            },
+            _ => (),
+        }
+
+        let key = (String::from(name_incoming), addr);
+        if !self.admit(&key) {
+            trace!("Discarding packet of stream {name_incoming} from {addr} because another sender is played ({}).", self.policy);
+            return;
+        }
+        if !self.streams.contains_key(&key) {
+            if self.streams.len() >= MAX_STREAMS {
+                debug!("Discarding packet of stream {name_incoming} from {addr} because {MAX_STREAMS} streams are received already.");
                 return;
             }
+            debug!("New stream {name_incoming} from {addr}.");
+        }
+
+        let stream = self.streams.entry(key.clone()).or_insert_with(|| VbanStream::new(name_incoming, addr, &self.config));
+        let was_playing = stream.state == PlayerState::Playing;
+        stream.receive(&packet, &self.config);
+
+        if stream.state == PlayerState::Playing && self.policy != SenderPolicy::MixAll && self.active.as_ref() != Some(&key) && !stream.fading_out {
+            self.take_over(&key);
+        }
 
-            let bits_per_sample = crate::VBAN_BIT_RESOLUTION_SIZE[self.sample_format.unwrap() as usize];
-            let codec = VBanCodec::from(head.sample_format);
-            let protocol = VBanProtocol::from(head.sample_rate);
-            let name_incoming : &str = from_utf8(&head.stream_name).unwrap();
+        if self.streams[&key].state == PlayerState::Playing && !self.streams[&key].attached {
+            self.connect(&key);
+        }
 
-            trace!("VBAN - #smp {}, bps {}, codec {}, name {}", num_samples, bits_per_sample, codec, name_incoming);
-            
-            if protocol != VBanProtocol::VbanProtocolAudio {
-                debug!("Discarding packet with protocol {:?} because it is not supported.", protocol);
+        let stream = &self.streams[&key];
+        if !was_playing && stream.state == PlayerState::Playing {
+            match &mut self.command {
+                None => (),
+                Some(cmd) => _ = cmd.arg("playback_started").output(),
+            }
+        }
+    }
+
+    /// Whether the audio of the sender `key` is played according to the sender policy
+    fn admit(&mut self, key : &(String, SocketAddr)) -> bool {
+        match self.policy {
+            SenderPolicy::MixAll => true,
+            SenderPolicy::Fixed(addr) => key.1.ip().to_canonical() == addr.to_canonical(),
+            SenderPolicy::FirstWins => match &self.active {
+                None => true,
+                Some(active) => active == key,
+            },
+            SenderPolicy::LatestWins { .. } => match self.streams.get_mut(key) {
+                Some(stream) if stream.locked_out => {
+                    // keep the sender out until it has been silent for the stream timeout
+                    stream.timer = Instant::now();
+                    false
+                },
+                _ => true,
+            },
+        }
+    }
+
+    /// Make `key` the active sender. With [`SenderPolicy::LatestWins`] the previous sender fades out.
+    fn take_over(&mut self, key : &(String, SocketAddr)){
+        let previous = self.active.replace(key.clone());
+
+        if let (SenderPolicy::LatestWins { crossfade_ms }, Some(previous)) = (self.policy, previous) {
+            if let Some(stream) = self.streams.get_mut(&previous).filter(|stream| stream.state == PlayerState::Playing) {
+                info!("Sender {} of stream {} takes over from {} of stream {}.", key.1, key.0, previous.1, previous.0);
+                stream.fading_out = true;
+                stream.fade_ms = crossfade_ms;
+                stream.target_gain = 0.0;
+                self.streams.get_mut(key).unwrap().fade_ms = crossfade_ms;
                 return;
             }
-            match codec {
-                VBanCodec::VbanCodecPcm => (),
-                VBanCodec::VbanCodecOpus(_) => (),
-                _ => {
-                    error!("Any codecs other than PCM and OPUS are not supported (found {:?}).", codec);
+        }
+        if !self.streams[key].open_failed {
+            info!("Playing sender {} of stream {} ({}).", key.1, key.0, self.policy);
+        }
+    }
+
+    /// Mix the stream `key` into the audio device it is routed to. The device is opened with the format of the stream
+    /// unless other streams already play on it.
+    fn connect(&mut self, key : &(String, SocketAddr)){
+        let device = String::from(self.config.device(&key.0));
+        let shared = self.streams.iter().any(|(k, stream)| k != key && stream.state == PlayerState::Playing && stream.attached && stream.sink_name == device);
+        let stream = self.streams.get_mut(key).unwrap();
+        stream.sink_name = device.clone();
+
+        if !shared {
+            if let Some(output) = self.outputs.remove(&device) {
+                output.close(&device);
+            }
+
+            let num_channels = stream.preferred_channels(&self.config.routing);
+            let sample_rate = self.config.output_rate.unwrap_or(stream.sample_rate());
+            match VbanOutput::open(&device, num_channels, sample_rate, stream.format.unwrap(), self.config.silence) {
+                Ok(output) => {
+                    self.outputs.insert(device.clone(), output);
+                },
+                Err(e) => {
+                    match stream.open_failed {
+                        true => trace!("Audio device {device} is still not available ({e})."),
+                        false => warn!("Could not grab audio device {device} ({e})"),
+                    }
+                    stream.open_failed = true;
+                    stream.stop();
                     return;
                 }
+            }
+        }
+
+        if stream.open_failed {
+            info!("Audio device {device} is available again.");
+        }
+        stream.open_failed = false;
+        stream.attach(&self.outputs[&device], &self.config);
+    }
+
+    /// Duck every stream while a stream of higher priority is active
+    fn update_ducking(&mut self){
+        let ducking = match &self.config.ducking {
+            None => return,
+            Some(d) => d,
+        };
 
+        let hold = Duration::from_millis(DUCKING_HOLD_MS);
+        let active_rank = self.streams.iter()
+            .filter(|(_, stream)| stream.state == PlayerState::Playing && stream.timer.elapsed() < hold)
+            .map(|((name, _), _)| ducking.rank(name))
+            .min();
+
+        for ((name, _), stream) in self.streams.iter_mut() {
+            let duck = ducking.gain(name, active_rank);
+            if duck < stream.duck_target {
+                debug!("Ducking stream {name}.");
+            } else if duck > stream.duck_target {
+                debug!("Releasing stream {name}.");
             }
-            if bits_per_sample != 2{
-                error!("Bitwidth other than 16 bits not supported (found {}).", bits_per_sample * 8);
+            stream.duck_target = duck;
+            stream.duck_attack_ms = ducking.attack_ms;
+            stream.duck_release_ms = ducking.release_ms;
+        }
+    }
+
+    fn set_state(&mut self, state : PlayerState){
+        let interval = match state {
+            PlayerState::Playing => PLAYOUT_INTERVAL,
+            PlayerState::Idle => IDLE_INTERVAL,
+        };
+        if let Err(e) = self.socket.set_read_timeout(Some(interval)) {
+            error!("Could not set socket timeout ({e}).");
+        }
+        self.state = state;
+    }
+
+
+    fn handle_text(&mut self, packet : &VbanPacketRef, addr : SocketAddr){
+        let text = match VbanText::from_packet(packet) {
+            Ok(t) => t,
+            Err(e) => {
+                debug!("Discarding text packet from {addr} ({e}).");
                 return;
             }
-            
-            let sr : VBanSampleRates  = head.sample_rate.into();
+        };
 
-            if head.num_channels > ( crate::VBAN_CHANNELS_MAX_NB - 1) as u8 {
-                debug!("Number of channels exceeds maximum of {}.", crate::VBAN_CHANNELS_MAX_NB);
-                return;
+        match &mut self.text_handler {
+            None => debug!("Discarding text from {addr} on stream {} because no handler is set.", text.stream_name),
+            Some(handler) => handler(&text, addr),
+        }
+    }
+
+
+    // SETTER
+    pub fn set_command(&mut self, cmd : Command){
+        self.command = Some(cmd);
+    }
+
+    /// Set a handler that is called for every VBAN-TEXT packet received on the socket
+    pub fn set_text_handler(&mut self, handler : TextHandler){
+        self.text_handler = Some(handler);
+    }
+
+    /// Set the identification that is sent in reply to VBAN-SERVICE pings
+    pub fn set_identity(&mut self, identity : VbanPing0){
+        self.identity = identity;
+    }
+
+    /// Receive the streams sent to the multicast group `group` on `interface`. May be called for several groups.
+    pub fn join_multicast(&mut self, group : IpAddr, interface : MulticastInterface) -> Result<(), Error> {
+        vban_multicast::join(&self.socket, group, interface)?;
+        info!("Joined multicast group {group} on {interface}.");
+        Ok(())
+    }
+
+    /// Play the stream `stream_name` on the audio device `device` instead of the default one. Takes effect when the
+    /// stream starts the next time.
+    pub fn set_stream_device(&mut self, stream_name : &str, device : &str){
+        self.config.devices.insert(String::from(stream_name), String::from(device));
+    }
+
+    /// Play the stream `stream_name` on the default audio device again
+    pub fn remove_stream_device(&mut self, stream_name : &str){
+        self.config.devices.remove(stream_name);
+    }
+
+    /// Mix the stream `stream_name` with a gain in dB. Affects streams that are playing right away.
+    pub fn set_stream_gain(&mut self, stream_name : &str, gain_db : f32){
+        self.config.gains.insert(String::from(stream_name), 10f64.powf(gain_db as f64 / 20.0));
+        self.update_gains(stream_name);
+    }
+
+    /// Mute or unmute the stream `stream_name`. Affects streams that are playing right away.
+    pub fn set_stream_mute(&mut self, stream_name : &str, mute : bool){
+        match mute {
+            true => self.config.muted.insert(String::from(stream_name)),
+            false => self.config.muted.remove(stream_name),
+        };
+        self.update_gains(stream_name);
+    }
+
+    fn update_gains(&mut self, stream_name : &str){
+        let gain = self.config.gain(stream_name);
+        for ((name, _), stream) in self.streams.iter_mut() {
+            if name == stream_name && !stream.fading_out {
+                stream.target_gain = gain;
             }
-            self.num_channels = Some(head.num_channels + 1);
+        }
+    }
 
-            match self.stream_name {
-                None => (),
-                Some(name) => {
-                    if from_utf8(&name).unwrap() != name_incoming {
-                        debug!("Discarding packet because stream names don't match (found {name_incoming}.");
-                        return;
-                    }
+    /// Choose which senders are played while several of them send audio. Of the streams that are playing, those the
+    /// new policy admits keep playing.
+    pub fn set_sender_policy(&mut self, policy : SenderPolicy){
+        self.policy = policy;
+        self.active = None;
+
+        for (key, stream) in self.streams.iter_mut() {
+            stream.locked_out = false;
+            if stream.state != PlayerState::Playing {
+                continue;
+            }
+            let keep = match policy {
+                SenderPolicy::MixAll => true,
+                SenderPolicy::Fixed(addr) => key.1.ip().to_canonical() == addr.to_canonical(),
+                SenderPolicy::FirstWins | SenderPolicy::LatestWins { .. } => self.active.is_none() && !stream.fading_out,
+            };
+            match keep {
+                true if policy != SenderPolicy::MixAll => self.active = Some(key.clone()),
+                true => (),
+                false => {
+                    info!("Stopping stream {} from {} ({policy}).", key.0, key.1);
+                    stream.stop();
+                },
+            }
+        }
+    }
+
+    /// Attenuate or silence streams while a stream of higher priority is active, `None` to turn ducking off
+    pub fn set_ducking(&mut self, ducking : Option<DuckingConfig>){
+        if ducking.is_none() {
+            for stream in self.streams.values_mut() {
+                stream.duck_target = 1.0;
+            }
+        }
+        self.config.ducking = ducking;
+    }
+
+    /// Add a rule that allows or denies senders. Rules apply to all packets, including text and service packets.
+    pub fn add_access_rule(&mut self, rule : AccessRule){
+        info!("Access rule: {rule}");
+        self.access.add(rule);
+        self.drop_rejected_streams();
+    }
+
+    /// Replace all access rules
+    pub fn set_access_list(&mut self, access : AccessList){
+        self.access = access;
+        self.drop_rejected_streams();
+    }
+
+    /// Stop the streams whose senders are no longer accepted
+    fn drop_rejected_streams(&mut self){
+        let access = &self.access;
+        self.streams.retain(|(name, addr), stream| {
+            let allowed = access.is_allowed(&addr.ip(), name);
+            if !allowed && stream.state == PlayerState::Playing {
+                info!("Stopping stream {name} from {addr} because the sender is no longer accepted.");
+                stream.stop();
+            }
+            allowed
+        });
+    }
+
+    /// Change the latency settings of the jitter buffers
+    pub fn set_jitter_buffer(&mut self, config : JitterBufferConfig){
+        self.config.jitter = config;
+        for stream in self.streams.values_mut() {
+            stream.jitter.set_config(config);
+        }
+    }
+
+    /// Select how the clock drift between the senders and the audio devices is compensated
+    pub fn set_drift_compensation(&mut self, mode : DriftCompensation){
+        self.config.drift = mode;
+        for stream in self.streams.values_mut() {
+            stream.drift.set_mode(mode);
+            stream.resampler = None;
+        }
+    }
+
+    /// Open the audio devices with a fixed sample rate and resample streams of other rates, `None` to follow the
+    /// rate of the stream. Takes effect when the next stream starts.
+    pub fn set_output_rate(&mut self, rate : Option<u32>){
+        self.config.output_rate = rate;
+    }
+
+    /// Select how the channels of a stream are mapped to the channels of the audio device. Takes effect when the next
+    /// stream starts.
+    pub fn set_channel_routing(&mut self, routing : ChannelRouting){
+        self.config.routing = routing;
+    }
+
+    /// Select the interpolation used when a stream is resampled
+    pub fn set_resample_quality(&mut self, quality : ResampleQuality){
+        self.config.resample_quality = quality;
+        for stream in self.streams.values_mut() {
+            stream.resampler = None;
+        }
+    }
+
+    // GETTER
+    /// Underruns, overruns and current latency of the jitter buffer of a stream
+    pub fn jitter_stats(&self, stream_name : &str, addr : SocketAddr) -> Option<JitterBufferStats> {
+        self.streams.get(&(String::from(stream_name), addr)).map(|stream| stream.jitter.stats())
+    }
+
+    /// Current correction of the clock drift of a stream
+    pub fn drift_stats(&self, stream_name : &str, addr : SocketAddr) -> Option<DriftStats> {
+        self.streams.get(&(String::from(stream_name), addr)).map(|stream| stream.drift.stats())
+    }
+
+    pub fn sender_policy(&self) -> SenderPolicy {
+        self.policy
+    }
+
+    /// Stream name and address of the sender that is played, `None` while nothing plays. With
+    /// [`SenderPolicy::MixAll`] a sender is only returned while it is the only one playing.
+    pub fn active_sender(&self) -> Option<(String, SocketAddr)> {
+        match self.policy {
+            SenderPolicy::MixAll => {
+                let mut playing = self.streams.iter().filter(|(_, stream)| stream.state == PlayerState::Playing).map(|(key, _)| key);
+                match (playing.next(), playing.next()) {
+                    (Some(key), None) => Some(key.clone()),
+                    _ => None,
                 }
+            },
+            _ => self.active.clone(),
+        }
+    }
+
+    /// Packets that were rejected by the access rules
+    pub fn rejected_stats(&self) -> &RejectedStats {
+        &self.rejected
+    }
+
+    /// Packet counters of every stream that received audio within the stream timeout
+    pub fn stream_stats(&self) -> Vec<VbanStreamStats> {
+        self.streams.values().map(|stream| stream.tracker.stats().clone()).collect()
+    }
+
+    /// Audio device every playing stream is mixed into
+    pub fn active_streams(&self) -> Vec<(VbanStreamStats, String)> {
+        self.streams.values()
+            .filter(|stream| stream.state == PlayerState::Playing)
+            .map(|stream| (stream.tracker.stats().clone(), stream.sink_name.clone()))
+            .collect()
+    }
+
+
+}
+
+
+// ****************************************
+//              VBAN Output
+// ****************************************
+
+/// An audio device that one or more streams are mixed into
+struct VbanOutput {
+
+    sink : AlsaSink,
+
+    num_channels : usize,
+}
+
+impl VbanOutput {
+
+    /// Open `device` and fill it with `silence` ms of silence before the first audio is played
+    fn open(device : &str, num_channels : usize, sample_rate : u32, format : SampleFormat, silence : u32) -> Result<Self, Error> {
+        let sink = AlsaSink::init_with_format(device, Some(num_channels as u32), Some(sample_rate), format)?;
+        trace!("Successfully initialized ALSA device {device} with {num_channels} channels at {} Hz", sink.sample_rate());
+
+        /* Push silence before the data */
+        let rate = sink.sample_rate();
+        let silence_buf = AudioBuffer::new(format, (rate / 1000 * silence) as usize, num_channels, rate);
+        sink.write(&silence_buf);
+
+        Ok(VbanOutput { sink, num_channels })
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sink.sample_rate()
+    }
+
+    /// Play the remaining audio and release the device
+    fn close(&self, device : &str){
+        if let Err(errno) = self.sink.pcm.drain(){
+            error!("Error while draining pcm: {errno}");
+        }
+        match self.sink.pcm.drop(){
+            Err(errno) => error!("Error while closing pcm: {errno}"),
+            Ok(()) => debug!("Audio device {device} released"),
+        }
+    }
+
+    /// Mix the audio of `streams` and move it to the device until the device holds `DEVICE_FILL_MS` of audio.
+    fn play(&mut self, streams : &mut [&mut VbanStream]){
+        let ch = self.num_channels;
+        let rate = self.sample_rate();
+        let device_fill = (rate / 1000 * DEVICE_FILL_MS) as usize;
+        let mut queued = self.sink.queued_frames();
+
+        while queued < device_fill {
+            for stream in streams.iter_mut() {
+                stream.fill(device_fill - queued);
             }
 
-            let audio_data : Vec<u8> = Vec::from(&buf[VBAN_PACKET_HEADER_BYTES + VBAN_PACKET_COUNTER_BYTES..size]);
-            let mut to_sink : Vec<i16>;
-            let mut left : i16 = 0;
-            let mut right : i16 = 0;
+            // streams without audio are refilling their jitter buffer and are silent meanwhile
+            let frames = match streams.iter().map(|stream| stream.pending_frames()).filter(|n| *n > 0).min() {
+                None => break,
+                Some(n) => n,
+            };
 
-            match codec{
-                VBanCodec::VbanCodecPcm => {
-                    to_sink = vec![0; audio_data.len() / bits_per_sample as usize];
+            let mut mix = vec![0.0; frames * ch];
+            for stream in streams.iter_mut() {
+                stream.mix_into(&mut mix, frames);
+            }
+            self.sink.write(&AudioBuffer { samples : Samples::F64(mix), num_channels : ch, sample_rate : rate });
+            queued += frames;
+        }
 
-                    for (idx, _smp) in audio_data.iter().enumerate() {
-                        if idx % 2 == 1 {
-                            continue;
-                        }
+        for stream in streams.iter_mut() {
+            stream.update_drift(queued);
+        }
+    }
+}
 
-                        if idx == audio_data.len() - 1 {
-                            break;
-                        }
 
-                        let amplitude_le = LittleEndian::read_i16(&audio_data[idx..idx+2]);
+// ****************************************
+//              VBAN Stream
+// ****************************************
 
-                        if idx % 4 == 0 {
-                            if amplitude_le > left {
-                                left = amplitude_le;
-                            }
-                        } else {
-                            if amplitude_le > right {
-                                right = amplitude_le;
-                            }
-                        }
+/// Playback of a single stream, identified by its name and the address of its sender
+struct VbanStream {
 
-                        to_sink[idx / 2] = amplitude_le;
-                    }
+    tracker : FrameTracker,
+
+    state : PlayerState,
+
+    timer : Instant,
+
+    sample_rate : Option<VBanSampleRates>,
+
+    num_channels : Option<usize>,
+
+    /// Definition of bitwidth (16, 24, 32) and integer/float type
+    sample_format : Option<VBanBitResolution>,
+
+    /// Sample format the stream is decoded to
+    format : Option<SampleFormat>,
+
+    /// Audio device the stream is mixed into
+    sink_name : String,
+
+    /// The conversion to the audio device is set up for the current format of the stream
+    attached : bool,
+
+    /// The audio device could not be opened when the stream tried to start, only reported once
+    open_failed : bool,
+
+    /// Decoder of the stream, `None` for PCM streams
+    decoder : Option<OpusDecoder>,
+
+    jitter : JitterBuffer,
+
+    drift : DriftCompensator,
+
+    /// Converts the stream to the rate of the audio device, `None` until it is needed
+    resampler : Option<Resampler>,
+
+    resample_quality : ResampleQuality,
+
+    /// Routing of the stream, `None` if its channels are played as they arrive
+    matrix : Option<ChannelMatrix>,
+
+    device_channels : usize,
+
+    device_rate : u32,
+
+    /// Interleaved audio with the channel count and rate of the device, waiting to be mixed
+    pending : VecDeque<f64>,
+
+    /// Gain the stream is currently mixed with
+    gain : f64,
+
+    /// Gain the stream fades to
+    target_gain : f64,
+
+    /// Factor by which the stream is currently ducked
+    duck : f64,
+
+    /// Factor the ducking fades to
+    duck_target : f64,
+
+    duck_attack_ms : u32,
+
+    duck_release_ms : u32,
+
+    /// Duration in ms of a fade of the gain from 0 to 1
+    fade_ms : u32,
+
+    /// The stream was taken over by another sender and is stopped once its gain reached 0
+    fading_out : bool,
+
+    /// Packets of the stream are ignored because it was taken over by another sender
+    locked_out : bool,
+}
+
+impl VbanStream {
+
+    fn new(stream_name : &str, addr : SocketAddr, config : &PlaybackConfig) -> Self {
+        VbanStream {
+            tracker : FrameTracker::new(stream_name, addr),
+            state : PlayerState::Idle,
+            timer : Instant::now(),
+            sample_rate : None,
+            num_channels : None,
+            sample_format : None,
+            format : None,
+            sink_name : String::from(config.device(stream_name)),
+            attached : false,
+            open_failed : false,
+            decoder : None,
+            jitter : JitterBuffer::new(config.jitter),
+            drift : DriftCompensator::new(config.drift),
+            resampler : None,
+            resample_quality : config.resample_quality,
+            matrix : None,
+            device_channels : 0,
+            device_rate : 0,
+            pending : VecDeque::new(),
+            gain : 0.0,
+            target_gain : config.gain(stream_name),
+            duck : 1.0,
+            duck_target : 1.0,
+            duck_attack_ms : 0,
+            duck_release_ms : 0,
+            fade_ms : FADE_MS,
+            fading_out : false,
+            locked_out : false,
+        }
+    }
+
+    /// Store an audio packet of the stream in the jitter buffer. A stream that was idle starts playing, the caller
+    /// connects it to its audio device.
+    fn receive(&mut self, packet : &VbanPacketRef, config : &PlaybackConfig){
+        let num_samples = packet.num_samples();
+        let codec = packet.codec();
+        let name_incoming = packet.stream_name();
+
+        let sample_format = packet.bit_resolution();
+        let bits_per_sample = match sample_format.bits() {
+            Some(bits) => bits,
+            None => {
+                error!("Bit resolution {:?} not supported.", sample_format);
+                return;
+            }
+        };
+
+        trace!("VBAN - #smp {}, bps {}, codec {}, name {}", num_samples, bits_per_sample, codec, name_incoming);
+
+        match codec {
+            VBanCodec::VbanCodecPcm => (),
+            VBanCodec::VbanCodecOpus(_) => (),
+            _ => {
+                error!("Any codecs other than PCM and OPUS are not supported (found {:?}).", codec);
+                return;
+            }
+
+        }
+        let sink_format = match (&codec, sample_format.sample_format()) {
+            (VBanCodec::VbanCodecOpus(_), _) if sample_format != VBanBitResolution::VbanBitfmt16Int => None,
+            (_, format) => format,
+        };
+        let sink_format = match sink_format {
+            Some(f) => f,
+            None => {
+                error!("Bitwidth of {} bits not supported with codec {}.", bits_per_sample, codec);
+                return;
+            }
+        };
+
+        // a PCM payload that does not hold whole frames would shift the channels of every later frame of the stream
+        if matches!(codec, VBanCodec::VbanCodecPcm) && sample_format.payload_size(num_samples * packet.num_channels()) != Some(packet.payload().len()) {
+            self.tracker.count_malformed();
+            debug!("Discarding frame {} of stream {name_incoming}, its payload of {} bytes does not match the header.", packet.nu_frame(), packet.payload().len());
+            return;
+        }
+
+        match self.tracker.track(packet.nu_frame()) {
+            FrameStatus::Next { lost } => {
+                if lost > 0 {
+                    debug!("Missing {lost} frame(s) before frame {} of stream {name_incoming}.", packet.nu_frame());
                 }
+            },
+            FrameStatus::Duplicate => {
+                trace!("Discarding duplicate frame {} of stream {name_incoming}.", packet.nu_frame());
+                return;
+            },
+            FrameStatus::OutOfOrder => trace!("Frame {} of stream {name_incoming} arrived out of order.", packet.nu_frame()),
+            FrameStatus::Late => {
+                trace!("Discarding late frame {} of stream {name_incoming}.", packet.nu_frame());
+                return;
+            },
+            FrameStatus::Restart => {
+                info!("Frame counter of stream {name_incoming} jumped to {}, restarting the stream.", packet.nu_frame());
+                self.jitter.reset();
+                self.drift.reset();
+                self.decoder = None;
+            },
+        };
+
+        let sr : VBanSampleRates = packet.sample_rate();
 
-                VBanCodec::VbanCodecOpus(_) => {
-                    if self.decoder.is_none(){
-
-                        let opus_ch = match self.num_channels.unwrap() {
-                            1 => Channels::Mono,
-                            2 => Channels::Stereo,
-                            _ => {
-                                error!("Error: Opus cannot handle {} channels", self.num_channels.unwrap());
-                                return;
-                            }
-                        };
-
-                        self.decoder = match Decoder::new(sr.into(), opus_ch){
-                            Ok(d) => Some(d),
-                            Err(e) => {
-                                error!("Error while trying to create an opus decoder: {e}");
-                                return;
-                            }
-                        };
+        // buffered packets can only be decoded with the format they were received with
+        if self.sample_format != Some(sample_format) {
+            self.sample_format = Some(sample_format);
+            self.jitter.reset();
+            self.drift.reset();
+        }
+
+        // the packet parser already limits the channel count to VBAN_CHANNELS_MAX_NB
+        let channels_changed = self.num_channels != Some(packet.num_channels());
+        self.num_channels = Some(packet.num_channels());
+
+        match codec {
+            VBanCodec::VbanCodecOpus(_) => {
+                if self.decoder.is_none() || channels_changed || self.sample_rate != Some(sr) {
+
+                    if self.num_channels() > OPUS_CHANNELS_MAX_NB {
+                        error!("Error: Opus cannot handle {} channels", self.num_channels());
+                        return;
                     }
 
-                    let dec = self.decoder.as_mut().unwrap();
-                    let opus_num_samples = dec.get_nb_samples(&audio_data).unwrap(); // TODO: needs proper error handling
-
-                    to_sink = vec![0; 2 * num_samples as usize];
-                    dec.decode(&audio_data, &mut to_sink, false).unwrap();
-
-                    for (idx, ampl) in to_sink.iter().enumerate(){
-                        if idx % 2 == 0 {
-                            if *ampl > left {
-                                left = *ampl;
-                            }
-                        } else {
-                            if *ampl > right {
-                                right = *ampl;
-                            }
+                    self.jitter.reset();
+
+                    self.drift.reset();
+                    self.decoder = match OpusDecoder::new(sr.into(), self.num_channels()){
+                        Ok(d) => Some(d),
+                        Err(e) => {
+                            error!("Error while trying to create an opus decoder: {e}");
+                            return;
                         }
-                    }
+                    };
+                }
+            },
+            _ => {
+                if self.decoder.take().is_some() {
+                    self.jitter.reset();
+                    self.drift.reset();
+                }
+            },
+        }
+
+        self.timer = Instant::now();
+        if self.state == PlayerState::Idle {
+            self.sample_rate = Some(sr);
+            self.format = Some(sink_format);
+            self.resample_quality = config.resample_quality;
+            self.target_gain = config.gain(name_incoming);
+            // fade in
+            self.gain = 0.0;
+            self.attached = false;
+            self.jitter.reset();
+            self.drift.reset();
+            self.state = PlayerState::Playing;
+
+            if !self.open_failed {
+                info!("Connected to stream {}: \nSR: {} \t Ch: {} \t BPS: {} \t Codec: {}\n", name_incoming, self.sample_rate(), self.num_channels(), self.bits_per_sample(), codec);
+            }
+        } else if sr != self.sample_rate.unwrap() || channels_changed || self.format != Some(sink_format) {
+            self.sample_rate = Some(sr);
+            self.format = Some(sink_format);
+            self.attached = false;
+            self.jitter.reset();
+            self.drift.reset();
+        }
+
+        match self.jitter.push(packet.nu_frame(), packet.payload(), num_samples, sr.into(), Instant::now()) {
+            PushResult::Queued => (),
+            PushResult::Late => {
+                self.tracker.count_late();
+                trace!("Discarding late frame {} of stream {name_incoming}.", packet.nu_frame());
+            },
+            PushResult::Duplicate => trace!("Discarding duplicate frame {} of stream {name_incoming}.", packet.nu_frame()),
+        }
+    }
 
+    /// Number of channels the stream would like the audio device to have
+    fn preferred_channels(&self, routing : &ChannelRouting) -> usize {
+        match routing.matrix(self.num_channels()) {
+            Ok(Some(matrix)) => matrix.outputs(),
+            _ => self.num_channels(),
+        }
+    }
+
+    /// Set up the conversion of the stream to the channels and rate of `output`
+    fn attach(&mut self, output : &VbanOutput, config : &PlaybackConfig){
+        let ch = self.num_channels();
+        self.device_channels = output.num_channels;
+        self.device_rate = output.sample_rate();
+        self.resampler = None;
+        self.pending.clear();
+
+        self.matrix = match config.routing.matrix(ch) {
+            Ok(Some(matrix)) if matrix.outputs() == self.device_channels => {
+                info!("Routing {} stream channels to {} device channels ({}).", matrix.inputs(), matrix.outputs(), config.routing);
+                Some(matrix)
+            },
+            Ok(_) if ch == self.device_channels => None,
+            Ok(_) => {
+                info!("Mixing {ch} stream channels into {} device channels.", self.device_channels);
+                Some(ChannelMatrix::adapt(ch, self.device_channels))
+            },
+            Err(e) => {
+                warn!("Playing the channels as they arrive ({e}).");
+                match ch == self.device_channels {
+                    true => None,
+                    false => Some(ChannelMatrix::adapt(ch, self.device_channels)),
                 }
+            },
+        };
+        self.attached = true;
+    }
 
-                _ => return // we've already caught that case above
-            }
-
-            self.timer = Instant::now();
-            if self.state == PlayerState::Idle {
-                match &self.sink {
-                    Some(_sink) => error!("Something's wrong. Sink is Some() although it should be None"),
-                    None => {
-                        self.sample_rate = Some(sr);
-                        self.sink = match AlsaSink::init(&self.sink_name, Some(self.num_channels() as u32), Some(self.sample_rate())){
-                            None => {
-                                warn!("Could not grab audio device");
-                                return
-                            },
-                            Some(sink) => {
-                                trace!("Successfully initialized ALSA device with {} channels at {} Hz", self.num_channels(), self.sample_rate());
-                                Some(sink)
-                            }
-                        };
-
-                        info!("Connected to stream {}: \nSR: {} \t Ch: {} \t BPS: {} \t Codec: {}\n", name_incoming, self.sample_rate(), self.num_channels(), self.bits_per_sample(), codec);
-
-                        /* Push silence before the data */
-                        let silence_buf = vec![0i16; (self.sample_rate() / 1000 * self.silence) as usize];
-                        self.sink.as_mut().unwrap().write(&silence_buf);
-                    }
+    /// Remove the stream from the mix
+    fn stop(&mut self){
+        self.state = PlayerState::Idle;
+        self.attached = false;
+        self.jitter.reset();
+        self.drift.reset();
+        self.resampler = None;
+        self.pending.clear();
+        self.fade_ms = FADE_MS;
+        self.fading_out = false;
+    }
+
+    fn pending_frames(&self) -> usize {
+        match self.device_channels {
+            0 => 0,
+            ch => self.pending.len() / ch,
+        }
+    }
+
+    /// Decode audio from the jitter buffer until `frames` frames are waiting to be mixed or the jitter buffer runs empty
+    fn fill(&mut self, frames : usize){
+        let underruns = self.jitter.stats().underruns;
+
+        while self.pending_frames() < frames {
+            let audio = match self.jitter.pop() {
+                None => break,
+                Some(JitterSlot::Packet(payload)) => self.decode(&payload),
+                Some(JitterSlot::Lost) => self.conceal(),
+            };
+
+            match audio {
+                Some(buf) => match self.convert(buf) {
+                    Some(buf) => self.pending.extend(buf.to_f64()),
+                    None => break,
+                },
+                None => break,
+            }
+        }
+
+        if self.jitter.stats().underruns > underruns {
+            debug!("Jitter buffer of stream {} ran empty, buffering {:.1} ms before resuming playback.", self.tracker.stats().stream_name, self.jitter.stats().target_ms);
+        }
+    }
+
+    /// Add up to `frames` waiting frames to the interleaved `mix`, fading the gain and the ducking towards their
+    /// targets
+    fn mix_into(&mut self, mix : &mut [f64], frames : usize){
+        let step = self.fade_step(self.fade_ms);
+        let duck_step = match self.duck_target < self.duck {
+            true => self.fade_step(self.duck_attack_ms),
+            false => self.fade_step(self.duck_release_ms),
+        };
+        let available = self.pending_frames().min(frames);
+
+        for frame in mix.chunks_exact_mut(self.device_channels).take(available) {
+            self.gain += (self.target_gain - self.gain).clamp(-step, step);
+            self.duck += (self.duck_target - self.duck).clamp(-duck_step, duck_step);
+            let gain = self.gain * self.duck;
+            for smp in frame.iter_mut() {
+                *smp += self.pending.pop_front().unwrap_or(0.0) * gain;
+            }
+        }
+    }
+
+    /// Change of a gain per frame for a fade from 0 to 1 in `ms`
+    fn fade_step(&self, ms : u32) -> f64 {
+        1.0 / (self.device_rate as f64 * ms as f64 / 1000.0).max(1.0)
+    }
+
+    /// Feed the drift compensation with the audio buffered for the stream, `queued` frames are waiting in the device
+    fn update_drift(&mut self, queued : usize){
+        match self.jitter.is_playing() {
+            true => {
+                // measured at the rate of the stream
+                let sr = self.sample_rate();
+                let target = (self.jitter.stats().target_ms + DEVICE_FILL_MS as f64) * sr as f64 / 1000.0;
+                let device_frames = queued + self.pending_frames();
+                let latency = self.jitter.buffered_samples() + device_frames * sr as usize / self.device_rate.max(1) as usize;
+                self.drift.update(latency, target, sr, Instant::now());
+            },
+            false => self.drift.pause(),
+        }
+    }
+
+    /// Correct the clock drift of `buf`, route its channels and convert it to the rate of the audio device
+    fn convert(&mut self, buf : AudioBuffer) -> Option<AudioBuffer> {
+        let buf = self.drift.process(buf);
+        let buf = match &self.matrix {
+            None => buf,
+            Some(matrix) => match matrix.apply(&buf) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    error!("Could not route the channels of stream {} ({e}).", self.tracker.stats().stream_name);
+                    return None;
                 }
-                match &mut self.command {
-                    None => (),
-                    Some(cmd) => _ = cmd.arg("playback_started").output(),
+            },
+        };
+
+        let sample_rate = self.sample_rate();
+        let device_rate = self.device_rate;
+        if device_rate == sample_rate && self.drift.mode() != DriftCompensation::Resample {
+            return Some(buf);
+        }
+
+        let resampler = match &mut self.resampler {
+            Some(r) if r.num_channels() == buf.num_channels => r,
+            _ => {
+                if device_rate != sample_rate {
+                    info!("Resampling from {sample_rate} Hz to {device_rate} Hz ({} interpolation).", self.resample_quality);
                 }
-                self.state = PlayerState::Playing;
-            } else {
-                if sr != self.sample_rate.unwrap(){
-                    self.sample_rate = Some(sr);
-                    let sink = self.sink.as_mut().unwrap();
-                    let _ = sink.pcm.drain();
-                    self.sink = Some(AlsaSink::init(&self.sink_name, Some(self.num_channels() as u32), Some(self.sample_rate())).expect("Could not create audio device with the required specs."));
+                self.resampler.insert(Resampler::new(buf.num_channels, device_rate as f64 / sample_rate as f64, self.resample_quality))
+            }
+        };
+        resampler.set_ratio(device_rate as f64 / sample_rate as f64 * self.drift.rate_ratio());
+
+        let mut out = resampler.process(&buf);
+        out.sample_rate = device_rate;
+        Some(out)
+    }
+
+    fn decode(&mut self, payload : &[u8]) -> Option<AudioBuffer> {
+        let num_channels = self.num_channels();
+        let sample_rate = self.sample_rate();
+
+        match self.decoder.as_mut() {
+            None => {
+                match AudioBuffer::from_payload(payload, self.sample_format.unwrap(), num_channels, sample_rate) {
+                    Ok(buf) => Some(buf),
+                    Err(e) => {
+                        error!("Could not decode PCM data ({e}).");
+                        None
+                    }
+                }
+            },
+            Some(dec) => {
+                let mut decoded = vec![0; num_channels * self.jitter.packet_samples()];
+                match dec.decode(payload, &mut decoded) {
+                    Ok(n) => {
+                        decoded.truncate(n * num_channels);
+                        Some(AudioBuffer { samples : Samples::I16(decoded), num_channels, sample_rate })
+                    },
+                    Err(e) => {
+                        error!("Could not decode Opus packet ({e}).");
+                        None
+                    }
                 }
             }
-            let sink = self.sink.as_mut().unwrap();
-            sink.write(&to_sink);
-            // println!("\x1B[1ALeft {:.4}, Right {:.4} (from {num_samples} samples)", (left as f32 / i16::MAX as f32), (right as f32 / i16::MAX as f32));
-        } else{
-            debug!("Got UDP packet that is not VBAN");
         }
     }
 
+    /// Audio in place of a lost packet. Opus recovers it from the redundant data in the next packet if that has already
+    /// arrived, or extrapolates it. PCM streams get silence.
+    fn conceal(&mut self) -> Option<AudioBuffer> {
+        let num_channels = self.num_channels();
+        let sample_rate = self.sample_rate();
+        let num_samples = self.jitter.packet_samples();
 
-    // SETTER
-    pub fn set_command(&mut self, cmd : Command){
-        self.command = Some(cmd);
+        match self.decoder.as_mut() {
+            None => Some(AudioBuffer::new(self.format.unwrap(), num_samples, num_channels, sample_rate)),
+            Some(dec) => {
+                let mut decoded = vec![0; num_channels * num_samples];
+                let res = match self.jitter.peek() {
+                    Some(next) => dec.decode_fec(next, &mut decoded),
+                    None => dec.conceal(&mut decoded),
+                };
+                match res {
+                    Ok(n) => {
+                        decoded.truncate(n * num_channels);
+                        Some(AudioBuffer { samples : Samples::I16(decoded), num_channels, sample_rate })
+                    },
+                    Err(e) => {
+                        debug!("Could not conceal lost Opus frame ({e}).");
+                        None
+                    }
+                }
+            }
+        }
     }
 
-    // GETTER
     fn sample_rate(&self) -> u32 {
         VBAN_SRLIST[self.sample_rate.unwrap() as usize]
     }
 
     fn bits_per_sample(&self) -> u8 {
-        self.sample_format.unwrap() as u8 + 1
+        self.sample_format.unwrap().bits().unwrap_or(0)
     }
 
-    fn num_channels(&self) -> u8 {
-        self.num_channels.unwrap() as u8
+    fn num_channels(&self) -> usize {
+        self.num_channels.unwrap()
     }
+}
 
 
-}
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Rate of the audio device, a fade of 10 ms takes 10 frames
+    const DEVICE_RATE : u32 = 1000;
+
+    fn playback_config() -> PlaybackConfig {
+        PlaybackConfig {
+            sink_name : String::from("default"),
+            devices : HashMap::new(),
+            silence : 0,
+            jitter : JitterBufferConfig::default(),
+            drift : DriftCompensation::Off,
+            output_rate : None,
+            resample_quality : ResampleQuality::default(),
+            routing : ChannelRouting::default(),
+            gains : HashMap::new(),
+            muted : HashSet::new(),
+            ducking : None,
+        }
+    }
 
+    /// Mono stream at full gain with `frames` frames of full scale audio waiting to be mixed
+    fn stream(frames : usize) -> VbanStream {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6980);
+        let mut stream = VbanStream::new("Stream1", addr, &playback_config());
+        stream.device_channels = 1;
+        stream.device_rate = DEVICE_RATE;
+        stream.gain = 1.0;
+        stream.pending.extend(std::iter::repeat_n(1.0, frames));
+        stream
+    }
+
+    fn mix(stream : &mut VbanStream, frames : usize) -> Vec<f64> {
+        let mut mix = vec![0.0; frames];
+        stream.mix_into(&mut mix, frames);
+        mix
+    }
+
+    fn assert_close(actual : &[f64], expected : &[f64]){
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn ducking_fades_with_attack_and_release(){
+        let mut stream = stream(32);
+        stream.duck_attack_ms = 10;
+        stream.duck_release_ms = 20;
+
+        // attack: steps of 1/10 down to the ducked gain, where it stays
+        stream.duck_target = 0.7;
+        assert_close(&mix(&mut stream, 5), &[0.9, 0.8, 0.7, 0.7, 0.7]);
+
+        // release: steps of 1/20 back to unity gain
+        stream.duck_target = 1.0;
+        assert_close(&mix(&mut stream, 8), &[0.75, 0.8, 0.85, 0.9, 0.95, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn paused_stream_fades_to_silence(){
+        let mut stream = stream(16);
+        stream.duck_attack_ms = 4;
+        stream.duck_target = 0.0;
+        assert_close(&mix(&mut stream, 6), &[0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,303 @@
+//! Resampling of interleaved audio by an arbitrary, slowly varying ratio.
+//!
+//! The quality of the interpolation between the input frames is selectable: linear and cubic (Catmull-Rom)
+//! interpolation are cheap but let some aliasing through, the windowed sinc filter is suited for converting between
+//! sample rates like 88.2 kHz and 48 kHz. The state between two calls of [`Resampler::process`] is kept, so that a
+//! stream can be resampled block by block without discontinuities.
+
+use crate::{AudioBuffer, Samples};
+
+/// Zero crossings of the sinc kernel on each side of its center
+const SINC_ZERO_CROSSINGS : usize = 16;
+
+/// Points per zero crossing at which the sinc kernel is tabulated
+const SINC_OVERSAMPLING : usize = 512;
+
+/// Cutoff of the anti-aliasing filter relative to the lower of the two Nyquist frequencies
+const SINC_ROLLOFF : f64 = 0.95;
+
+
+// ****************************************
+//             Resample Quality
+// ****************************************
+
+/// Interpolation used by a [`Resampler`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResampleQuality {
+    /// Linear interpolation between two frames
+    Linear,
+    /// Cubic interpolation between four frames
+    Cubic,
+    /// Windowed sinc filter with a low pass against aliasing
+    #[default]
+    Sinc,
+}
+
+impl std::fmt::Display for ResampleQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResampleQuality::Linear => write!(f, "linear"),
+            ResampleQuality::Cubic => write!(f, "cubic"),
+            ResampleQuality::Sinc => write!(f, "sinc"),
+        }
+    }
+}
+
+
+// ****************************************
+//               Resampler
+// ****************************************
+
+pub struct Resampler {
+    num_channels : usize,
+    quality : ResampleQuality,
+    /// Output frames per input frame
+    ratio : f64,
+    /// Input frames the interpolation needs before the current position
+    history : usize,
+    /// Input frames the interpolation needs after the current position
+    lookahead : usize,
+    /// Cutoff of the sinc filter relative to the input Nyquist frequency
+    cutoff : f64,
+    /// Sinc kernel from its center to the last zero crossing, empty for the other qualities
+    kernel : Vec<f64>,
+    /// Position of the next output frame in `frames`, counted in input frames
+    pos : f64,
+    /// Interleaved input frames that are still needed for the interpolation
+    frames : Vec<f64>,
+}
+
+impl Resampler {
+    /// Create a resampler that produces `ratio` output frames per input frame. The anti-aliasing filter of the sinc
+    /// quality is designed for `ratio`, later changes by [`Resampler::set_ratio`] are meant to be small.
+    pub fn new(num_channels : usize, ratio : f64, quality : ResampleQuality) -> Self {
+        let cutoff = ratio.min(1.0) * SINC_ROLLOFF;
+        let (history, lookahead, kernel) = match quality {
+            ResampleQuality::Linear => (0, 1, Vec::new()),
+            ResampleQuality::Cubic => (1, 2, Vec::new()),
+            ResampleQuality::Sinc => {
+                let half_width = (SINC_ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+                (half_width - 1, half_width, sinc_kernel())
+            },
+        };
+
+        let mut resampler = Resampler {
+            num_channels,
+            quality,
+            ratio,
+            history,
+            lookahead,
+            cutoff,
+            kernel,
+            pos : 0.0,
+            frames : Vec::new(),
+        };
+        resampler.reset();
+        resampler
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Change the ratio, the change takes effect with the next output frame.
+    pub fn set_ratio(&mut self, ratio : f64){
+        self.ratio = ratio;
+    }
+
+    pub fn quality(&self) -> ResampleQuality {
+        self.quality
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    /// Forget all buffered input, e.g. because the stream starts over
+    pub fn reset(&mut self){
+        self.pos = self.history as f64;
+        self.frames = vec![0.0; self.history * self.num_channels];
+    }
+
+    /// Resample `buf`. The result has the format, channel count and sample rate of `buf`, the caller decides
+    /// which rate the output is played at.
+    pub fn process(&mut self, buf : &AudioBuffer) -> AudioBuffer {
+        let ch = self.num_channels;
+        if ch == 0 || buf.num_channels != ch || self.ratio <= 0.0 {
+            return buf.clone();
+        }
+
+        self.frames.extend(buf.to_f64());
+        let num_frames = self.frames.len() / ch;
+        let step = 1.0 / self.ratio;
+
+        let mut out = Vec::with_capacity(((buf.num_frames() as f64 * self.ratio).ceil() as usize + 1) * ch);
+        while self.pos + (self.lookahead as f64) < num_frames as f64 {
+            let i = self.pos.floor() as usize;
+            let t = self.pos - i as f64;
+            let x = |frame : usize, c : usize| self.frames[frame * ch + c];
+
+            match self.quality {
+                ResampleQuality::Linear => {
+                    for c in 0..ch {
+                        out.push(x(i, c) + (x(i + 1, c) - x(i, c)) * t);
+                    }
+                },
+                ResampleQuality::Cubic => {
+                    for c in 0..ch {
+                        out.push(cubic(x(i - 1, c), x(i, c), x(i + 1, c), x(i + 2, c), t));
+                    }
+                },
+                ResampleQuality::Sinc => {
+                    let base = out.len();
+                    out.resize(base + ch, 0.0);
+                    for frame in (i - self.history)..=(i + self.lookahead) {
+                        let w = self.sinc_weight(frame as f64 - self.pos);
+                        for c in 0..ch {
+                            out[base + c] += w * x(frame, c);
+                        }
+                    }
+                },
+            }
+            self.pos += step;
+        }
+
+        // drop the input that is no longer needed
+        let consumed = (self.pos.floor() as usize).saturating_sub(self.history).min(num_frames);
+        self.frames.drain(..consumed * ch);
+        self.pos -= consumed as f64;
+
+        AudioBuffer { samples : Samples::F64(out), num_channels : ch, sample_rate : buf.sample_rate }.convert(buf.format())
+    }
+
+    /// Weight of the input frame at `distance` input frames from the output position
+    fn sinc_weight(&self, distance : f64) -> f64 {
+        let x = distance.abs() * self.cutoff * SINC_OVERSAMPLING as f64;
+        let idx = x.floor() as usize;
+        if idx + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+        let frac = x - idx as f64;
+        (self.kernel[idx] + (self.kernel[idx + 1] - self.kernel[idx]) * frac) * self.cutoff
+    }
+}
+
+/// Blackman windowed sinc from its center to the last zero crossing
+fn sinc_kernel() -> Vec<f64> {
+    let len = SINC_ZERO_CROSSINGS * SINC_OVERSAMPLING;
+    (0..=len).map(|k| {
+        let x = k as f64 / SINC_OVERSAMPLING as f64;
+        let sinc = match k {
+            0 => 1.0,
+            _ => (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x),
+        };
+        let u = std::f64::consts::PI * k as f64 / len as f64;
+        sinc * (0.42 + 0.5 * u.cos() + 0.08 * (2.0 * u).cos())
+    }).collect()
+}
+
+/// Catmull-Rom interpolation between `x1` and `x2` at `t` in [0, 1)
+fn cubic(x0 : f64, x1 : f64, x2 : f64, x3 : f64, t : f64) -> f64 {
+    let a = -0.5 * x0 + 1.5 * x1 - 1.5 * x2 + 0.5 * x3;
+    let b = x0 - 2.5 * x1 + 2.0 * x2 - 0.5 * x3;
+    let c = -0.5 * x0 + 0.5 * x2;
+    ((a * t + b) * t + c) * t + x1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq : f64, sample_rate : f64, num_frames : usize, num_channels : usize) -> Vec<f64> {
+        (0..num_frames * num_channels)
+            .map(|i| 0.5 * (2.0 * std::f64::consts::PI * freq * (i / num_channels) as f64 / sample_rate).sin())
+            .collect()
+    }
+
+    fn buffer(samples : &[f64], num_channels : usize) -> AudioBuffer {
+        AudioBuffer { samples : Samples::F64(samples.to_vec()), num_channels, sample_rate : 48000 }
+    }
+
+    fn samples(buf : AudioBuffer) -> Vec<f64> {
+        match buf.samples {
+            Samples::F64(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Resample `input` in blocks of `block` frames
+    fn resample(resampler : &mut Resampler, input : &[f64], block : usize) -> Vec<f64> {
+        let ch = resampler.num_channels();
+        input.chunks(block * ch).flat_map(|chunk| samples(resampler.process(&buffer(chunk, ch)))).collect()
+    }
+
+    fn rms(samples : &[f64]) -> f64 {
+        (samples.iter().map(|x| x * x).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn unity_ratio_passes_the_input_through(){
+        let input = sine(1000.0, 48000.0, 1000, 2);
+        for quality in [ResampleQuality::Linear, ResampleQuality::Cubic] {
+            let mut resampler = Resampler::new(2, 1.0, quality);
+            let output = resample(&mut resampler, &input, 100);
+            // the frames needed for the interpolation are held back
+            assert_eq!(output.len(), input.len() - resampler.lookahead * 2);
+            for (i, (out, inp)) in output.iter().zip(&input).enumerate() {
+                assert!((out - inp).abs() < 1e-12, "{quality} sample {i}: {out} != {inp}");
+            }
+        }
+    }
+
+    #[test]
+    fn history_is_kept_across_calls(){
+        let input = sine(1000.0, 44100.0, 4410, 2);
+        for quality in [ResampleQuality::Linear, ResampleQuality::Cubic, ResampleQuality::Sinc] {
+            let whole = resample(&mut Resampler::new(2, 48000.0 / 44100.0, quality), &input, 4410);
+            for block in [1, 7, 64, 256] {
+                let blocks = resample(&mut Resampler::new(2, 48000.0 / 44100.0, quality), &input, block);
+                assert_eq!(blocks.len(), whole.len(), "{quality} in blocks of {block}");
+                for (a, b) in blocks.iter().zip(&whole) {
+                    assert!((a - b).abs() < 1e-9, "{quality} in blocks of {block}: {a} != {b}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ratio_sets_the_output_length(){
+        let input = sine(1000.0, 44100.0, 44100, 1);
+        let mut resampler = Resampler::new(1, 48000.0 / 44100.0, ResampleQuality::Sinc);
+        let output = resample(&mut resampler, &input, 441);
+        let missing = 48000 - output.len();
+        assert!(missing <= 2 * resampler.lookahead, "{} frames", output.len());
+
+        // a 1 kHz tone keeps its level
+        let level = rms(&output[2000..]) / rms(&input[2000..]);
+        assert!((level - 1.0).abs() < 0.01, "level {level}");
+    }
+
+    #[test]
+    fn sinc_filters_aliasing(){
+        // 20 kHz do not fit below the Nyquist frequency of 24 kHz audio
+        let input = sine(20000.0, 48000.0, 48000, 1);
+        let output = resample(&mut Resampler::new(1, 0.5, ResampleQuality::Sinc), &input, 256);
+        assert!(rms(&output[1000..]) < 0.001 * rms(&input), "level {}", rms(&output[1000..]));
+
+        let output = resample(&mut Resampler::new(1, 0.5, ResampleQuality::Linear), &input, 256);
+        assert!(rms(&output[1000..]) > 0.1 * rms(&input));
+    }
+
+    #[test]
+    fn reset_and_channel_mismatch(){
+        let input = sine(1000.0, 48000.0, 256, 2);
+        let mut resampler = Resampler::new(2, 0.5, ResampleQuality::Cubic);
+        let first = samples(resampler.process(&buffer(&input, 2)));
+        resampler.reset();
+        assert_eq!(samples(resampler.process(&buffer(&input, 2))), first);
+
+        let mono = buffer(&input, 1);
+        assert_eq!(resampler.process(&mono), mono);
+    }
+}
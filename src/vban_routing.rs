@@ -0,0 +1,315 @@
+//! Routing of the channels of a stream to the channels of the audio device.
+//!
+//! A [`ChannelMatrix`] holds a gain for every pair of stream and device channel. Every device channel is the sum of
+//! the stream channels weighted with their gains, which covers picking, swapping, downmixing and upmixing channels.
+//! Channels are counted from zero and are expected in WAVE order (front left, front right, center, LFE, back left,
+//! back right, ...).
+
+use crate::{AudioBuffer, Error, Samples};
+
+/// Gain of the center and surround channels in the 5.1 downmix (-3 dB)
+const DOWNMIX_GAIN : f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+
+// ****************************************
+//             Channel Matrix
+// ****************************************
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMatrix {
+    inputs : usize,
+    outputs : usize,
+    /// Gains of all stream channels for device channel 0, followed by those for device channel 1, ...
+    gains : Vec<f32>,
+}
+
+impl ChannelMatrix {
+    /// A matrix of `inputs` stream channels and `outputs` device channels that mutes everything
+    pub fn new(inputs : usize, outputs : usize) -> Self {
+        ChannelMatrix {
+            inputs,
+            outputs,
+            gains : vec![0.0; inputs * outputs],
+        }
+    }
+
+    /// Create a matrix from one row of gains per device channel, each row holds a gain per stream channel
+    pub fn from_rows(rows : &[Vec<f32>]) -> Result<Self, Error> {
+        let inputs = match rows.first() {
+            None => return Err(Error::InvalidRouting(String::from("the matrix has no rows"))),
+            Some(row) => row.len(),
+        };
+        if inputs == 0 || rows.iter().any(|row| row.len() != inputs) {
+            return Err(Error::InvalidRouting(String::from("all rows of the matrix need the same, non-zero number of gains")));
+        }
+
+        Ok(ChannelMatrix {
+            inputs,
+            outputs : rows.len(),
+            gains : rows.concat(),
+        })
+    }
+
+    /// Play every stream channel on the device channel with the same number
+    pub fn identity(num_channels : usize) -> Self {
+        let mut matrix = ChannelMatrix::new(num_channels, num_channels);
+        for ch in 0..num_channels {
+            matrix.set_gain(ch, ch, 1.0);
+        }
+        matrix
+    }
+
+    /// Play a mono stream on both channels of a stereo device
+    pub fn mono_to_stereo() -> Self {
+        let mut matrix = ChannelMatrix::new(1, 2);
+        matrix.set_gain(0, 0, 1.0);
+        matrix.set_gain(0, 1, 1.0);
+        matrix
+    }
+
+    /// Mix a stereo stream down to a mono device
+    pub fn stereo_to_mono() -> Self {
+        let mut matrix = ChannelMatrix::new(2, 1);
+        matrix.set_gain(0, 0, 0.5);
+        matrix.set_gain(1, 0, 0.5);
+        matrix
+    }
+
+    /// Mix a 5.1 stream down to a stereo device. Center and surround channels are attenuated by 3 dB, the LFE channel
+    /// is dropped and the result is scaled so that it cannot clip.
+    pub fn surround_to_stereo() -> Self {
+        let norm = 1.0 / (1.0 + 2.0 * DOWNMIX_GAIN);
+        let mut matrix = ChannelMatrix::new(6, 2);
+        matrix.set_gain(0, 0, norm);
+        matrix.set_gain(2, 0, DOWNMIX_GAIN * norm);
+        matrix.set_gain(4, 0, DOWNMIX_GAIN * norm);
+        matrix.set_gain(1, 1, norm);
+        matrix.set_gain(2, 1, DOWNMIX_GAIN * norm);
+        matrix.set_gain(5, 1, DOWNMIX_GAIN * norm);
+        matrix
+    }
+
+    /// Play the stream channels in `channels` on device channels 0, 1, ... of a stream with `inputs` channels
+    pub fn select(inputs : usize, channels : &[usize]) -> Result<Self, Error> {
+        if channels.is_empty() {
+            return Err(Error::InvalidRouting(String::from("no channels selected")));
+        }
+        let mut matrix = ChannelMatrix::new(inputs, channels.len());
+        for (output, input) in channels.iter().enumerate() {
+            if *input >= inputs {
+                return Err(Error::InvalidRouting(format!("channel {} does not exist in a stream of {inputs} channels", input + 1)));
+            }
+            matrix.set_gain(*input, output, 1.0);
+        }
+        Ok(matrix)
+    }
+
+    /// Play a stream of `inputs` channels on a device of `outputs` channels. Uses the presets where they fit, plays
+    /// mono streams on every device channel and otherwise plays the first channels as they arrive.
+    pub fn adapt(inputs : usize, outputs : usize) -> Self {
+        match (inputs, outputs) {
+            (1, 2) => ChannelMatrix::mono_to_stereo(),
+            (2, 1) => ChannelMatrix::stereo_to_mono(),
+            (6, 2) => ChannelMatrix::surround_to_stereo(),
+            (1, _) => {
+                let mut matrix = ChannelMatrix::new(1, outputs);
+                for ch in 0..outputs {
+                    matrix.set_gain(0, ch, 1.0);
+                }
+                matrix
+            },
+            _ => {
+                let mut matrix = ChannelMatrix::new(inputs, outputs);
+                for ch in 0..inputs.min(outputs) {
+                    matrix.set_gain(ch, ch, 1.0);
+                }
+                matrix
+            },
+        }
+    }
+
+    /// Number of stream channels
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    /// Number of device channels
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    pub fn gain(&self, input : usize, output : usize) -> f32 {
+        self.gains[output * self.inputs + input]
+    }
+
+    pub fn set_gain(&mut self, input : usize, output : usize, gain : f32){
+        self.gains[output * self.inputs + input] = gain;
+    }
+
+    /// Route the channels of `buf`. The result keeps the sample format of `buf`.
+    pub fn apply(&self, buf : &AudioBuffer) -> Result<AudioBuffer, Error> {
+        if buf.num_channels != self.inputs {
+            return Err(Error::InvalidRouting(format!("matrix for {} channels cannot route a buffer of {} channels", self.inputs, buf.num_channels)));
+        }
+
+        let input = buf.to_f64();
+        let mut output = Vec::with_capacity(buf.num_frames() * self.outputs);
+        for frame in input.chunks_exact(self.inputs) {
+            for row in self.gains.chunks_exact(self.inputs) {
+                output.push(row.iter().zip(frame).map(|(gain, smp)| *gain as f64 * smp).sum());
+            }
+        }
+
+        Ok(AudioBuffer { samples : Samples::F64(output), num_channels : self.outputs, sample_rate : buf.sample_rate }.convert(buf.format()))
+    }
+}
+
+
+// ****************************************
+//             Channel Routing
+// ****************************************
+
+/// How the channels of incoming streams are played on the audio device
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ChannelRouting {
+    /// Open the device with the channel count of the stream
+    #[default]
+    Direct,
+    MonoToStereo,
+    StereoToMono,
+    SurroundToStereo,
+    /// Play the given stream channels (counted from zero) in this order, e.g. `[2, 3]` for channels 3/4
+    Select(Vec<usize>),
+    Matrix(ChannelMatrix),
+}
+
+impl ChannelRouting {
+    /// The matrix for a stream of `num_channels` channels, `None` if the channels are played as they arrive
+    pub fn matrix(&self, num_channels : usize) -> Result<Option<ChannelMatrix>, Error> {
+        let matrix = match self {
+            ChannelRouting::Direct => return Ok(None),
+            ChannelRouting::MonoToStereo => ChannelMatrix::mono_to_stereo(),
+            ChannelRouting::StereoToMono => ChannelMatrix::stereo_to_mono(),
+            ChannelRouting::SurroundToStereo => ChannelMatrix::surround_to_stereo(),
+            ChannelRouting::Select(channels) => ChannelMatrix::select(num_channels, channels)?,
+            ChannelRouting::Matrix(matrix) => matrix.clone(),
+        };
+
+        match matrix.inputs() == num_channels {
+            true => Ok(Some(matrix)),
+            false => Err(Error::InvalidRouting(format!("routing for {} channels cannot be applied to a stream of {num_channels} channels", matrix.inputs()))),
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelRouting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelRouting::Direct => write!(f, "direct"),
+            ChannelRouting::MonoToStereo => write!(f, "mono to stereo"),
+            ChannelRouting::StereoToMono => write!(f, "stereo to mono"),
+            ChannelRouting::SurroundToStereo => write!(f, "5.1 to stereo"),
+            ChannelRouting::Select(channels) => {
+                let channels : Vec<String> = channels.iter().map(|ch| (ch + 1).to_string()).collect();
+                write!(f, "channels {}", channels.join("/"))
+            },
+            ChannelRouting::Matrix(matrix) => write!(f, "{}x{} matrix", matrix.inputs(), matrix.outputs()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SampleFormat;
+
+    fn buffer(samples : &[f32], num_channels : usize) -> AudioBuffer {
+        AudioBuffer { samples : Samples::F32(samples.to_vec()), num_channels, sample_rate : 48000 }
+    }
+
+    fn samples(buf : AudioBuffer) -> Vec<f32> {
+        match buf.samples {
+            Samples::F32(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    fn assert_close(a : &[f32], b : &[f32]){
+        assert_eq!(a.len(), b.len(), "{a:?} != {b:?}");
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-6), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn presets(){
+        let stereo = buffer(&[0.5, -0.5, 0.25, 0.75], 2);
+        assert_close(&samples(ChannelMatrix::identity(2).apply(&stereo).unwrap()), &[0.5, -0.5, 0.25, 0.75]);
+        assert_close(&samples(ChannelMatrix::stereo_to_mono().apply(&stereo).unwrap()), &[0.0, 0.5]);
+
+        let mono = ChannelMatrix::mono_to_stereo().apply(&buffer(&[0.5, 0.25], 1)).unwrap();
+        assert_eq!(mono.num_channels, 2);
+        assert_close(&samples(mono), &[0.5, 0.5, 0.25, 0.25]);
+
+        // full scale on every channel but the LFE must not clip
+        let surround = ChannelMatrix::surround_to_stereo().apply(&buffer(&[1.0; 6], 6)).unwrap();
+        assert_close(&samples(surround), &[1.0, 1.0]);
+        let center = ChannelMatrix::surround_to_stereo().apply(&buffer(&[0.0, 0.0, 1.0, 1.0, 0.0, 0.0], 6)).unwrap();
+        assert_close(&samples(center), &[DOWNMIX_GAIN / (1.0 + 2.0 * DOWNMIX_GAIN); 2]);
+    }
+
+    #[test]
+    fn keeps_the_sample_format(){
+        let buf = AudioBuffer::new(SampleFormat::I16, 4, 2, 44100);
+        let routed = ChannelMatrix::stereo_to_mono().apply(&buf).unwrap();
+        assert_eq!(routed.format(), SampleFormat::I16);
+        assert_eq!(routed.num_frames(), 4);
+        assert_eq!(routed.sample_rate, 44100);
+    }
+
+    #[test]
+    fn channel_mismatch_is_an_error(){
+        assert!(ChannelMatrix::stereo_to_mono().apply(&buffer(&[0.0; 6], 6)).is_err());
+        assert!(ChannelMatrix::identity(2).apply(&buffer(&[0.0; 3], 1)).is_err());
+    }
+
+    #[test]
+    fn select_channels(){
+        let matrix = ChannelMatrix::select(4, &[2, 3]).unwrap();
+        assert_close(&samples(matrix.apply(&buffer(&[0.1, 0.2, 0.3, 0.4], 4)).unwrap()), &[0.3, 0.4]);
+        let matrix = ChannelMatrix::select(2, &[1, 0, 0]).unwrap();
+        assert_close(&samples(matrix.apply(&buffer(&[0.1, 0.2], 2)).unwrap()), &[0.2, 0.1, 0.1]);
+
+        assert!(ChannelMatrix::select(2, &[2]).is_err());
+        assert!(ChannelMatrix::select(2, &[]).is_err());
+    }
+
+    #[test]
+    fn matrix_from_rows(){
+        let matrix = ChannelMatrix::from_rows(&[vec![1.0, 0.5], vec![0.0, 1.0], vec![0.25, 0.25]]).unwrap();
+        assert_eq!((matrix.inputs(), matrix.outputs()), (2, 3));
+        assert_eq!(matrix.gain(1, 0), 0.5);
+        assert_close(&samples(matrix.apply(&buffer(&[0.4, 0.8], 2)).unwrap()), &[0.8, 0.8, 0.3]);
+
+        assert!(ChannelMatrix::from_rows(&[]).is_err());
+        assert!(ChannelMatrix::from_rows(&[vec![]]).is_err());
+        assert!(ChannelMatrix::from_rows(&[vec![1.0, 0.0], vec![1.0]]).is_err());
+    }
+
+    #[test]
+    fn adapt_to_the_device(){
+        assert_eq!(ChannelMatrix::adapt(1, 2), ChannelMatrix::mono_to_stereo());
+        assert_eq!(ChannelMatrix::adapt(6, 2), ChannelMatrix::surround_to_stereo());
+        assert_close(&samples(ChannelMatrix::adapt(1, 4).apply(&buffer(&[0.5], 1)).unwrap()), &[0.5; 4]);
+        assert_close(&samples(ChannelMatrix::adapt(4, 2).apply(&buffer(&[0.1, 0.2, 0.3, 0.4], 4)).unwrap()), &[0.1, 0.2]);
+        assert_close(&samples(ChannelMatrix::adapt(2, 3).apply(&buffer(&[0.1, 0.2], 2)).unwrap()), &[0.1, 0.2, 0.0]);
+    }
+
+    #[test]
+    fn routing_for_a_stream(){
+        assert_eq!(ChannelRouting::Direct.matrix(2).unwrap(), None);
+        assert_eq!(ChannelRouting::StereoToMono.matrix(2).unwrap(), Some(ChannelMatrix::stereo_to_mono()));
+        assert!(ChannelRouting::StereoToMono.matrix(6).is_err());
+        assert!(ChannelRouting::Select(vec![4]).matrix(2).is_err());
+        assert_eq!(ChannelRouting::Select(vec![2, 3]).to_string(), "channels 3/4");
+    }
+}
@@ -1,9 +1,11 @@
 
-use std::{net::{IpAddr, UdpSocket}, process::Command, usize};
-use byteorder::{ByteOrder, LittleEndian};
-use opus::{Channels, Encoder};
-use log::{error, info, trace};
-use crate::{AlsaSource, VBanBitResolution, VBanCodec, VBanHeader, VBanSampleRates, VbanSource, VBAN_HEADER_SIZE, VBAN_PACKET_COUNTER_BYTES, VBAN_PACKET_HEADER_BYTES, VBAN_PACKET_MAX_LEN_BYTES, VBAN_PACKET_MAX_SAMPLES, VBAN_STREAM_NAME_SIZE, OPUS_BITRATE, OPUS_FRAME_SIZE};
+use std::net::{IpAddr, UdpSocket};
+use log::{error, info, trace, warn};
+use crate::{Error, AlsaSource, VBanBitResolution, VBanCodec, VBanSampleRates, VbanSource, VBAN_PACKET_MAX_SAMPLES, VBAN_DATA_MAX_SIZE, VBAN_STREAM_NAME_SIZE, AudioBuffer, SampleFormat, Samples};
+use crate::vban_opus::{stream_encoder, OpusConfig, OpusEncoder, OPUS_CHANNELS_MAX_NB};
+use crate::vban_packet::VbanPacketBuilder;
+use crate::vban_service::{self, VbanPing0, VBAN_DEVICE_TRANSMITTER, VBAN_FEATURE_AUDIO, VBAN_SERVICE_PORT};
+use crate::vban_multicast::{self, MulticastConfig};
 
 
 // ****************************************
@@ -16,6 +18,10 @@ pub struct VbanSender {
 
     socket : UdpSocket,
 
+    /// Socket on the VBAN port that receives the pings of the VBAN discovery, `None` if `socket` is bound to that port
+    /// or another node on this host holds it
+    service_socket : Option<UdpSocket>,
+
     sample_rate : VBanSampleRates,
 
     num_channels : u8, // 1 = one channel, unlike in the VBAN header, where 0 = one channel
@@ -23,16 +29,24 @@ pub struct VbanSender {
     /// Definition of codec, bitwidth (16, 24, 32) and integer/float type
     sample_format : VBanBitResolution, 
 
-    /// Stream name
-    name : [u8; 16],
+    /// Header template for outgoing packets (stream name, sample rate, channels and format)
+    packet : VbanPacketBuilder,
 
     nu_frame : u32,
 
     source : AlsaSource,
 
-    command : Option<Command>,
+    /// Sample format the source is read with
+    source_format : SampleFormat,
+
+    /// Frames read from the source for each packet
+    frames_per_packet : usize,
 
-    encoder : VBanCodec
+    /// Encoder of the stream, `None` for PCM streams
+    encoder : Option<OpusEncoder>,
+
+    /// Sent in reply to VBAN-SERVICE pings
+    identity : VbanPing0
 }
 
 impl VbanSender {
@@ -51,176 +65,210 @@ impl VbanSender {
     /// * `encoder` - Option<VBanCodec> - Optional codec to use (Opus or PCM)
     /// 
     /// # Returns 
-    /// `Some(VbanSender)` if successful, `None` otherwise.
+    /// `Ok(VbanSender)` if successful, otherwise the `Error` that prevented the sender from being set up.
     /// 
-    pub fn create(peer : (IpAddr, u16), local_addr : (IpAddr, u16), stream_name : String, numch : u8, sample_rate : VBanSampleRates, format : VBanBitResolution, source_name : String, encoder : u8) -> Option<Self> {
-
-        if format != VBanBitResolution::VbanBitfmt16Int {
-            error!("Only 16 bit sample resolution is supported");
-            return None;
-        }
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(peer : (IpAddr, u16), local_addr : (IpAddr, u16), stream_name : String, numch : u8, sample_rate : VBanSampleRates, format : VBanBitResolution, source_name : String, encoder : u8) -> Result<Self, Error> {
 
         if stream_name.len() > VBAN_STREAM_NAME_SIZE {
-            error!("Stream name exceeds limit of {} chars", VBAN_STREAM_NAME_SIZE);
-            return None;
+            return Err(Error::InvalidStreamName(format!("stream name exceeds limit of {} chars", VBAN_STREAM_NAME_SIZE)));
         }
 
-        let mut name   = [0; 16];
-        name[..stream_name.len()].copy_from_slice(stream_name.as_bytes());
+        let bits_per_sample = match format.bits() {
+            Some(bits) => bits as usize,
+            None => return Err(Error::UnsupportedFormat(format!("bit resolution {:?} is not supported", format))),
+        };
+        if numch == 0 || bits_per_sample * numch as usize > VBAN_DATA_MAX_SIZE * 8 {
+            return Err(Error::UnsupportedFormat(format!("{} channels of {} bit samples do not fit into a VBAN packet", numch, bits_per_sample)));
+        }
 
-        let enc = match VBanCodec::from(encoder) {
-            VBanCodec::VbanCodecPcm => {
-                VBanCodec::VbanCodecPcm
-            }
-            VBanCodec::VbanCodecOpus(None) => {
-                let ch = match numch {
-                    1 => Channels::Mono,
-                    2 => Channels::Stereo,
-                    _ => {
-                        error!("Encoder OPUS does not support {} channels!", numch);
-                        return None
-                    }
-                };
-                let sr = match sample_rate {
-                    VBanSampleRates::SampleRate12000Hz => 12000,
-                    VBanSampleRates::SampleRate24000Hz => 24000,
-                    VBanSampleRates::SampleRate48000Hz => 48000,
-                    _ => {
-                        error!("Encoder OPUS does not support sample rate {}!", sample_rate);
-                        return None
-                    }
-                };
-                let mut e =  Encoder::new(sr, Channels::from(ch), opus::Application::Audio).expect("Could not create encoder!");
-                e.set_bitrate(opus::Bitrate::Bits(OPUS_BITRATE)).expect("Could not set bitrate of encoder");
-                VBanCodec::VbanCodecOpus(Some(e))
-            }
-            VBanCodec::VbanCodecOpus(Some(e)) => VBanCodec::VbanCodecOpus(Some(e)),
-            _ => {
-                error!("Codec not supported");
-                return None;
+        let codec = VBanCodec::from(encoder);
+        let encoder = match codec {
+            VBanCodec::VbanCodecPcm => None,
+            VBanCodec::VbanCodecOpus(_) => {
+                if format != VBanBitResolution::VbanBitfmt16Int {
+                    return Err(Error::UnsupportedFormat(String::from("encoder OPUS only supports 16 bit samples")));
+                }
+                if numch as usize > OPUS_CHANNELS_MAX_NB {
+                    return Err(Error::UnsupportedFormat(format!("encoder OPUS does not support {} channels", numch)));
+                }
+                Some(stream_encoder(sample_rate, numch, &OpusConfig::default())?)
             }
+            codec => return Err(Error::UnsupportedFormat(format!("codec {} not supported", codec)))
         };
 
-        let source = match AlsaSource::init(&source_name, numch as u32, sample_rate.into()){
-            None => {
-                error!("Could not create audio source");
-                return None;
-            }
-            Some(s) => s
+        let packet = VbanPacketBuilder::new()
+            .stream_name(&stream_name)
+            .sample_rate(sample_rate)
+            .num_channels(numch as usize)
+            .bit_resolution(format)
+            .codec(&codec);
+
+        let frames_per_packet = match encoder {
+            None => (VBAN_DATA_MAX_SIZE * 8 / (bits_per_sample * numch as usize)).min(VBAN_PACKET_MAX_SAMPLES),
+            Some(_) => OpusConfig::default().frame_duration.num_samples(sample_rate.into()),
         };
 
-        let result = VbanSender {
+        let source_format = match format.sample_format() {
+            Some(f) => f,
+            None => return Err(Error::UnsupportedFormat(format!("bit resolution {:?} is not supported", format))),
+        };
+        let source = AlsaSource::init(&source_name, numch as u32, sample_rate.into(), source_format)?;
+
+        let mut identity = VbanPing0::new(VBAN_DEVICE_TRANSMITTER, VBAN_FEATURE_AUDIO, "rvban");
+        identity.device_name = source_name;
+        identity.prefered_rate = sample_rate.into();
+
+        let mut result = VbanSender {
             peer,
 
+            service_socket : None,
+
             socket : match UdpSocket::bind(local_addr){
                 Ok(sock) => {
                     trace!("Successfully created socket on {}:{}", local_addr.0, local_addr.1);
                     sock
                 },
-                Err(_) => {
-                    error!("Could not create udp socket");
-                    return None
-                }
+                Err(e) => return Err(Error::SocketBind(e))
             },
 
-            sample_rate : sample_rate,
+            sample_rate,
             num_channels : numch,
             sample_format : format, 
-            name : name,
+            packet,
             nu_frame : 0,
-            source : source,
-            command : None,
-            encoder : enc
+            source,
+            source_format,
+            frames_per_packet,
+            encoder,
+
+            identity
 
         };
 
-        info!("Starting stream '{}' -  SR: {}, Ch: {}, Encoder: {}", std::str::from_utf8(&result.name).unwrap_or(""), result.sample_rate, result.num_channels, result.encoder);
+        if let Err(e) = result.socket.set_nonblocking(true) {
+            return Err(Error::Io(e));
+        }
+
+        // the VBAN discovery pings the VBAN port, not the port the stream is sent from
+        if local_addr.1 != VBAN_SERVICE_PORT {
+            result.service_socket = match vban_service::bind_service_socket(local_addr.0, VBAN_SERVICE_PORT) {
+                Ok(sock) => Some(sock),
+                Err(e) => {
+                    warn!("Could not listen for VBAN-SERVICE pings on port {VBAN_SERVICE_PORT}, the stream cannot be discovered ({e}).");
+                    None
+                }
+            };
+        }
+
+        info!("Starting stream '{}' -  SR: {}, Ch: {}, Encoder: {}", stream_name, result.sample_rate, result.num_channels, codec);
 
-        Some(result)
+        Ok(result)
     }
 
 
     /// Handle one iteration of reading from source, composing a VBAN packet and sending via UDP.
     pub fn handle(&mut self){
-        let mut vban_packet :[u8; VBAN_PACKET_MAX_LEN_BYTES] = [0; VBAN_PACKET_MAX_LEN_BYTES];
-
-        // this assumes stereo ... better would be to take as much samples as possible for our given num_channels
-        let mut audio_in : Vec<i16> = vec![0; VBAN_PACKET_MAX_SAMPLES * 2];
-
-        match self.encoder {
-            VBanCodec::VbanCodecPcm => (),
-            VBanCodec::VbanCodecOpus(_) => audio_in.resize(OPUS_FRAME_SIZE*self.num_channels as usize, 0),
-            _ => panic!("Unsupported codec in VbanSender struct")
-        }
-
+        let num_channels = self.num_channels as usize;
+        let mut audio_in = AudioBuffer::new(self.source_format, self.frames_per_packet, num_channels, self.sample_rate.into());
         self.source.read(&mut audio_in);
 
-        let mut encoded = vec![0u8; audio_in.len() * 2];
-
-        match self.encoder {
-            VBanCodec::VbanCodecPcm => {
-                for (idx, smp) in audio_in.iter().enumerate(){
-                    LittleEndian::write_i16(&mut encoded[2* idx..], *smp);
+        let encoded = match &mut self.encoder {
+            None => {
+                match audio_in.to_payload(self.sample_format) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        error!("Could not encode samples: {e}");
+                        return;
+                    }
                 }
             },
-            VBanCodec::VbanCodecOpus(ref mut enc) => {
-                let bytes = match enc.as_mut().unwrap().encode(&audio_in, &mut encoded){
+            Some(enc) => {
+                let pcm = match audio_in.convert(SampleFormat::I16).samples {
+                    Samples::I16(s) => s,
+                    _ => unreachable!(),
+                };
+                let mut encoded = vec![0u8; VBAN_DATA_MAX_SIZE];
+                let bytes = match enc.encode(&pcm, &mut encoded){
                     Ok(size) => size,
-                    Err(_e) => 0
+                    Err(e) => {
+                        error!("Could not encode samples with OPUS: {e}");
+                        return;
+                    }
                 };
                 encoded.resize(bytes, 0); // this should hopefully shrink the vector
-                trace!("OPUS compression: {} => {bytes} bytes", audio_in.len() * 2);
+                trace!("OPUS compression: {} => {bytes} bytes", pcm.len() * 2);
+                encoded
             },
-            _ => panic!("Unsupported Codec in VbanSender struct")
-        }
+        };
 
-        let num_samples = audio_in.len() / self.num_channels as usize;
+        let num_samples = audio_in.num_frames();
         trace!("Samples in packet: {}, audio_in len: {}, ch: {}", num_samples, audio_in.len(), self.num_channels);
 
-        let mut format= self.sample_format as u8;
-        match self.encoder{
-            VBanCodec::VbanCodecPcm => (),
-            VBanCodec::VbanCodecOpus(_) => format |= <VBanCodec as Into<u8>>::into(VBanCodec::VbanCodecOpus(None)),
-            _ => ()
-        }
-
-        let hdr = VBanHeader {
-            preamble : [b'V', b'B', b'A', b'N'],
-            sample_rate : self.sample_rate.into(),
-            num_samples : (num_samples - 1) as u8,
-            num_channels : self.num_channels -1 , // 0 means one channel in VBAN
-            sample_format : format,
-            stream_name : self.name,
-            nu_frame : self.nu_frame
+        let packet = match self.packet.clone().num_samples(num_samples).nu_frame(self.nu_frame).build(&encoded) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Could not compose VBAN packet: {e}");
+                return;
+            }
         };
 
-        trace!("Composing packet with nu_frame: {}", hdr.nu_frame);
+        trace!("Composing packet with nu_frame: {}", self.nu_frame);
+        trace!("Packet has an effective length of {} bytes", packet.as_bytes().len());
 
-        let hdr : [u8; VBAN_PACKET_HEADER_BYTES+VBAN_PACKET_COUNTER_BYTES] = hdr.into();
+        // the socket stays unconnected, a connected socket would drop the pings of every host but the peer
+        match self.socket.send_to(packet.as_bytes(), self.peer){
+            Ok(bytes) => trace!("Successfully sent {bytes} bytes via socket"),
+            Err(e) => error!("Error while sending data via socket: {e}")
+        }
 
-        vban_packet[..VBAN_HEADER_SIZE+VBAN_PACKET_COUNTER_BYTES].copy_from_slice(&hdr);
+        self.nu_frame = self.nu_frame.wrapping_add(1);
 
-        if hdr.len() + encoded.len() > VBAN_PACKET_MAX_LEN_BYTES {
-            error!("Constructed VBAN packet would exceed the limit of {} bytes.", VBAN_PACKET_MAX_LEN_BYTES);
-            return;
+        self.answer_pings();
+    }
+
+    /// Reply to all pending VBAN-SERVICE pings on the sockets.
+    fn answer_pings(&mut self){
+        vban_service::answer_pings(&self.socket, &self.identity);
+        if let Some(service) = &self.service_socket {
+            vban_service::answer_pings(service, &self.identity);
         }
+    }
 
-        trace!("Packet has an effective length of {} bytes", hdr.len() + encoded.len());
+    /// Set the identification that is sent in reply to VBAN-SERVICE pings
+    pub fn set_identity(&mut self, identity : VbanPing0){
+        self.identity = identity;
+    }
 
-        let vban_data = &mut vban_packet[VBAN_PACKET_HEADER_BYTES+VBAN_PACKET_COUNTER_BYTES..];
-        vban_data[..encoded.len()].copy_from_slice(&encoded);
+    /// Set TTL, interface and loopback of the packets if the peer is a multicast group.
+    ///
+    /// # Returns
+    /// An `Error` if the peer is not a multicast group or the socket does not accept the settings.
+    pub fn set_multicast(&mut self, config : MulticastConfig) -> Result<(), Error> {
+        vban_multicast::configure_sender(&self.socket, self.peer.0, &config)?;
+        info!("Sending to multicast group {} on {} with TTL {}", self.peer.0, config.interface, config.ttl);
+        Ok(())
+    }
 
-        match self.socket.connect(self.peer){
-            Ok(()) => (),
-            Err(e) => error!("Could not connect to peer: {e}")
-        }
+    /// Allow sending to a broadcast address, e.g. 192.168.1.255 to reach every host of a subnet.
+    pub fn set_broadcast(&mut self, broadcast : bool) -> Result<(), Error> {
+        self.socket.set_broadcast(broadcast).map_err(Error::Io)
+    }
 
-        match self.socket.send(&vban_packet[..hdr.len()+encoded.len()]){
-            Ok(bytes) => trace!("Successfully sent {bytes} bytes via socket"),
-            Err(e) => error!("Error while sending data via socket: {e}")
+    /// Replace the Opus encoder by one with the given settings.
+    ///
+    /// # Returns
+    /// An `Error` if the stream is not Opus encoded or the settings are not supported, e.g. if a frame would exceed the
+    /// VBAN limit of 256 samples or in-band FEC is enabled for frames shorter than 10 ms.
+    pub fn set_opus_config(&mut self, config : OpusConfig) -> Result<(), Error> {
+        if self.encoder.is_none() {
+            return Err(Error::UnsupportedFormat(String::from("Opus settings require the Opus encoder")));
         }
 
-        self.nu_frame = self.nu_frame.wrapping_add(1);
+        self.encoder = Some(stream_encoder(self.sample_rate, self.num_channels, &config)?);
+        self.frames_per_packet = config.frame_duration.num_samples(self.sample_rate.into());
+        info!("Opus encoder: {} frames, {} bitrate, complexity {}", config.frame_duration, if config.vbr { "variable" } else { "constant" }, config.complexity);
+        Ok(())
     }
 
 
@@ -0,0 +1,395 @@
+//! VBAN-SERVICE sub-protocol. Answers PING0 identification requests, as sent by the VBAN discovery of Voicemeeter, and
+//! scans the network for other VBAN nodes.
+
+use std::{io::ErrorKind, net::{IpAddr, SocketAddr, UdpSocket}, time::{Duration, Instant}};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, error, trace};
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::{Error, VBanProtocol, VBAN_PROTOCOL_MAX_SIZE};
+use crate::vban_packet::{VbanPacketBuilder, VbanPacketRef};
+
+
+// ****************************************
+//            VBAN Service Types
+// ****************************************
+const VBAN_SERVICE_IDENTIFICATION : u8 = 0x00;
+const VBAN_SERVICE_FNCT_PING0 : u8 = 0x00;
+const VBAN_SERVICE_FNCT_REPLY : u8 = 0x80;
+
+/// Stream name used for ping requests and replies
+const VBAN_SERVICE_STREAM_NAME : &str = "VBAN Service";
+
+/// Size of the PING0 payload
+pub const VBAN_PING0_SIZE : usize = 676;
+
+/// Port the VBAN discovery sends its pings to
+pub const VBAN_SERVICE_PORT : u16 = 6980;
+
+// Device types (`bit_type`)
+pub const VBAN_DEVICE_RECEPTOR : u32 = 0x0000_0001;
+pub const VBAN_DEVICE_TRANSMITTER : u32 = 0x0000_0002;
+pub const VBAN_DEVICE_RECEPTORSPOT : u32 = 0x0000_0004;
+pub const VBAN_DEVICE_TRANSMITTERSPOT : u32 = 0x0000_0008;
+pub const VBAN_DEVICE_VIRTUALDEVICE : u32 = 0x0000_0010;
+pub const VBAN_DEVICE_VIRTUALMIXER : u32 = 0x0000_0020;
+pub const VBAN_DEVICE_MATRIX : u32 = 0x0000_0040;
+pub const VBAN_DEVICE_DAW : u32 = 0x0000_0080;
+pub const VBAN_DEVICE_SERVER : u32 = 0x0100_0000;
+
+// Stream capabilities (`bit_feature`)
+pub const VBAN_FEATURE_AUDIO : u32 = 0x0000_0001;
+pub const VBAN_FEATURE_AOIP : u32 = 0x0000_0002;
+pub const VBAN_FEATURE_VOIP : u32 = 0x0000_0004;
+pub const VBAN_FEATURE_SERIAL : u32 = 0x0000_0100;
+pub const VBAN_FEATURE_MIDI : u32 = 0x0000_0300;
+pub const VBAN_FEATURE_FRAME : u32 = 0x0000_1000;
+pub const VBAN_FEATURE_TXT : u32 = 0x0001_0000;
+
+
+// ****************************************
+//               VBAN Ping0
+// ****************************************
+
+/// Identification of a VBAN node, the payload of PING0 requests and replies
+#[derive(Clone, Debug, PartialEq)]
+pub struct VbanPing0 {
+    /// Device type, see the `VBAN_DEVICE_*` constants
+    pub bit_type : u32,
+    /// Stream capabilities, see the `VBAN_FEATURE_*` constants
+    pub bit_feature : u32,
+    pub bit_feature_ex : u32,
+    pub prefered_rate : u32,
+    pub min_rate : u32,
+    pub max_rate : u32,
+    pub color_rgb : u32,
+    /// Application version (major, minor, patch, build)
+    pub version : [u8; 4],
+    pub gps_position : String,
+    pub user_position : String,
+    pub lang_code : String,
+    pub distant_ip : String,
+    pub distant_port : u16,
+    pub device_name : String,
+    pub manufacturer_name : String,
+    pub application_name : String,
+    pub host_name : String,
+    pub user_name : String,
+    pub user_comment : String,
+}
+
+impl VbanPing0 {
+
+    /// Identification of this host running `application_name`. Host name, user name and version are filled in
+    /// automatically.
+    pub fn new(bit_type : u32, bit_feature : u32, application_name : &str) -> Self {
+        let host_name = match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+            Ok(name) => name.trim().to_string(),
+            Err(_) => std::env::var("HOSTNAME").unwrap_or_default(),
+        };
+
+        Self {
+            bit_type,
+            bit_feature,
+            bit_feature_ex : 0,
+            prefered_rate : 48000,
+            min_rate : 6000,
+            max_rate : 705600,
+            color_rgb : 0,
+            version : [
+                env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+                0,
+            ],
+            gps_position : String::new(),
+            user_position : String::new(),
+            lang_code : String::from("en-US"),
+            distant_ip : String::new(),
+            distant_port : 0,
+            device_name : String::new(),
+            manufacturer_name : String::from("rvban"),
+            application_name : application_name.to_string(),
+            host_name,
+            user_name : std::env::var("USER").unwrap_or_default(),
+            user_comment : String::new(),
+        }
+    }
+
+    /// Decode a PING0 payload
+    pub fn from_bytes(data : &[u8]) -> Result<Self, Error> {
+        if data.len() < VBAN_PING0_SIZE {
+            return Err(Error::InvalidService(format!("PING0 payload has {} bytes, expected {}", data.len(), VBAN_PING0_SIZE)));
+        }
+
+        Ok(Self {
+            bit_type : LittleEndian::read_u32(&data[0..]),
+            bit_feature : LittleEndian::read_u32(&data[4..]),
+            bit_feature_ex : LittleEndian::read_u32(&data[8..]),
+            prefered_rate : LittleEndian::read_u32(&data[12..]),
+            min_rate : LittleEndian::read_u32(&data[16..]),
+            max_rate : LittleEndian::read_u32(&data[20..]),
+            color_rgb : LittleEndian::read_u32(&data[24..]),
+            version : [data[28], data[29], data[30], data[31]],
+            gps_position : read_str(&data[32..40]),
+            user_position : read_str(&data[40..48]),
+            lang_code : read_str(&data[48..56]),
+            distant_ip : read_str(&data[128..160]),
+            distant_port : LittleEndian::read_u16(&data[160..]),
+            device_name : read_str(&data[164..228]),
+            manufacturer_name : read_str(&data[228..292]),
+            application_name : read_str(&data[292..356]),
+            host_name : read_str(&data[356..420]),
+            user_name : read_str(&data[420..548]),
+            user_comment : read_str(&data[548..676]),
+        })
+    }
+
+    /// Encode as PING0 payload. Strings that do not fit into their field are truncated.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; VBAN_PING0_SIZE];
+
+        LittleEndian::write_u32(&mut data[0..], self.bit_type);
+        LittleEndian::write_u32(&mut data[4..], self.bit_feature);
+        LittleEndian::write_u32(&mut data[8..], self.bit_feature_ex);
+        LittleEndian::write_u32(&mut data[12..], self.prefered_rate);
+        LittleEndian::write_u32(&mut data[16..], self.min_rate);
+        LittleEndian::write_u32(&mut data[20..], self.max_rate);
+        LittleEndian::write_u32(&mut data[24..], self.color_rgb);
+        data[28..32].copy_from_slice(&self.version);
+        write_str(&mut data[32..40], &self.gps_position);
+        write_str(&mut data[40..48], &self.user_position);
+        write_str(&mut data[48..56], &self.lang_code);
+        write_str(&mut data[128..160], &self.distant_ip);
+        LittleEndian::write_u16(&mut data[160..], self.distant_port);
+        write_str(&mut data[164..228], &self.device_name);
+        write_str(&mut data[228..292], &self.manufacturer_name);
+        write_str(&mut data[292..356], &self.application_name);
+        write_str(&mut data[356..420], &self.host_name);
+        write_str(&mut data[420..548], &self.user_name);
+        write_str(&mut data[548..676], &self.user_comment);
+
+        data
+    }
+
+    /// Human readable name of the device type
+    pub fn device_type(&self) -> &'static str {
+        match self.bit_type {
+            t if t & VBAN_DEVICE_SERVER != 0 => "Server",
+            t if t & VBAN_DEVICE_DAW != 0 => "DAW",
+            t if t & VBAN_DEVICE_MATRIX != 0 => "Matrix",
+            t if t & VBAN_DEVICE_VIRTUALMIXER != 0 => "Virtual Mixer",
+            t if t & VBAN_DEVICE_VIRTUALDEVICE != 0 => "Virtual Device",
+            t if t & (VBAN_DEVICE_RECEPTOR | VBAN_DEVICE_RECEPTORSPOT) != 0 && t & (VBAN_DEVICE_TRANSMITTER | VBAN_DEVICE_TRANSMITTERSPOT) != 0 => "Transceiver",
+            t if t & (VBAN_DEVICE_RECEPTOR | VBAN_DEVICE_RECEPTORSPOT) != 0 => "Receptor",
+            t if t & (VBAN_DEVICE_TRANSMITTER | VBAN_DEVICE_TRANSMITTERSPOT) != 0 => "Transmitter",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Read a NUL padded string field
+fn read_str(field : &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Write a NUL padded string field, always leaving room for the terminating NUL
+fn write_str(field : &mut [u8], value : &str) {
+    let mut len = value.len().min(field.len() - 1);
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+fn ping_packet(function : u8, nu_frame : u32, identity : &VbanPing0) -> Result<Vec<u8>, Error> {
+    let packet = VbanPacketBuilder::new()
+        .protocol(VBanProtocol::VbanProtocolService)
+        .sample_rate_index(0)
+        .format_nbs(function)
+        .format_nbc(VBAN_SERVICE_IDENTIFICATION)
+        .format_bit(0)
+        .stream_name(VBAN_SERVICE_STREAM_NAME)
+        .nu_frame(nu_frame)
+        .build(&identity.to_bytes())?;
+
+    Ok(packet.into_bytes())
+}
+
+
+// ****************************************
+//          VBAN Service Handling
+// ****************************************
+
+/// Answer `packet` with `identity` if it is a PING0 request. Returns `Ok(true)` if a reply was sent and `Ok(false)` if
+/// the packet was no PING0 request.
+pub fn reply_to_ping(packet : &VbanPacketRef, addr : SocketAddr, socket : &UdpSocket, identity : &VbanPing0) -> Result<bool, Error> {
+    if packet.protocol() != VBanProtocol::VbanProtocolService
+        || packet.format_nbc() != VBAN_SERVICE_IDENTIFICATION
+        || packet.format_nbs() != VBAN_SERVICE_FNCT_PING0 {
+        return Ok(false);
+    }
+
+    match VbanPing0::from_bytes(packet.payload()) {
+        Ok(requester) => debug!("Ping from {} ({}) at {addr}", requester.application_name, requester.host_name),
+        Err(_) => debug!("Ping from {addr}"),
+    }
+
+    let reply = ping_packet(VBAN_SERVICE_FNCT_REPLY, packet.nu_frame(), identity)?;
+    match socket.send_to(&reply, addr) {
+        Ok(bytes) => trace!("Sent ping reply of {bytes} bytes to {addr}"),
+        Err(e) => return Err(Error::Io(e)),
+    }
+
+    Ok(true)
+}
+
+/// Bind a non-blocking socket that receives the pings sent to `port`, usually [`VBAN_SERVICE_PORT`], for a node whose
+/// audio is sent from another port. The address may be reused, so that several sources on one host can answer
+/// broadcast pings. A recipient that already listens on the port without allowing reuse makes this fail.
+pub fn bind_service_socket(ip_addr : IpAddr, port : u16) -> Result<UdpSocket, Error> {
+    let addr = SocketAddr::new(ip_addr, port);
+    let socket = match Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP)) {
+        Ok(sock) => sock,
+        Err(e) => return Err(Error::Io(e)),
+    };
+    socket.set_reuse_address(true).map_err(Error::Io)?;
+    if let Err(e) = socket.bind(&addr.into()) {
+        return Err(Error::SocketBind(e));
+    }
+    socket.set_nonblocking(true).map_err(Error::Io)?;
+    Ok(socket.into())
+}
+
+/// Reply to all pings that are pending on the non-blocking `socket` and discard every other packet.
+pub fn answer_pings(socket : &UdpSocket, identity : &VbanPing0){
+    let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+
+    while let Ok((size, addr)) = socket.recv_from(&mut buf) {
+        let packet = match VbanPacketRef::parse(&buf[..size]) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+
+        if let Err(e) = reply_to_ping(&packet, addr, socket, identity) {
+            error!("Could not answer service request from {addr}: {e}");
+        }
+    }
+}
+
+/// A VBAN node that answered a ping
+#[derive(Clone, Debug, PartialEq)]
+pub struct VbanNode {
+    pub addr : SocketAddr,
+    pub info : VbanPing0,
+}
+
+/// Send a PING0 request to `target`, usually a broadcast address on port 6980, and collect all nodes that reply within
+/// `timeout`.
+///
+/// # Arguments
+///
+/// * `local_addr` - (IpAddr, u16) - Local IP address and port to bind to
+/// * `target` - (IpAddr, u16) - Address the request is sent to
+/// * `timeout` - Duration - Time to wait for replies
+/// * `identity` - &VbanPing0 - Identification sent along with the request
+///
+pub fn scan(local_addr : (IpAddr, u16), target : (IpAddr, u16), timeout : Duration, identity : &VbanPing0) -> Result<Vec<VbanNode>, Error> {
+    let socket = match UdpSocket::bind(local_addr) {
+        Ok(sock) => sock,
+        Err(e) => return Err(Error::SocketBind(e)),
+    };
+    socket.set_broadcast(true).map_err(Error::Io)?;
+
+    let request = ping_packet(VBAN_SERVICE_FNCT_PING0, 0, identity)?;
+    socket.send_to(&request, target).map_err(Error::Io)?;
+
+    let mut nodes : Vec<VbanNode> = Vec::new();
+    let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+    let start = Instant::now();
+
+    while let Some(remaining) = timeout.checked_sub(start.elapsed()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining)).map_err(Error::Io)?;
+
+        let (size, addr) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let packet = match VbanPacketRef::parse(&buf[..size]) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Ignoring packet from {addr} ({e}).");
+                continue;
+            }
+        };
+
+        if packet.protocol() != VBanProtocol::VbanProtocolService
+            || packet.format_nbc() != VBAN_SERVICE_IDENTIFICATION
+            || packet.format_nbs() != VBAN_SERVICE_FNCT_REPLY {
+            trace!("Ignoring packet from {addr} because it is no ping reply.");
+            continue;
+        }
+
+        let info = match VbanPing0::from_bytes(packet.payload()) {
+            Ok(info) => info,
+            Err(e) => {
+                debug!("Ignoring ping reply from {addr} ({e}).");
+                continue;
+            }
+        };
+
+        if nodes.iter().any(|n| n.addr == addr) {
+            continue;
+        }
+        nodes.push(VbanNode { addr, info });
+    }
+
+    Ok(nodes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread};
+
+    use super::*;
+
+    #[test]
+    fn service_socket_answers_pings_on_the_vban_port(){
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let identity = VbanPing0::new(VBAN_DEVICE_TRANSMITTER, VBAN_FEATURE_AUDIO, "rvban");
+        // the socket a sender binds with its default settings
+        let service = bind_service_socket(localhost, VBAN_SERVICE_PORT).unwrap();
+
+        let scanner = thread::spawn(move || {
+            scan((localhost, 0), (localhost, VBAN_SERVICE_PORT), Duration::from_millis(500), &VbanPing0::new(0, 0, "test")).unwrap()
+        });
+        let start = Instant::now();
+        while !scanner.is_finished() && start.elapsed() < Duration::from_secs(2) {
+            answer_pings(&service, &identity);
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let nodes = scanner.join().unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].info.application_name, "rvban");
+        assert_eq!(nodes[0].info.bit_type, VBAN_DEVICE_TRANSMITTER);
+    }
+
+    #[test]
+    fn service_sockets_share_the_port(){
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let first = bind_service_socket(localhost, 0).unwrap();
+        let port = first.local_addr().unwrap().port();
+        assert!(bind_service_socket(localhost, port).is_ok());
+
+        // a recipient that does not allow reuse keeps the port to itself
+        let recipient = UdpSocket::bind((localhost, 0)).unwrap();
+        assert!(bind_service_socket(localhost, recipient.local_addr().unwrap().port()).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! Tracking of lost, duplicate and reordered packets of a VBAN stream based on the frame counter (`nu_frame`).
+
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}};
+
+/// Number of frames behind the newest one for which duplicates and reordered packets are recognized
+const VBAN_REORDER_WINDOW : u32 = 64;
+
+/// Number of consecutive old packets after which the sender is assumed to have restarted with a low frame counter
+const VBAN_RESTART_THRESHOLD : u32 = 8;
+
+/// Largest jump of the frame counter that is counted as lost packets, larger jumps are treated as a restart of the sender
+const VBAN_MAX_FRAME_GAP : u32 = 1024;
+
+/// Number of sender addresses for which rejected packets are counted separately
+const VBAN_MAX_REJECTED_SOURCES : usize = 256;
+
+
+// ****************************************
+//            VBAN Stream Stats
+// ****************************************
+
+/// Packet counters of a single stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct VbanStreamStats {
+    pub stream_name : String,
+    /// Address of the sender
+    pub addr : SocketAddr,
+    /// Packets that arrived, including duplicate and late ones
+    pub received : u64,
+    /// Packets that never arrived
+    pub lost : u64,
+    /// Packets that arrived more than once
+    pub duplicate : u64,
+    /// Packets that arrived after a packet with a higher frame counter
+    pub out_of_order : u64,
+    /// Packets that arrived too late to be played
+    pub late : u64,
+    /// Number of times the frame counter jumped, e.g. because the sender was restarted
+    pub restarts : u64,
+    /// Packets whose payload does not match the format of their header
+    pub malformed : u64,
+}
+
+impl VbanStreamStats {
+    pub fn new(stream_name : &str, addr : SocketAddr) -> Self {
+        VbanStreamStats {
+            stream_name : String::from(stream_name),
+            addr,
+            received : 0,
+            lost : 0,
+            duplicate : 0,
+            out_of_order : 0,
+            late : 0,
+            restarts : 0,
+            malformed : 0,
+        }
+    }
+}
+
+
+/// Packets that were rejected by the access rules of a recipient
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RejectedStats {
+    /// All rejected packets
+    pub packets : u64,
+    /// Rejected packets by sender address. Only the first senders are listed, so that a flood of spoofed addresses
+    /// cannot exhaust the memory.
+    pub sources : HashMap<IpAddr, u64>,
+}
+
+impl RejectedStats {
+    /// Count a rejected packet from `addr`. Returns true if it is the first one from `addr` that is listed.
+    pub fn count(&mut self, addr : IpAddr) -> bool {
+        self.packets += 1;
+        let listed = self.sources.len();
+        match self.sources.get_mut(&addr) {
+            Some(n) => {
+                *n += 1;
+                false
+            },
+            None if listed < VBAN_MAX_REJECTED_SOURCES => {
+                self.sources.insert(addr, 1);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+
+// ****************************************
+//              Frame Tracker
+// ****************************************
+
+/// Classification of a packet by its frame counter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameStatus {
+    /// The packet is newer than all previous ones, `lost` packets are missing in between
+    Next { lost : u32 },
+    /// The packet has been received before
+    Duplicate,
+    /// The packet is older than the newest one but has not been received before
+    OutOfOrder,
+    /// The packet is older than the reorder window and is dropped
+    Late,
+    /// The frame counter jumped, the stream starts over with this packet
+    Restart,
+}
+
+/// Follows the frame counter of one stream and keeps its [`VbanStreamStats`] up to date.
+#[derive(Clone, Debug)]
+pub struct FrameTracker {
+    stats : VbanStreamStats,
+    /// Highest frame counter seen since the last restart, `None` before the first packet
+    newest : Option<u32>,
+    /// Bit `i` is set if frame `newest - i` has been received
+    received_mask : u64,
+    /// Number of consecutive packets that were older than the newest one
+    old_in_row : u32,
+}
+
+impl FrameTracker {
+    pub fn new(stream_name : &str, addr : SocketAddr) -> Self {
+        FrameTracker {
+            stats : VbanStreamStats::new(stream_name, addr),
+            newest : None,
+            received_mask : 0,
+            old_in_row : 0,
+        }
+    }
+
+    pub fn stats(&self) -> &VbanStreamStats {
+        &self.stats
+    }
+
+    /// Count a packet that arrived in time but could not be played
+    pub fn count_late(&mut self){
+        self.stats.late += 1;
+    }
+
+    /// Count a packet that was discarded because its payload does not match its header
+    pub fn count_malformed(&mut self){
+        self.stats.malformed += 1;
+    }
+
+    /// Classify a packet with the frame counter `nu_frame` and update the stats.
+    pub fn track(&mut self, nu_frame : u32) -> FrameStatus {
+        self.stats.received += 1;
+
+        let newest = match self.newest {
+            None => {
+                self.restart(nu_frame);
+                return FrameStatus::Next { lost : 0 };
+            },
+            Some(n) => n,
+        };
+
+        let ahead = nu_frame.wrapping_sub(newest);
+        let behind = newest.wrapping_sub(nu_frame);
+
+        if ahead == 0 || ahead > VBAN_MAX_FRAME_GAP {
+            self.old_in_row += 1;
+            if self.old_in_row >= VBAN_RESTART_THRESHOLD {
+                self.stats.restarts += 1;
+                self.restart(nu_frame);
+                return FrameStatus::Restart;
+            }
+        } else {
+            self.old_in_row = 0;
+        }
+
+        if ahead == 0 {
+            self.stats.duplicate += 1;
+            FrameStatus::Duplicate
+        } else if ahead <= VBAN_MAX_FRAME_GAP {
+            self.received_mask = match ahead < u64::BITS {
+                true => (self.received_mask << ahead) | 1,
+                false => 1,
+            };
+            self.newest = Some(nu_frame);
+            self.stats.lost += (ahead - 1) as u64;
+            FrameStatus::Next { lost : ahead - 1 }
+        } else if behind < VBAN_REORDER_WINDOW {
+            let bit = 1u64 << behind;
+            if self.received_mask & bit != 0 {
+                self.stats.duplicate += 1;
+                return FrameStatus::Duplicate;
+            }
+            // the packet was counted as lost when the gap was detected
+            self.received_mask |= bit;
+            self.stats.lost = self.stats.lost.saturating_sub(1);
+            self.stats.out_of_order += 1;
+            FrameStatus::OutOfOrder
+        } else if ahead < behind || nu_frame < VBAN_REORDER_WINDOW {
+            // a large jump forward, or back to the start of the counter after a restart of the sender
+            self.stats.restarts += 1;
+            self.restart(nu_frame);
+            FrameStatus::Restart
+        } else {
+            // a single stray packet, the sender is only assumed to have restarted after VBAN_RESTART_THRESHOLD of them
+            self.stats.late += 1;
+            FrameStatus::Late
+        }
+    }
+
+    fn restart(&mut self, nu_frame : u32){
+        self.newest = Some(nu_frame);
+        self.received_mask = 1;
+        self.old_in_row = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> FrameTracker {
+        FrameTracker::new("Stream1", "127.0.0.1:6980".parse().unwrap())
+    }
+
+    #[test]
+    fn lost_and_reordered_frames(){
+        let mut t = tracker();
+        assert_eq!(t.track(10), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.track(11), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.track(14), FrameStatus::Next { lost : 2 });
+        assert_eq!(t.stats().lost, 2);
+
+        assert_eq!(t.track(12), FrameStatus::OutOfOrder);
+        assert_eq!(t.track(12), FrameStatus::Duplicate);
+        assert_eq!(t.track(14), FrameStatus::Duplicate);
+        assert_eq!(t.track(15), FrameStatus::Next { lost : 0 });
+
+        let stats = t.stats();
+        assert_eq!(stats.received, 7);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.duplicate, 2);
+        assert_eq!(stats.restarts, 0);
+    }
+
+    #[test]
+    fn frame_counter_wraps(){
+        let mut t = tracker();
+        t.track(u32::MAX - 1);
+        assert_eq!(t.track(u32::MAX), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.track(0), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.track(2), FrameStatus::Next { lost : 1 });
+        assert_eq!(t.track(1), FrameStatus::OutOfOrder);
+        assert_eq!(t.stats().lost, 0);
+        assert_eq!(t.stats().restarts, 0);
+    }
+
+    #[test]
+    fn jumps_restart_the_stream(){
+        let mut t = tracker();
+        t.track(5000);
+        assert_eq!(t.track(0), FrameStatus::Restart);
+        assert_eq!(t.track(1), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.track(1 + VBAN_MAX_FRAME_GAP + 1), FrameStatus::Restart);
+        assert_eq!(t.stats().restarts, 2);
+        assert_eq!(t.stats().lost, 0);
+    }
+
+    #[test]
+    fn old_frames_in_a_row_restart_the_stream(){
+        let mut t = tracker();
+        for n in 0..=100 {
+            t.track(n);
+        }
+        // a sender that restarts within the reorder window first looks like duplicates
+        for n in 93..100 {
+            assert_eq!(t.track(n), FrameStatus::Duplicate);
+        }
+        assert_eq!(t.track(100), FrameStatus::Restart);
+        assert_eq!(t.track(101), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.stats().restarts, 1);
+    }
+
+    #[test]
+    fn stray_old_frames_are_late(){
+        let mut t = tracker();
+        for n in 0..=200 {
+            t.track(n);
+        }
+        assert_eq!(t.track(100), FrameStatus::Late);
+        assert_eq!(t.track(201), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.track(202), FrameStatus::Next { lost : 0 });
+
+        let stats = t.stats();
+        assert_eq!(stats.late, 1);
+        assert_eq!(stats.restarts, 0);
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn old_frames_beyond_the_window_in_a_row_restart_the_stream(){
+        let mut t = tracker();
+        for n in 1000..=1200 {
+            t.track(n);
+        }
+        for n in 500..500 + VBAN_RESTART_THRESHOLD - 1 {
+            assert_eq!(t.track(n), FrameStatus::Late);
+        }
+        assert_eq!(t.track(500 + VBAN_RESTART_THRESHOLD - 1), FrameStatus::Restart);
+        assert_eq!(t.track(500 + VBAN_RESTART_THRESHOLD), FrameStatus::Next { lost : 0 });
+        assert_eq!(t.stats().restarts, 1);
+    }
+
+    #[test]
+    fn late_packets(){
+        let mut t = tracker();
+        t.track(0);
+        t.count_late();
+        t.count_malformed();
+        assert_eq!(t.stats().late, 1);
+        assert_eq!(t.stats().malformed, 1);
+        assert_eq!(t.stats().received, 1);
+    }
+
+    #[test]
+    fn rejected_sources_are_capped(){
+        let mut rejected = RejectedStats::default();
+        for n in 0..VBAN_MAX_REJECTED_SOURCES as u32 {
+            assert!(rejected.count(IpAddr::from(n.to_be_bytes())));
+        }
+        assert!(!rejected.count(IpAddr::from([10, 0, 0, 1])));
+        assert!(!rejected.count(IpAddr::from([0, 0, 0, 0])));
+
+        assert_eq!(rejected.packets, VBAN_MAX_REJECTED_SOURCES as u64 + 2);
+        assert_eq!(rejected.sources.len(), VBAN_MAX_REJECTED_SOURCES);
+        assert_eq!(rejected.sources[&IpAddr::from([0, 0, 0, 0])], 2);
+        assert!(!rejected.sources.contains_key(&IpAddr::from([10, 0, 0, 1])));
+    }
+}
@@ -0,0 +1,302 @@
+//! VBAN-TEXT sub-protocol, e.g. for sending Voicemeeter remote commands like `Strip[0].Gain = -6;`.
+
+use std::{net::{IpAddr, SocketAddr, UdpSocket}, time::Duration};
+use log::{debug, trace};
+use crate::{Error, VBanProtocol, VBAN_DATA_MAX_SIZE, VBAN_PROTOCOL_MAX_SIZE, VBAN_STREAM_NAME_SIZE};
+use crate::vban_packet::{VbanPacketBuilder, VbanPacketRef};
+
+
+// ****************************************
+//            VBAN Text Format
+// ****************************************
+const VBAN_TEXT_FORMAT_MASK : u8 = 0xF0;
+
+/// Encoding of the text, stored in the upper bits of the `format_bit` header field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanTextFormat {
+    Ascii,
+    Utf8,
+    /// Wide characters, i.e. UTF-16 little endian
+    Wchar,
+    User
+}
+
+impl From<u8> for VBanTextFormat {
+    fn from(value : u8) -> Self {
+        match value & VBAN_TEXT_FORMAT_MASK {
+            0x00 => VBanTextFormat::Ascii,
+            0x10 => VBanTextFormat::Utf8,
+            0x20 => VBanTextFormat::Wchar,
+            _ => VBanTextFormat::User
+        }
+    }
+}
+
+impl From<VBanTextFormat> for u8 {
+    fn from(value : VBanTextFormat) -> Self {
+        match value {
+            VBanTextFormat::Ascii => 0x00,
+            VBanTextFormat::Utf8 => 0x10,
+            VBanTextFormat::Wchar => 0x20,
+            VBanTextFormat::User => 0xF0
+        }
+    }
+}
+
+impl std::fmt::Display for VBanTextFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VBanTextFormat::Ascii => write!(f, "ASCII"),
+            VBanTextFormat::Utf8 => write!(f, "UTF-8"),
+            VBanTextFormat::Wchar => write!(f, "WCHAR"),
+            VBanTextFormat::User => write!(f, "User"),
+        }
+    }
+}
+
+
+// ****************************************
+//               VBAN Text
+// ****************************************
+
+/// Content of a received VBAN-TEXT packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct VbanText {
+    pub stream_name : String,
+    pub format : VBanTextFormat,
+    /// Channel identifier (`format_nbc` header field)
+    pub channel : u8,
+    pub text : String,
+}
+
+/// Handler that is called with every received VBAN-TEXT packet and the address of its sender
+pub type TextHandler = Box<dyn FnMut(&VbanText, SocketAddr) + Send>;
+
+impl VbanText {
+
+    /// Decode the payload of a VBAN-TEXT packet. Trailing NUL characters are removed.
+    pub fn from_packet(packet : &VbanPacketRef) -> Result<Self, Error> {
+        if packet.protocol() != VBanProtocol::VbanProtocolTxt {
+            return Err(Error::InvalidText(format!("expected a text packet, found protocol {:?}", packet.protocol())));
+        }
+
+        let format = VBanTextFormat::from(packet.format_bit());
+        let payload = packet.payload();
+
+        let text = match format {
+            VBanTextFormat::Ascii => {
+                if !payload.is_ascii() {
+                    return Err(Error::InvalidText(String::from("ASCII text contains non-ASCII bytes")));
+                }
+                String::from_utf8_lossy(payload).into_owned()
+            },
+            VBanTextFormat::Utf8 => match std::str::from_utf8(payload) {
+                Ok(t) => t.to_string(),
+                Err(e) => return Err(Error::InvalidText(format!("text is not valid UTF-8 ({e})"))),
+            },
+            VBanTextFormat::Wchar => {
+                let wide : Vec<u16> = payload.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+                match String::from_utf16(&wide) {
+                    Ok(t) => t,
+                    Err(e) => return Err(Error::InvalidText(format!("text is not valid UTF-16 ({e})"))),
+                }
+            },
+            VBanTextFormat::User => return Err(Error::UnsupportedFormat(String::from("user defined text format"))),
+        };
+
+        Ok(Self {
+            stream_name : packet.stream_name().to_string(),
+            format,
+            channel : packet.format_nbc(),
+            text : text.trim_end_matches('\0').to_string(),
+        })
+    }
+}
+
+/// Split `text` into payloads that fit into single packets, without splitting characters.
+fn encode_text(text : &str, format : VBanTextFormat) -> Result<Vec<Vec<u8>>, Error> {
+    let mut payloads = Vec::new();
+    let mut current = Vec::new();
+
+    for c in text.chars() {
+        let mut encoded = [0u8; 4];
+        let bytes : Vec<u8> = match format {
+            VBanTextFormat::Ascii => {
+                if !c.is_ascii() {
+                    return Err(Error::InvalidText(format!("'{c}' cannot be sent as ASCII")));
+                }
+                vec![c as u8]
+            },
+            VBanTextFormat::Utf8 => c.encode_utf8(&mut encoded).as_bytes().to_vec(),
+            VBanTextFormat::Wchar => {
+                let mut wide = [0u16; 2];
+                c.encode_utf16(&mut wide).iter().flat_map(|w| w.to_le_bytes()).collect()
+            },
+            VBanTextFormat::User => return Err(Error::UnsupportedFormat(String::from("user defined text format"))),
+        };
+
+        if current.len() + bytes.len() > VBAN_DATA_MAX_SIZE {
+            payloads.push(current);
+            current = Vec::new();
+        }
+        current.extend_from_slice(&bytes);
+    }
+
+    if !current.is_empty() || payloads.is_empty() {
+        payloads.push(current);
+    }
+
+    Ok(payloads)
+}
+
+
+// ****************************************
+//            VBAN Text Sender
+// ****************************************
+pub struct VbanTextSender {
+
+    peer : (IpAddr, u16),
+
+    socket : UdpSocket,
+
+    format : VBanTextFormat,
+
+    /// Header template for outgoing packets
+    packet : VbanPacketBuilder,
+
+    nu_frame : u32,
+}
+
+impl VbanTextSender {
+
+    /// Create a VbanTextSender object.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - (IpAddr, u16) - IP address and port of the receiver
+    /// * `local_addr` - (IpAddr, u16) - Local IP address and port to bind to
+    /// * `stream_name` - String - Name of the text stream (max 16 characters), Voicemeeter expects "Command1" by default
+    /// * `format` - VBanTextFormat - Encoding of the text
+    ///
+    /// # Returns
+    /// `Ok(VbanTextSender)` if successful, otherwise the `Error` that prevented the sender from being set up.
+    ///
+    pub fn create(peer : (IpAddr, u16), local_addr : (IpAddr, u16), stream_name : String, format : VBanTextFormat) -> Result<Self, Error> {
+
+        if stream_name.len() > VBAN_STREAM_NAME_SIZE {
+            return Err(Error::InvalidStreamName(format!("stream name exceeds limit of {} chars", VBAN_STREAM_NAME_SIZE)));
+        }
+
+        let socket = match UdpSocket::bind(local_addr){
+            Ok(sock) => sock,
+            Err(e) => return Err(Error::SocketBind(e)),
+        };
+
+        let packet = VbanPacketBuilder::new()
+            .protocol(VBanProtocol::VbanProtocolTxt)
+            .sample_rate_index(0)
+            .format_nbs(0)
+            .format_nbc(0)
+            .format_bit(format.into())
+            .stream_name(&stream_name);
+
+        Ok(Self {
+            peer,
+            socket,
+            format,
+            packet,
+            nu_frame : 0,
+        })
+    }
+
+    /// Send `text`, split into as many packets as necessary.
+    pub fn send(&mut self, text : &str) -> Result<(), Error> {
+        for payload in encode_text(text, self.format)? {
+            let packet = self.packet.clone().nu_frame(self.nu_frame).build(&payload)?;
+
+            match self.socket.send_to(packet.as_bytes(), self.peer){
+                Ok(bytes) => trace!("Sent text packet of {bytes} bytes"),
+                Err(e) => return Err(Error::Io(e)),
+            }
+
+            self.nu_frame = self.nu_frame.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+
+    /// The socket the text is sent from, e.g. to listen for replies of the peer
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+
+// ****************************************
+//           VBAN Text Receiver
+// ****************************************
+pub struct VbanTextReceiver {
+
+    socket : UdpSocket,
+
+    stream_name : Option<String>,
+}
+
+impl VbanTextReceiver {
+
+    /// Create a receiver for VBAN-TEXT packets bound to `ip_addr:port`. If `stream_name` is set, only text streams with
+    /// that name are accepted.
+    pub fn create(ip_addr : IpAddr, port : u16, stream_name : Option<String>) -> Result<Self, Error> {
+        if let Some(name) = &stream_name {
+            if name.len() > VBAN_STREAM_NAME_SIZE {
+                return Err(Error::InvalidStreamName(format!("stream name exceeds the limit of {} characters", VBAN_STREAM_NAME_SIZE)));
+            }
+        }
+
+        let socket = match UdpSocket::bind((ip_addr, port)){
+            Ok(sock) => sock,
+            Err(e) => return Err(Error::SocketBind(e)),
+        };
+
+        Ok(Self { socket, stream_name })
+    }
+
+    /// Set a timeout for [`recv`](Self::recv). `None` blocks until a text packet arrives.
+    pub fn set_timeout(&self, timeout : Option<Duration>) -> Result<(), Error> {
+        self.socket.set_read_timeout(timeout).map_err(Error::Io)
+    }
+
+    /// Wait for the next text packet. Packets of other sub-protocols and other streams are skipped.
+    pub fn recv(&self) -> Result<(VbanText, SocketAddr), Error> {
+        let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+
+        loop {
+            let (size, addr) = match self.socket.recv_from(&mut buf){
+                Ok(res) => res,
+                Err(e) => return Err(Error::Io(e)),
+            };
+
+            let packet = match VbanPacketRef::parse(&buf[..size]) {
+                Ok(p) => p,
+                Err(e) => {
+                    debug!("Discarding malformed packet from {addr} ({e}).");
+                    continue;
+                }
+            };
+
+            if packet.protocol() != VBanProtocol::VbanProtocolTxt {
+                trace!("Discarding packet with protocol {:?} from {addr}.", packet.protocol());
+                continue;
+            }
+
+            if let Some(name) = &self.stream_name {
+                if name != packet.stream_name() {
+                    trace!("Discarding text packet of stream {} from {addr}.", packet.stream_name());
+                    continue;
+                }
+            }
+
+            return Ok((VbanText::from_packet(&packet)?, addr));
+        }
+    }
+}